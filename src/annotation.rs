@@ -0,0 +1,51 @@
+use crate::{resolve_location, config::MAX_GUEST_ADDR};
+
+use rustc_hash::FxHashMap;
+use parse_int::parse;
+
+/// How the values observed at an annotated location are turned into coverage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationMode {
+    /// Every distinct value is new coverage
+    Set,
+
+    /// Values larger than any previously observed value are new coverage
+    Max,
+
+    /// Values smaller than any previously observed value are new coverage
+    Min,
+}
+
+/// Guest value that is used as additional coverage feedback (IJON-style state annotation). The
+/// JIT checks every load and store that overlaps the location, and feeds the value at the location
+/// into the coverage map after the access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Annotation {
+    /// Guest address of the value
+    pub addr: usize,
+
+    /// Size of the value in bytes (1, 2, 4, or 8)
+    pub width: usize,
+
+    /// How observed values are turned into coverage
+    pub mode: AnnotationMode,
+}
+
+impl Annotation {
+    /// Parse an annotation from `LOC:WIDTH:MODE` (eg. `maze_pos:4:set` or `0x1234:8:max`). The
+    /// value has to lie entirely within the guest's address space since the JIT reads it directly
+    pub fn parse(sym_map: &FxHashMap<String, usize>, spec: &str) -> Option<Self> {
+        let mut fields = spec.rsplitn(3, ':');
+        let mode = match fields.next()? {
+            "set" => AnnotationMode::Set,
+            "max" => AnnotationMode::Max,
+            "min" => AnnotationMode::Min,
+            _ => return None,
+        };
+        let width = parse::<usize>(fields.next()?).ok().filter(|e| [1, 2, 4, 8].contains(e))?;
+        let addr = resolve_location(sym_map, fields.next()?)?
+            .checked_add(width).filter(|&e| e <= MAX_GUEST_ADDR)? - width;
+
+        Some(Self { addr, width, mode })
+    }
+}
@@ -0,0 +1,178 @@
+#![feature(once_cell)]
+
+use sfuzz::{
+    emulator::Emulator,
+    jit::Jit,
+    hooks::insert_hooks,
+    Input, Corpus, error_exit, load_elf_segments, setup_stack, setup_harness,
+    config::{handle_harness_args, HarnessArgs, CovMethod, SmcPolicy, COV_METHOD, NO_PERM_CHECKS,
+        FULL_TRACE, CMP_COV, SMC_POLICY, CMP_LOG, VALUE_PROFILE, TRACE_BLOCKS, NUM_THREADS,
+        DEBUG_PRINT, FUZZ_INPUT, MAX_GUEST_ADDR, MAP_SIZE, MAP_STATS, DETERMINISTIC},
+};
+use std::sync::{Arc, Mutex};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+use clap::Parser;
+use serde::Serialize;
+
+/// Used by clap to parse command-line arguments
+#[derive(Debug, Parser)]
+#[clap(name = "sfuzz-cov", author = "seal9055", version,
+    about = "Replay a corpus and report the blocks it reaches per function")]
+#[clap(override_usage = "sfuzz-cov [OPTION] -- /path/to/fuzzed_app [ ... ] (use `@@` to specify \
+    position of the input in target-argv, and the same `-s`, `--inject`, `--function`, etc. \
+    flags the inputs were fuzzed with)\n\n    ex: sfuzz-cov -i in -i out/queue -- \
+    ./test_cases/test @@")]
+struct Cli {
+    #[clap(short, value_name = "DIR", required = true)]
+    /// - Directories containing the inputs to replay (eg. the seeds and `out/queue`)
+    input_dirs: Vec<String>,
+
+    #[clap(short, value_name = "FILE")]
+    /// - Write a JSON summary of the coverage to this file
+    json: Option<String>,
+
+    #[clap(short = 't', default_value = "10000000")]
+    /// - Maximum number of instructions that a single input is run for
+    timeout: u64,
+
+    #[clap(flatten)]
+    harness: HarnessArgs,
+
+    #[clap(last = true)]
+    /// The target alongside its arguments
+    fuzzed_app: Vec<String>,
+}
+
+/// Blocks reached within a single function
+#[derive(Debug, Serialize)]
+struct FunctionCoverage {
+    name: String,
+    addr: usize,
+    blocks_reached: usize,
+    blocks_total: usize,
+}
+
+/// Coverage reached by the entire replayed corpus
+#[derive(Debug, Serialize)]
+struct CoverageSummary {
+    inputs: usize,
+    blocks_reached: usize,
+    functions_reached: usize,
+    functions_total: usize,
+    functions: Vec<FunctionCoverage>,
+}
+
+/// Load the target the same way the fuzzer does, replay every input with block tracing enabled,
+/// and report the blocks that were reached in each function. The snapshot and harness flags need
+/// to match the ones the inputs were fuzzed with
+fn main() {
+    let mut args = Cli::parse();
+
+    // The JIT is configured through the same variables as the fuzzer. No coverage is collected
+    // since only the traced blocks are of interest
+    COV_METHOD.set(CovMethod::None).unwrap();
+    NO_PERM_CHECKS.set(false).unwrap();
+    FULL_TRACE.set(false).unwrap();
+    CMP_COV.set(false).unwrap();
+    CMP_LOG.set(false).unwrap();
+    VALUE_PROFILE.set(false).unwrap();
+    MAP_SIZE.set(CovMethod::None.default_map_size()).unwrap();
+    MAP_STATS.set(false).unwrap();
+    DETERMINISTIC.set(false).unwrap();
+    SMC_POLICY.set(SmcPolicy::Ignore).unwrap();
+    TRACE_BLOCKS.set(true).unwrap();
+    NUM_THREADS.set(1).unwrap();
+    DEBUG_PRINT.set(false).unwrap();
+    FUZZ_INPUT.set("fuzz_input\0".to_string()).unwrap();
+
+    if args.fuzzed_app.is_empty() {
+        error_exit("You need to specify the target to replay the inputs against");
+    }
+    handle_harness_args(&args.harness, &mut args.fuzzed_app);
+
+    // Read in all inputs up front. They are also added to the corpus since snapshots that are
+    // taken without an input prefix are reached using the corpus inputs
+    let mut inputs: Vec<Vec<u8>> = Vec::new();
+    for dir in &args.input_dirs {
+        let entries = std::fs::read_dir(dir).unwrap_or_else(|_| {
+            error_exit(&format!("Could not read input directory: {}", dir));
+        });
+        for entry in entries {
+            inputs.push(std::fs::read(entry.unwrap().path()).unwrap());
+        }
+    }
+
+    let jit = Arc::new(Jit::new(16 * 1024 * 1024));
+    let corpus = Corpus::new(*MAP_SIZE.get().unwrap());
+    corpus.inputs.write().extend(inputs.iter().map(|e| Input::new(e.clone(), None)));
+    let mut emu = Emulator::new(MAX_GUEST_ADDR, jit, Arc::new(Mutex::new(0)));
+
+    let sym_map = load_elf_segments(&args.fuzzed_app[0], &mut emu).unwrap_or_else(||{
+        error_exit("Unrecoverable error while loading elf segments");
+    });
+    setup_stack(&mut emu, &args.fuzzed_app);
+    insert_hooks(&sym_map, &mut emu);
+    setup_harness(&mut emu, &corpus, &sym_map);
+    emu.timeout = args.timeout;
+
+    // Replay all inputs and collect the addresses of the blocks they reached
+    let original = emu.fork();
+    let mut reached: FxHashSet<usize> = FxHashSet::default();
+    for data in &inputs {
+        emu.reset(&original);
+        emu.fuzz_input.clear();
+        emu.fuzz_input.extend_from_slice(data);
+
+        let mut instr_count = 0;
+        let mut trace_arr_len = 0;
+        if emu.prepare_case().is_ok() {
+            emu.run_jit(&corpus, &mut instr_count, &mut [], &mut trace_arr_len);
+        }
+        emu.block_hits.drain(|idx, _| {
+            reached.insert(idx * 4);
+        });
+    }
+
+    // Group the reached blocks by function
+    let mut func_blocks: FxHashMap<usize, usize> = FxHashMap::default();
+    for &addr in &reached {
+        if let Some(func) = emu.function_containing(addr) {
+            *func_blocks.entry(func).or_insert(0) += 1;
+        }
+    }
+
+    // Only functions that were reached are lifted to determine their total number of blocks,
+    // since these are known to only contain supported instructions
+    let mut functions: Vec<FunctionCoverage> = func_blocks.into_iter().map(|(addr, blocks)| {
+        let irgraph = emu.lift_func(addr).unwrap();
+        FunctionCoverage {
+            name: emu.functions.get(&addr).unwrap().1.clone(),
+            addr,
+            blocks_reached: blocks,
+            blocks_total: irgraph.get_block_sizes(&irgraph.get_leaders()).len(),
+        }
+    }).collect();
+    functions.sort_by_key(|e| e.addr);
+
+    println!("{:<40} {:>12} {:>9}", "Function", "Blocks", "Coverage");
+    for func in &functions {
+        println!("{:<40} {:>12} {:>8.2}%", func.name,
+                 format!("{}/{}", func.blocks_reached, func.blocks_total),
+                 func.blocks_reached as f64 / func.blocks_total as f64 * 100.0);
+    }
+
+    let summary = CoverageSummary {
+        inputs: inputs.len(),
+        blocks_reached: reached.len(),
+        functions_reached: functions.len(),
+        functions_total: emu.functions.len(),
+        functions,
+    };
+    println!("\nReplayed {} inputs, reaching {} blocks in {}/{} functions", summary.inputs,
+             summary.blocks_reached, summary.functions_reached, summary.functions_total);
+
+    if let Some(path) = &args.json {
+        std::fs::write(path, serde_json::to_string_pretty(&summary).unwrap()).unwrap();
+    }
+}
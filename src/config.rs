@@ -2,14 +2,20 @@ use crate::error_exit;
 
 use std::sync::OnceLock;
 
-use clap::Parser;
+use clap::{Args, Parser};
 use parse_int::parse;
 
 /// Method used to track coverage, currently only Edge and Block coverage is implemented
 pub static COV_METHOD: OnceLock<CovMethod> = OnceLock::new();
 
-/// Address at which the fuzzer attempts to create a snapshot once reached
-pub static SNAPSHOT_ADDR: OnceLock<Option<usize>> = OnceLock::new();
+/// Locations at which the fuzzer attempts to create snapshots once reached, optionally alongside
+/// the number of times they need to be hit. Snapshots are taken in order, and fuzz cases are
+/// launched off of the last one
+pub static SNAPSHOTS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Input that is fed to the target while advancing through the snapshots. Fuzz inputs are read
+/// starting from the end of this prefix
+pub static INPUT_PREFIX: OnceLock<Option<Vec<u8>>> = OnceLock::new();
 
 /// Number of cores to run the fuzzer with
 pub static NUM_THREADS: OnceLock<usize> = OnceLock::new();
@@ -20,6 +26,18 @@ pub static OUTPUT_DIR: OnceLock<String> = OnceLock::new();
 /// File that contains the user-supplied dictionary
 pub static DICT_FILE: OnceLock<Option<String>> = OnceLock::new();
 
+/// Highest `@level` of the dictionary entries that are loaded from the dictionary file
+pub static DICT_LEVEL: OnceLock<usize> = OnceLock::new();
+
+/// Context-free grammar that inputs are generated from and mutated with
+pub static GRAMMAR: OnceLock<Option<String>> = OnceLock::new();
+
+/// Shared library that implements a custom mutator
+pub static CUSTOM_MUTATOR: OnceLock<Option<String>> = OnceLock::new();
+
+/// Percentage of the mutated cases that are mutated by the custom mutator
+pub static CUSTOM_MUTATOR_PROB: OnceLock<u32> = OnceLock::new();
+
 /// Input provided as argument to the target being fuzzed
 pub static FUZZ_INPUT: OnceLock<String> = OnceLock::new();
 
@@ -38,6 +56,19 @@ pub static SEND_REMOTE: OnceLock<Option<String>> = OnceLock::new();
 /// coverage tracking so larger magic numbers can still be found through fuzzing
 pub static CMP_COV: OnceLock<bool> = OnceLock::new();
 
+/// Log the operands of comparisons for a single run of each corpus entry, and use them to replace
+/// the parts of the input they were read from (input-to-state replacement)
+pub static CMP_LOG: OnceLock<bool> = OnceLock::new();
+
+/// Record the number of differing bits between the operands of each comparison in a separate
+/// feature map, and keep inputs that reach a new (comparison, differing bits) pair (libFuzzer's
+/// value-profile)
+pub static VALUE_PROFILE: OnceLock<bool> = OnceLock::new();
+
+/// Run a deterministic stage of mutations (bit flips, arithmetic, interesting values, dictionary
+/// entries) on each corpus entry before it is mutated randomly
+pub static DETERMINISTIC: OnceLock<bool> = OnceLock::new();
+
 /// Manually override the automatically calibrated timeout
 pub static OVERRIDE_TIMEOUT: OnceLock<Option<u64>> = OnceLock::new();
 
@@ -49,6 +80,41 @@ pub static FULL_TRACE: OnceLock<bool> = OnceLock::new();
 /// Amount of cases that will be run before the fuzzer automatically shuts down
 pub static RUN_CASES: OnceLock<Option<usize>> = OnceLock::new();
 
+/// Determines how writes to executable memory (self-modifying code) are handled
+pub static SMC_POLICY: OnceLock<SmcPolicy> = OnceLock::new();
+
+/// Addresses or symbols at which a fuzz case is ended as if exit() was called
+pub static EXIT_POINTS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Addresses or symbols that are reported as reached targets, saving off the input that reached
+/// them
+pub static TARGET_POINTS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Locations that the address and length of the fuzz-input are written to once the snapshot is
+/// reached, instead of having the target read the input from a file
+pub static INJECT: OnceLock<Option<String>> = OnceLock::new();
+
+/// Function that is called directly for each fuzz case instead of running the target from its
+/// entry point
+pub static FUNCTION: OnceLock<Option<String>> = OnceLock::new();
+
+/// Arguments that the fuzzed function is called with
+pub static FUNCTION_ARGS: OnceLock<String> = OnceLock::new();
+
+/// Guest values (`LOC:WIDTH:MODE`) that are used as additional coverage feedback
+pub static ANNOTATIONS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Number of entries in the coverage map, always a power of two
+pub static MAP_SIZE: OnceLock<usize> = OnceLock::new();
+
+/// Record the exact key (eg. the pair of block addresses of an edge) behind every coverage map
+/// entry, so the rate at which distinct edges collide in the map can be measured
+pub static MAP_STATS: OnceLock<bool> = OnceLock::new();
+
+/// Record the exact addresses of the blocks that each case reaches, so the coverage of the corpus
+/// can be exported
+pub static TRACE_BLOCKS: OnceLock<bool> = OnceLock::new();
+
 /// Size of memory space allocated for each thread's virtual address space
 pub const MAX_GUEST_ADDR: usize = 64 * 1024 * 1024;
 
@@ -63,9 +129,40 @@ pub enum CovMethod {
     /// Track edge level coverage
     Edge,
 
-    /// Track edge level coverage alongside a call-stack hash to get more fine-grained coverage
-    /// results
+    /// Track edge level coverage alongside a hash of the shadow call stack, so edges are
+    /// distinguished by the calling context they are reached from
     CallStack,
+
+    /// Track how often each edge is hit per case, bucketed into AFL's hit-count classes, so
+    /// inputs that run a loop a different amount of times are considered new coverage
+    HitCount,
+
+    /// Track the sequence of the last N (2-8) blocks instead of just the last edge
+    NGram(usize),
+}
+
+impl CovMethod {
+    /// Default number of entries in the coverage map. N-gram coverage distinguishes far more
+    /// paths than edge coverage, so the map is grown for it to keep hash collisions down
+    pub fn default_map_size(&self) -> usize {
+        match self {
+            CovMethod::NGram(n) if *n >= 4 => 64 * 1024 * 1024,
+            CovMethod::NGram(_) => 32 * 1024 * 1024,
+            _ => 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum SmcPolicy {
+    /// Don't check for writes to executable memory. Stale translations keep being executed
+    Ignore,
+
+    /// Invalidate the translations of modified code so it is recompiled once it is reached again
+    Invalidate,
+
+    /// Treat writes to executable memory as a bug and report them as crashes
+    Crash,
 }
 
 /// Used by clap to parse command-line arguments
@@ -105,6 +202,22 @@ pub struct Cli {
     /// for the fuzzer to get past large magic value comparisons
     pub no_cmp_cov: bool,
 
+    #[clap(long = "cmplog", help_heading = "CONFIG", takes_value = false)]
+    /// - Log the operands of all comparisons the first time an input is picked, and try replacing
+    /// the parts of the input they came from with the values they were compared against
+    pub cmplog: bool,
+
+    #[clap(long = "value-profile", help_heading = "CONFIG", takes_value = false)]
+    /// - Treat inputs that bring the operands of a comparison closer to each other bit-wise than
+    /// before as interesting, even if they did not reach new coverage
+    pub value_profile: bool,
+
+    #[clap(long = "deterministic", help_heading = "CONFIG", takes_value = false)]
+    /// - Run deterministic mutations (walking bit flips, arithmetic, interesting values, and
+    /// dictionary entries at every offset) on each new corpus entry before mutating it randomly.
+    /// Progress is saved to the `deterministic` output directory and resumed on restart
+    pub deterministic: bool,
+
     #[clap(short = 'e', help_heading = "CONFIG")]
     /// - File extension for the fuzz test input file if the target requires it
     pub extension: Option<String>,
@@ -120,11 +233,6 @@ pub struct Cli {
     /// https://github.com/rsalz47/cs326-final-gimel
     pub send_remote: Option<String>,
 
-    #[clap(short = 's', help_heading = "CONFIG")]
-    /// - Take a snapshot of the target at specified address and launch future fuzz-cases off of this
-    /// snapshot
-    pub snapshot: Option<String>,
-
     #[clap(short = 't', help_heading = "CONFIG")]
     /// - Override the timeout that is otherwise dynamically set during calibration phase
     pub override_timeout: Option<u64>,
@@ -139,19 +247,109 @@ pub struct Cli {
     pub full_trace: bool,
 
     #[clap(short = 'd', value_name = "DICT", help_heading = "CONFIG", forbid_empty_values = true)]
-    /// - Optionally supply a new-line separated list of inputs that will be mutated into the 
-    /// fuzz-inputs
+    /// - Optionally supply a dictionary of tokens that will be mutated into the fuzz-inputs, either
+    /// in the AFL/libFuzzer format or as a new-line separated list. Append `@LEVEL` to also load
+    /// entries annotated with a level up to LEVEL. These are merged with the strings and constants
+    /// found in the target
     pub dictionary: Option<String>,
 
+    #[clap(long = "grammar", value_name = "FILE", help_heading = "CONFIG")]
+    /// - Generate seeds from this context-free grammar (JSON or BNF) and mutate them on their
+    /// derivation trees. Seeds from the input directory are still mutated byte-wise
+    pub grammar: Option<String>,
+
+    #[clap(long = "custom-mutator", value_name = "LIB", help_heading = "CONFIG")]
+    /// - Load a custom mutator from this shared library (see `custom_mutator.rs` for the exported
+    /// functions). It is blended with the built-in mutation strategies
+    pub custom_mutator: Option<String>,
+
+    #[clap(long = "custom-mutator-prob", value_name = "PERCENT", default_value_t = 50,
+           help_heading = "CONFIG")]
+    /// - Percentage of the mutated cases that are mutated by the custom mutator instead of the
+    /// built-in strategies
+    pub custom_mutator_prob: u32,
+
     #[clap(short = 'c', help_heading = "CONFIG", default_value = "edge")]
-    /// - Coverage method, currently supports `edge`, `block`, and `call-stack` based coverage
+    /// - Coverage method, currently supports `edge`, `block`, `call-stack`, `hit-count`, and
+    /// `ngram2` through `ngram8` based coverage
     pub cov_method: String,
 
+    #[clap(long = "map-size", value_name = "SIZE", help_heading = "CONFIG")]
+    /// - Number of entries in the coverage map, a power of two between 64K and 1G (eg. `65536` or
+    /// `64M`). Defaults to 16M, or 32M/64M for n-gram coverage
+    pub map_size: Option<String>,
+
+    #[clap(long = "map-stats", help_heading = "CONFIG", takes_value = false)]
+    /// - Track the exact edge or block behind every coverage map entry and show how often distinct
+    /// ones collide in the map. Slows down fuzzing, meant to pick a map size for large targets
+    pub map_stats: bool,
+
+    #[clap(long = "cov-export", help_heading = "CONFIG")]
+    /// - Write the blocks reached by each corpus entry to the `coverage` output directory as drcov
+    /// logs (Lighthouse), alongside an lcov report if the target has debug information
+    pub cov_export: bool,
+
+    #[clap(long = "smc", value_name = "POLICY", help_heading = "CONFIG", default_value = "ignore")]
+    /// - Handling of writes to executable memory (self-modifying code), supports `ignore`,
+    /// `invalidate` (recompile modified code once it is reached, single-threaded only), and
+    /// `crash` (report as bug)
+    pub smc_policy: String,
+
+    #[clap(flatten)]
+    pub harness: HarnessArgs,
+
+    #[clap(long = "annotate", value_name = "LOC:WIDTH:MODE", help_heading = "CONFIG")]
+    /// - Use the WIDTH-byte (1, 2, 4, 8) value at this address or symbol as coverage feedback
+    /// whenever it is loaded or stored. MODE is `set` (every distinct value is new coverage), `max`
+    /// (larger values), or `min` (smaller values). Can be specified multiple times
+    pub annotations: Vec<String>,
+
     #[clap(last = true)]
     /// The target to be fuzzed alongside its arguments
     pub fuzzed_app: Vec<String>,
 }
 
+/// Command-line arguments that determine how the target is set up before each case is run. These
+/// are shared with `sfuzz-cov` so it replays inputs the same way they were fuzzed
+#[derive(Debug, Args)]
+pub struct HarnessArgs {
+    #[clap(short = 's', value_name = "LOC[:HITS]", help_heading = "CONFIG")]
+    /// - Take a snapshot of the target at specified address or symbol (optionally once it has been
+    /// hit HITS times) and launch future fuzz-cases off of this snapshot. If specified multiple
+    /// times, the snapshots are taken in order, each one starting from the previous
+    pub snapshot: Vec<String>,
+
+    #[clap(long = "prefix", value_name = "FILE", help_heading = "CONFIG")]
+    /// - Input that is consumed by the target while advancing through the snapshots. Fuzz-inputs
+    /// are then read as if they directly followed this prefix
+    pub prefix: Option<String>,
+
+    #[clap(long = "inject", value_name = "PTR[,LEN]", help_heading = "CONFIG")]
+    /// - Once the snapshot is reached, place the fuzz-input in an exactly-sized allocation and
+    /// write its address and length to the given registers or addresses (eg. `a0,a1`)
+    pub inject: Option<String>,
+
+    #[clap(long = "function", value_name = "LOC", help_heading = "CONFIG")]
+    /// - Call this function directly for each fuzz case with arguments derived from the
+    /// fuzz-input. The case ends once the function returns
+    pub function: Option<String>,
+
+    #[clap(long = "args", value_name = "SPEC", help_heading = "CONFIG", default_value = "buf,len")]
+    /// - Arguments passed to `--function` in `a0`-`a7`. Supports `buf` and `len` for the input,
+    /// `u8`-`u64` for integers taken from the front of the input, and integer literals
+    pub function_args: String,
+
+    #[clap(long = "exit", value_name = "LOC", help_heading = "CONFIG")]
+    /// - End fuzz cases once this address or symbol (eg. `0x1234`, `parse_header`, or
+    /// `main+0x1c`) is reached. Can be specified multiple times
+    pub exit_points: Vec<String>,
+
+    #[clap(long = "target", value_name = "LOC", help_heading = "CONFIG")]
+    /// - Save inputs that reach this address or symbol to the `targets` output directory and end
+    /// the fuzz case. Can be specified multiple times
+    pub target_points: Vec<String>,
+}
+
 /// Initialize configuration variables based on passed in commandline arguments, and verify that
 /// the user properly setup their fuzz-case
 pub fn handle_cli(args: &mut Cli) {
@@ -161,6 +359,8 @@ pub fn handle_cli(args: &mut Cli) {
     SEND_REMOTE.set(args.send_remote.clone()).unwrap();
     OVERRIDE_TIMEOUT.set(args.override_timeout).unwrap();
     CMP_COV.set(!args.no_cmp_cov).unwrap();
+    CMP_LOG.set(args.cmplog).unwrap();
+    VALUE_PROFILE.set(args.value_profile).unwrap();
 
     if args.fuzzed_app.is_empty() {
         error_exit("You need to specify the target to be fuzzed");
@@ -176,19 +376,83 @@ pub fn handle_cli(args: &mut Cli) {
     OUTPUT_DIR.set(args.output_dir.clone()).unwrap();
 
     if let Some(dict) = &args.dictionary {
+        // Like AFL, `FILE@LEVEL` loads the entries up to the given level
+        let (dict, level) = match dict.rsplit_once('@') {
+            Some((file, level)) if !std::path::Path::new(&dict).is_file() => {
+                (file, parse::<usize>(level).unwrap_or_else(|_| {
+                    error_exit("You need to specify a valid dictionary level");
+                }))
+            },
+            _ => (dict.as_str(), 0),
+        };
         if !std::path::Path::new(&dict).is_file() {
             error_exit("You need to specify a valid dictionary file");
         }
         DICT_FILE.set(Some(dict.to_string())).unwrap();
+        DICT_LEVEL.set(level).unwrap();
     } else {
         DICT_FILE.set(None).unwrap();
+        DICT_LEVEL.set(0).unwrap();
+    }
+
+    if let Some(grammar) = &args.grammar {
+        if !std::path::Path::new(&grammar).is_file() {
+            error_exit("You need to specify a valid grammar file");
+        }
     }
+    GRAMMAR.set(args.grammar.clone()).unwrap();
+
+    if let Some(lib) = &args.custom_mutator {
+        if !std::path::Path::new(&lib).is_file() {
+            error_exit("You need to specify a valid custom mutator library");
+        }
+    }
+    if args.custom_mutator_prob > 100 {
+        error_exit("The custom mutator probability needs to be a percentage (0-100)");
+    }
+    CUSTOM_MUTATOR.set(args.custom_mutator.clone()).unwrap();
+    CUSTOM_MUTATOR_PROB.set(args.custom_mutator_prob).unwrap();
 
     // Create the directory to save crashes too
     let mut crash_dir = args.output_dir.clone();
     crash_dir.push_str("/crashes");
     std::fs::create_dir_all(crash_dir).unwrap();
 
+    // Create the directory to save inputs that are added to the corpus too
+    let mut queue_dir = args.output_dir.clone();
+    queue_dir.push_str("/queue");
+    std::fs::create_dir_all(queue_dir).unwrap();
+
+    // Targets of indirect jumps are appended as they are found, so a list left over from a
+    // previous run is removed
+    let _ = std::fs::remove_file(format!("{}/indirect_targets", args.output_dir));
+
+    // Create the directory that the progress of the deterministic stage is saved to
+    DETERMINISTIC.set(args.deterministic).unwrap();
+    if args.deterministic {
+        let mut det_dir = args.output_dir.clone();
+        det_dir.push_str("/deterministic");
+        std::fs::create_dir_all(det_dir).unwrap();
+    }
+
+    // Create the directory that coverage is exported to
+    TRACE_BLOCKS.set(args.cov_export).unwrap();
+    if args.cov_export {
+        let mut cov_dir = args.output_dir.clone();
+        cov_dir.push_str("/coverage");
+        std::fs::create_dir_all(cov_dir).unwrap();
+    }
+
+    // Annotations are resolved once the symbol table has been loaded
+    ANNOTATIONS.set(args.annotations.clone()).unwrap();
+
+    // Create the directory to save inputs that reached a target too
+    if !args.harness.target_points.is_empty() {
+        let mut target_dir = args.output_dir.clone();
+        target_dir.push_str("/targets");
+        std::fs::create_dir_all(target_dir).unwrap();
+    }
+
     // Set the fuzz-input. If the user specified an extension, add that too
     FUZZ_INPUT.set(
         if let Some(ext) = &args.extension {
@@ -198,20 +462,8 @@ pub fn handle_cli(args: &mut Cli) {
         }
     ).unwrap();
 
-    // Verify that the user supplied `@@` and use it to setup the fuzz-input's argv
-    let index = args.fuzzed_app.iter().position(|e| e == "@@").unwrap_or_else(|| {
-        error_exit("You need to specify how the fuzz-case input files should be passed in. This \
-                   can be done using the `@@` flag as shown in the example under `Usage`.");
-    });
-    args.fuzzed_app[index] = FUZZ_INPUT.get().unwrap().to_string();
-
-    // Set snapshot address if requested
-    if let Some(ss) = &args.snapshot {
-        let num_repr = parse::<usize>(&ss).unwrap();
-        SNAPSHOT_ADDR.set(Some(num_repr)).unwrap();
-    } else {
-        SNAPSHOT_ADDR.set(None).unwrap();
-    }
+    // Setup the snapshots, exit points, and harness that each case is run with
+    handle_harness_args(&args.harness, &mut args.fuzzed_app);
 
     // Set max number of cases if requested
     if let Some(runs) = &args.run_cases {
@@ -232,9 +484,62 @@ pub fn handle_cli(args: &mut Cli) {
         "call-stack" => {
             COV_METHOD.set(CovMethod::CallStack).unwrap();
         },
+        "hit-count" => {
+            COV_METHOD.set(CovMethod::HitCount).unwrap();
+        },
+        method if method.starts_with("ngram") => {
+            match method["ngram".len()..].parse::<usize>() {
+                Ok(n) if (2..=8).contains(&n) => COV_METHOD.set(CovMethod::NGram(n)).unwrap(),
+                _ => error_exit("N-gram coverage supports between 2 and 8 blocks, eg. `ngram4`"),
+            }
+        },
         _ => {
             error_exit("You're specified coverage method is not supported, please chose `edge`, \
-                       `block`, or `call-stack`")
+                       `block`, `call-stack`, `hit-count`, or `ngram2`-`ngram8`")
+        },
+    }
+
+    // Set the size of the coverage map, which has to be a power of two so hashes can be masked
+    // down to an index
+    let map_size = match &args.map_size {
+        Some(size) => {
+            let (num, unit) = match size.to_ascii_uppercase().chars().last() {
+                Some('K') => (&size[..size.len() - 1], 1 << 10),
+                Some('M') => (&size[..size.len() - 1], 1 << 20),
+                Some('G') => (&size[..size.len() - 1], 1 << 30),
+                _ => (size.as_str(), 1),
+            };
+            match parse::<usize>(num).ok().and_then(|e| e.checked_mul(unit)) {
+                Some(v) if v.is_power_of_two() && ((1 << 16)..=(1 << 30)).contains(&v) => v,
+                _ => error_exit("The coverage map size needs to be a power of two between 64K and \
+                                1G, eg. `64M`"),
+            }
+        },
+        None => COV_METHOD.get().unwrap().default_map_size(),
+    };
+    MAP_SIZE.set(map_size).unwrap();
+    MAP_STATS.set(args.map_stats).unwrap();
+
+    // Set the policy for writes to executable memory
+    match args.smc_policy.as_str() {
+        "ignore" => {
+            SMC_POLICY.set(SmcPolicy::Ignore).unwrap();
+        },
+        "invalidate" => {
+            // The lookup table is shared, so other threads would run translations of code that
+            // was only modified in one thread's memory
+            if args.num_threads != 1 {
+                error_exit("The `invalidate` self-modifying code policy only works when running \
+                           single-threaded");
+            }
+            SMC_POLICY.set(SmcPolicy::Invalidate).unwrap();
+        },
+        "crash" => {
+            SMC_POLICY.set(SmcPolicy::Crash).unwrap();
+        },
+        _ => {
+            error_exit("You're specified self-modifying code policy is not supported, please chose \
+                       `ignore`, `invalidate`, or `crash`")
         },
     }
 
@@ -247,7 +552,8 @@ pub fn handle_cli(args: &mut Cli) {
 
     if false {
         println!("cov_method: {:?}", COV_METHOD);
-        println!("snapshot_addr: {:?}", SNAPSHOT_ADDR);
+        println!("snapshots: {:?}", SNAPSHOTS);
+        println!("input_prefix: {:?}", INPUT_PREFIX);
         println!("num_threads: {:?}", NUM_THREADS);
         println!("output_dir: {:?}", OUTPUT_DIR);
         println!("fuzz_input: {:?}", FUZZ_INPUT);
@@ -256,6 +562,69 @@ pub fn handle_cli(args: &mut Cli) {
         println!("send_remote: {:?}", SEND_REMOTE);
         println!("override_timeout: {:?}", OVERRIDE_TIMEOUT);
         println!("full_trace: {:?}", FULL_TRACE);
+        println!("smc_policy: {:?}", SMC_POLICY);
+        println!("exit_points: {:?}", EXIT_POINTS);
+        println!("target_points: {:?}", TARGET_POINTS);
+        println!("annotations: {:?}", ANNOTATIONS);
+        println!("inject: {:?}", INJECT);
+        println!("function: {:?}", FUNCTION);
+        println!("function_args: {:?}", FUNCTION_ARGS);
+        println!("trace_blocks: {:?}", TRACE_BLOCKS);
+        println!("map_size: {:?}", MAP_SIZE);
+        println!("map_stats: {:?}", MAP_STATS);
+        println!("cmp_log: {:?}", CMP_LOG);
+        println!("value_profile: {:?}", VALUE_PROFILE);
+        println!("deterministic: {:?}", DETERMINISTIC);
+    }
+}
+
+/// Initialize the configuration variables that determine how the target is set up before each
+/// case, and replace `@@` in the target's arguments with the fuzz-input. `FUZZ_INPUT` has to be set
+/// before this is called
+pub fn handle_harness_args(args: &HarnessArgs, fuzzed_app: &mut [String]) {
+    // Exit points and targets are resolved once the symbol table has been loaded
+    EXIT_POINTS.set(args.exit_points.clone()).unwrap();
+    TARGET_POINTS.set(args.target_points.clone()).unwrap();
+
+    // Inputs injected into the target's memory require a snapshot to inject them at
+    if args.inject.is_some() && args.snapshot.is_empty() {
+        error_exit("Injecting the fuzz-input requires a snapshot (`-s`) to inject it at");
+    }
+    INJECT.set(args.inject.clone()).unwrap();
+
+    // Arguments for the function harness are resolved once the symbol table is loaded
+    if args.function.is_some() && args.inject.is_some() {
+        error_exit("`--inject` and `--function` can not be used together");
+    }
+    FUNCTION.set(args.function.clone()).unwrap();
+    FUNCTION_ARGS.set(args.function_args.clone()).unwrap();
+
+    // Verify that the user supplied `@@` and use it to setup the fuzz-input's argv. This is not
+    // required if the input is instead directly passed to the target by a harness
+    match fuzzed_app.iter().position(|e| e == "@@") {
+        Some(index) => fuzzed_app[index] = FUZZ_INPUT.get().unwrap().to_string(),
+        None if args.inject.is_some() || args.function.is_some() => {},
+        None => {
+            error_exit("You need to specify how the fuzz-case input files should be passed in. \
+                       This can be done using the `@@` flag as shown in the example under \
+                       `Usage`.");
+        },
+    }
+
+    // Set snapshot locations if requested, these are resolved once the symbol table is loaded
+    SNAPSHOTS.set(args.snapshot.clone()).unwrap();
+
+    // Read in the input prefix used to reach the snapshots
+    if let Some(prefix) = &args.prefix {
+        if args.snapshot.is_empty() {
+            error_exit("An input prefix can only be used alongside snapshots");
+        }
+        let data = std::fs::read(prefix).unwrap_or_else(|_| {
+            error_exit("You need to specify a valid input prefix file");
+        });
+        INPUT_PREFIX.set(Some(data)).unwrap();
+    } else {
+        INPUT_PREFIX.set(None).unwrap();
     }
 }
 
@@ -0,0 +1,238 @@
+use crate::elfparser;
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use rustc_hash::FxHashMap;
+use gimli::{Dwarf, EndianSlice, LittleEndian, SectionId};
+
+/// Source line that the instruction at `addr` was generated from
+#[derive(Debug, Clone, Copy)]
+pub struct LineEntry {
+    /// Address of the instruction
+    pub addr: usize,
+
+    /// Index into the file list of the line table
+    pub file: usize,
+
+    /// Line number within the file
+    pub line: u64,
+}
+
+/// Mapping of instruction addresses to source lines extracted from the target's DWARF information
+#[derive(Debug, Default)]
+pub struct LineTable {
+    /// Source files referenced by the line entries
+    pub files: Vec<String>,
+
+    /// Line entries sorted by address
+    pub entries: Vec<LineEntry>,
+}
+
+/// Information about the fuzzed binary required to export the exact coverage reached by the
+/// fuzzer in the drcov (Lighthouse) and lcov (genhtml) formats
+#[derive(Debug)]
+pub struct CoverageExport {
+    /// Absolute path of the target, used as the module name in drcov logs
+    pub module: String,
+
+    /// Lowest address of the loaded segments
+    pub base: usize,
+
+    /// End address of the highest loaded segment
+    pub end: usize,
+
+    /// Line information, only available if the target was compiled with debug information
+    pub lines: Option<LineTable>,
+
+    /// Held while the lcov report is written so threads write it in the order they took their
+    /// copy of the reached blocks
+    pub lcov_lock: Mutex<()>,
+}
+
+impl CoverageExport {
+    /// Parse the target's loadable segments and line information
+    pub fn new(filename: &str) -> Option<Self> {
+        let target = std::fs::read(filename).ok()?;
+        let elf_hdr = elfparser::Header::new(&target)?;
+
+        // Determine the range that the module is loaded at
+        let mut base = usize::MAX;
+        let mut end = 0;
+        let mut offset = elf_hdr.phoff - elf_hdr.phentsize as usize;
+        for _ in 0..elf_hdr.phnum {
+            offset += elf_hdr.phentsize as usize;
+            let program_hdr = elfparser::ProgramHeader::new(&target[offset..])?;
+
+            if program_hdr.seg_type == elfparser::LOADSEGMENT {
+                base = base.min(program_hdr.vaddr);
+                end = end.max(program_hdr.vaddr + program_hdr.memsz);
+            }
+        }
+
+        // Collect the contents of all sections by name so the debug sections can be loaded
+        let shstrtab_off = elf_hdr.shoff + elf_hdr.shstrndx as usize * elf_hdr.shentsize as usize;
+        let shstrtab = elfparser::SectionHeader::new(&target[shstrtab_off..])?;
+        let mut sections: FxHashMap<String, &[u8]> = FxHashMap::default();
+
+        offset = elf_hdr.shoff - elf_hdr.shentsize as usize;
+        for _ in 0..elf_hdr.shnum {
+            offset += elf_hdr.shentsize as usize;
+            let section_hdr = elfparser::SectionHeader::new(&target[offset..])?;
+
+            let str_start = shstrtab.s_offset + section_hdr.s_name as usize;
+            let str_size  = target[str_start..].iter().position(|&b| b == 0)?;
+            let name = std::str::from_utf8(&target[str_start..str_start + str_size]).ok()?;
+            if section_hdr.s_type != 0x8 {
+                sections.insert(name.to_string(), target.get(section_hdr.s_offset..
+                    section_hdr.s_offset.checked_add(section_hdr.s_size)?)?);
+            }
+        }
+
+        let lines = if sections.contains_key(".debug_line") {
+            load_line_table(&sections)
+        } else {
+            None
+        };
+
+        let module = std::fs::canonicalize(filename).ok()?.to_string_lossy().to_string();
+        Some(Self { module, base, end, lines, lcov_lock: Mutex::new(()) })
+    }
+
+    /// Write a drcov log containing the given blocks (address, size in bytes) to `path`. These
+    /// can be loaded into IDA, Binary Ninja, or Ghidra using Lighthouse
+    pub fn write_drcov(&self, path: &str, blocks: &[(usize, usize)]) -> std::io::Result<()> {
+        let mut out = Vec::new();
+        writeln!(out, "DRCOV VERSION: 2")?;
+        writeln!(out, "DRCOV FLAVOR: sfuzz")?;
+        writeln!(out, "Module Table: version 2, count 1")?;
+        writeln!(out, "Columns: id, base, end, entry, checksum, timestamp, path")?;
+        writeln!(out, " 0, {:#x}, {:#x}, 0x0, 0x0, 0x0, {}", self.base, self.end, self.module)?;
+        writeln!(out, "BB Table: {} bbs", blocks.len())?;
+
+        // Each block is stored as a 32-bit offset from the module base, a 16-bit size and a 16-bit
+        // module id
+        for &(addr, size) in blocks {
+            out.extend_from_slice(&((addr - self.base) as u32).to_le_bytes());
+            out.extend_from_slice(&(size as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+        }
+        std::fs::write(path, out)
+    }
+
+    /// Write an lcov tracefile to `path` that covers every source line of the target. `blocks`
+    /// maps the address of each reached block to its size in bytes and the amount of inputs that
+    /// reached it. Nothing is written if the target has no line information
+    pub fn write_lcov(&self, path: &str, blocks: &FxHashMap<usize, (usize, usize)>)
+            -> std::io::Result<()> {
+        let lines = match &self.lines {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        // Number of inputs that reached each instruction
+        let mut hits: FxHashMap<usize, usize> = FxHashMap::default();
+        for (&addr, &(size, count)) in blocks {
+            for instr in (addr..addr + size).step_by(4) {
+                hits.insert(instr, count);
+            }
+        }
+
+        // A line is hit as often as the most frequently reached instruction generated from it
+        let mut file_lines: Vec<FxHashMap<u64, usize>> = vec![FxHashMap::default();
+            lines.files.len()];
+        for entry in &lines.entries {
+            let count = file_lines[entry.file].entry(entry.line).or_insert(0);
+            *count = (*count).max(hits.get(&entry.addr).copied().unwrap_or(0));
+        }
+
+        let mut out = Vec::new();
+        writeln!(out, "TN:")?;
+        for (file, line_hits) in lines.files.iter().zip(file_lines) {
+            if line_hits.is_empty() {
+                continue;
+            }
+            let mut line_hits: Vec<(u64, usize)> = line_hits.into_iter().collect();
+            line_hits.sort_unstable();
+
+            writeln!(out, "SF:{}", file)?;
+            for (line, count) in &line_hits {
+                writeln!(out, "DA:{},{}", line, count)?;
+            }
+            writeln!(out, "LH:{}", line_hits.iter().filter(|e| e.1 != 0).count())?;
+            writeln!(out, "LF:{}", line_hits.len())?;
+            writeln!(out, "end_of_record")?;
+        }
+        std::fs::write(path, out)
+    }
+}
+
+/// Run the line number programs of all compilation units in the DWARF information to map
+/// instruction addresses to source lines
+fn load_line_table(sections: &FxHashMap<String, &[u8]>) -> Option<LineTable> {
+    let dwarf = Dwarf::load(|id: SectionId| -> Result<EndianSlice<LittleEndian>, gimli::Error> {
+        Ok(EndianSlice::new(sections.get(id.name()).copied().unwrap_or(&[]), LittleEndian))
+    }).ok()?;
+
+    let mut table = LineTable::default();
+    let mut file_ids: FxHashMap<String, usize> = FxHashMap::default();
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next().ok()? {
+        let unit = dwarf.unit(header).ok()?;
+        let program = match unit.line_program.clone() {
+            Some(v) => v,
+            None => continue,
+        };
+
+        // Files are resolved once per unit since each unit has its own file list
+        let mut unit_files: FxHashMap<u64, usize> = FxHashMap::default();
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row().ok()? {
+            if row.end_sequence() {
+                continue;
+            }
+            let line = match row.line() {
+                Some(v) => v.get(),
+                None => continue,
+            };
+
+            let file = match unit_files.get(&row.file_index()) {
+                Some(v) => *v,
+                None => {
+                    let file = match row.file(header) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+
+                    // Relative paths are relative to the include directory, which in turn can be
+                    // relative to the compilation directory
+                    let mut path = PathBuf::new();
+                    if let Some(dir) = &unit.comp_dir {
+                        path.push(dir.to_string_lossy().as_ref());
+                    }
+                    if let Some(dir) = file.directory(header) {
+                        path.push(dwarf.attr_string(&unit, dir).ok()?.to_string_lossy().as_ref());
+                    }
+                    path.push(dwarf.attr_string(&unit, file.path_name()).ok()?
+                              .to_string_lossy().as_ref());
+                    let path = path.to_string_lossy().to_string();
+
+                    let next_id = table.files.len();
+                    let id = *file_ids.entry(path.clone()).or_insert(next_id);
+                    if id == next_id {
+                        table.files.push(path);
+                    }
+                    unit_files.insert(row.file_index(), id);
+                    id
+                },
+            };
+
+            table.entries.push(LineEntry { addr: row.address() as usize, file, line });
+        }
+    }
+
+    table.entries.sort_unstable_by_key(|e| e.addr);
+    Some(table)
+}
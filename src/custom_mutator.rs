@@ -0,0 +1,275 @@
+use libloading::Library;
+
+use std::ffi::c_void;
+
+/// Largest input a custom mutator is allowed to produce
+pub const MAX_INPUT_SIZE: usize = 1024 * 1024;
+
+/// Format-specific mutator that is blended with the built-in mutation strategies
+pub trait CustomMutator {
+    /// Seed the mutator. Called once before any other hook
+    fn init(&mut self, seed: u32);
+
+    /// Mutate `input` in place. `add_input` is a different corpus entry that can be used for
+    /// splicing, and the result may not be larger than `max_size`
+    fn mutate(&mut self, input: &mut Vec<u8>, add_input: &[u8], max_size: usize);
+
+    /// Whether this mutator implements trimming. If it does, each corpus entry is trimmed the
+    /// first time it is picked
+    fn can_trim(&self) -> bool {
+        false
+    }
+
+    /// Start trimming `input`. Returns the number of trimming steps
+    fn init_trim(&mut self, _input: &[u8]) -> usize {
+        0
+    }
+
+    /// Return the next trimmed candidate
+    fn trim(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Report whether the last candidate kept the behavior of the input. Returns the index of the
+    /// next step, trimming is done once it reaches the number of steps
+    fn post_trim(&mut self, _success: bool) -> usize {
+        usize::MAX
+    }
+
+    /// Fix up an input right before it is run (eg. checksums or length fields). This is applied
+    /// to every mutated input, including those of the built-in strategies. Results that are larger
+    /// than `MAX_INPUT_SIZE` are truncated
+    fn post_process(&mut self, _input: &mut Vec<u8>) {}
+}
+
+/// Run the trimming steps of `custom` on `input`. `run` executes each candidate and returns whether
+/// it kept the behavior of the input, in which case the candidate replaces the input if it is
+/// shorter. Returns the trimmed input alongside the number of candidates that were run
+pub fn trim_with(custom: &mut dyn CustomMutator, input: &[u8],
+                 mut run: impl FnMut(&[u8]) -> bool) -> (Vec<u8>, usize) {
+    let mut trimmed = input.to_vec();
+    let mut num_cases = 0;
+
+    let steps = custom.init_trim(&trimmed);
+    let mut step = 0;
+    while step < steps {
+        let candidate = custom.trim();
+        num_cases += 1;
+
+        let success = run(&candidate) && !candidate.is_empty() && candidate.len() < trimmed.len();
+        if success {
+            trimmed = candidate;
+        }
+        step = custom.post_trim(success);
+    }
+    (trimmed, num_cases)
+}
+
+type InitFn = unsafe extern "C" fn(u32) -> *mut c_void;
+type FuzzFn = unsafe extern "C" fn(*mut c_void, *mut u8, usize, *mut *mut u8, *mut u8, usize,
+                                   usize) -> usize;
+type InitTrimFn = unsafe extern "C" fn(*mut c_void, *mut u8, usize) -> i32;
+type TrimFn = unsafe extern "C" fn(*mut c_void, *mut *mut u8) -> usize;
+type PostTrimFn = unsafe extern "C" fn(*mut c_void, u8) -> i32;
+type PostProcessFn = unsafe extern "C" fn(*mut c_void, *mut u8, usize, *mut *mut u8) -> usize;
+type DeinitFn = unsafe extern "C" fn(*mut c_void);
+
+/// Custom mutator that forwards the hooks to a shared library, modeled on AFL++'s custom mutator
+/// API. The library exports C functions prefixed with `sfuzz_custom_`:
+///
+/// ```c
+/// // Required
+/// void  *sfuzz_custom_init(unsigned int seed);
+/// size_t sfuzz_custom_fuzz(void *data, uint8_t *buf, size_t buf_size, uint8_t **out_buf,
+///                          uint8_t *add_buf, size_t add_buf_size, size_t max_size);
+///
+/// // Optional
+/// int32_t sfuzz_custom_init_trim(void *data, uint8_t *buf, size_t buf_size);
+/// size_t  sfuzz_custom_trim(void *data, uint8_t **out_buf);
+/// int32_t sfuzz_custom_post_trim(void *data, uint8_t success);
+/// size_t  sfuzz_custom_post_process(void *data, uint8_t *buf, size_t buf_size,
+///                                   uint8_t **out_buf);
+/// void    sfuzz_custom_deinit(void *data);
+/// ```
+///
+/// Output buffers are owned by the library and only have to stay valid until the next call. Every
+/// worker thread calls `sfuzz_custom_init` to get its own instance.
+pub struct SharedLibMutator {
+    /// Keeps the library loaded for as long as the function pointers below are used
+    _lib: Library,
+
+    /// State returned by `sfuzz_custom_init`, passed to all other hooks
+    data: *mut c_void,
+
+    init_fn:         InitFn,
+    fuzz_fn:         FuzzFn,
+    init_trim_fn:    Option<InitTrimFn>,
+    trim_fn:         Option<TrimFn>,
+    post_trim_fn:    Option<PostTrimFn>,
+    post_process_fn: Option<PostProcessFn>,
+    deinit_fn:       Option<DeinitFn>,
+}
+
+impl SharedLibMutator {
+    /// Load the custom mutator from the shared library at `path`. Fails if the library could not
+    /// be loaded or does not export the required hooks
+    pub fn load(path: &str) -> Result<Self, String> {
+        unsafe {
+            let lib = Library::new(path).map_err(|e| e.to_string())?;
+
+            let init_fn = *lib.get::<InitFn>(b"sfuzz_custom_init\0")
+                .map_err(|e| e.to_string())?;
+            let fuzz_fn = *lib.get::<FuzzFn>(b"sfuzz_custom_fuzz\0")
+                .map_err(|e| e.to_string())?;
+            let init_trim_fn = lib.get::<InitTrimFn>(b"sfuzz_custom_init_trim\0").ok().map(|e| *e);
+            let trim_fn = lib.get::<TrimFn>(b"sfuzz_custom_trim\0").ok().map(|e| *e);
+            let post_trim_fn = lib.get::<PostTrimFn>(b"sfuzz_custom_post_trim\0").ok().map(|e| *e);
+            let post_process_fn = lib.get::<PostProcessFn>(b"sfuzz_custom_post_process\0").ok()
+                .map(|e| *e);
+            let deinit_fn = lib.get::<DeinitFn>(b"sfuzz_custom_deinit\0").ok().map(|e| *e);
+
+            Ok(Self {
+                _lib: lib,
+                data: std::ptr::null_mut(),
+                init_fn,
+                fuzz_fn,
+                init_trim_fn,
+                trim_fn,
+                post_trim_fn,
+                post_process_fn,
+                deinit_fn,
+            })
+        }
+    }
+}
+
+impl CustomMutator for SharedLibMutator {
+    fn init(&mut self, seed: u32) {
+        self.data = unsafe { (self.init_fn)(seed) };
+    }
+
+    fn mutate(&mut self, input: &mut Vec<u8>, add_input: &[u8], max_size: usize) {
+        let mut add_input = add_input.to_vec();
+        let mut out: *mut u8 = std::ptr::null_mut();
+        let size = unsafe {
+            (self.fuzz_fn)(self.data, input.as_mut_ptr(), input.len(), &mut out,
+                           add_input.as_mut_ptr(), add_input.len(), max_size)
+        };
+        if !out.is_null() {
+            let mutated = unsafe { std::slice::from_raw_parts(out, size.min(max_size)) }.to_vec();
+            *input = mutated;
+        }
+    }
+
+    fn can_trim(&self) -> bool {
+        self.init_trim_fn.is_some() && self.trim_fn.is_some() && self.post_trim_fn.is_some()
+    }
+
+    fn init_trim(&mut self, input: &[u8]) -> usize {
+        let mut input = input.to_vec();
+        let steps = unsafe {
+            (self.init_trim_fn.unwrap())(self.data, input.as_mut_ptr(), input.len())
+        };
+        steps.max(0) as usize
+    }
+
+    fn trim(&mut self) -> Vec<u8> {
+        let mut out: *mut u8 = std::ptr::null_mut();
+        let size = unsafe { (self.trim_fn.unwrap())(self.data, &mut out) };
+        if out.is_null() {
+            return Vec::new();
+        }
+        unsafe { std::slice::from_raw_parts(out, size.min(MAX_INPUT_SIZE)) }.to_vec()
+    }
+
+    fn post_trim(&mut self, success: bool) -> usize {
+        let step = unsafe { (self.post_trim_fn.unwrap())(self.data, success as u8) };
+
+        // Negative return values indicate an error, which stops the trimming
+        usize::try_from(step).unwrap_or(usize::MAX)
+    }
+
+    fn post_process(&mut self, input: &mut Vec<u8>) {
+        if let Some(post_process_fn) = self.post_process_fn {
+            let mut out: *mut u8 = std::ptr::null_mut();
+            let size = unsafe { post_process_fn(self.data, input.as_mut_ptr(), input.len(),
+                                                &mut out) };
+            if !out.is_null() {
+                *input = unsafe {
+                    std::slice::from_raw_parts(out, size.min(MAX_INPUT_SIZE))
+                }.to_vec();
+            }
+        }
+    }
+}
+
+impl Drop for SharedLibMutator {
+    fn drop(&mut self) {
+        if let (Some(deinit_fn), false) = (self.deinit_fn, self.data.is_null()) {
+            unsafe { deinit_fn(self.data) };
+        }
+    }
+}
+
+/// Unit tests for custom mutators implemented in-process
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trims the input by removing one byte at a time from the end
+    struct TrimEnd {
+        input: Vec<u8>,
+        len: usize,
+    }
+
+    impl CustomMutator for TrimEnd {
+        fn init(&mut self, _seed: u32) {}
+
+        fn mutate(&mut self, input: &mut Vec<u8>, _add_input: &[u8], _max_size: usize) {
+            input.push(b'!');
+        }
+
+        fn can_trim(&self) -> bool {
+            true
+        }
+
+        fn init_trim(&mut self, input: &[u8]) -> usize {
+            self.input = input.to_vec();
+            self.len = input.len();
+            input.len()
+        }
+
+        fn trim(&mut self) -> Vec<u8> {
+            self.input[..self.len - 1].to_vec()
+        }
+
+        fn post_trim(&mut self, success: bool) -> usize {
+            if success {
+                self.len -= 1;
+                self.input.len() - self.len
+            } else {
+                usize::MAX
+            }
+        }
+    }
+
+    #[test]
+    fn trim_flow() {
+        let mut custom = TrimEnd { input: Vec::new(), len: 0 };
+
+        // Candidates are kept until one changes the behavior of the input
+        let mut runs = Vec::new();
+        let (trimmed, num_cases) = trim_with(&mut custom, b"KEY1234", |candidate| {
+            runs.push(candidate.to_vec());
+            candidate.starts_with(b"KEY")
+        });
+        assert_eq!(trimmed, b"KEY");
+        assert_eq!(num_cases, 5);
+        assert_eq!(runs.last().unwrap(), b"KE");
+
+        // Empty candidates never replace the input
+        let (trimmed, num_cases) = trim_with(&mut custom, b"ab", |_| true);
+        assert_eq!(trimmed, b"a");
+        assert_eq!(num_cases, 2);
+    }
+}
@@ -2,20 +2,26 @@ use crate::{
     mmu::{Mmu, Perms},
     elfparser,
     riscv::{decode_instr, Instr},
-    jit::{Jit, LibFuncs, CompileInputs},
+    jit::{Jit, LibFuncs, CompileInputs, CALL_STACK_SIZE},
+    harness::Harness,
+    annotation::Annotation,
     irgraph::{IRGraph, Flag},
     emulator::FileType::{STDIN, STDOUT, STDERR},
     pretty_printing::{LogType, log},
-    config::NUM_THREADS,
-    syscalls, Corpus, error_exit,
+    config::{NUM_THREADS, SMC_POLICY, SmcPolicy, COV_METHOD, CovMethod,
+        TRACE_BLOCKS, MAP_STATS, DETERMINISTIC},
+    syscalls, Corpus, HitCounts, CmpLog, CmpLogEntry, IndirectJumps, MapCollisions,
+    error_exit,
 };
 
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::arch::asm;
 use std::collections::BTreeMap;
 
 use rustc_hash::FxHashMap;
 use iced_x86::code_asm::*;
+use fasthash::{xx::Hash32, FastHash};
 
 /// 33 RISCV Registers
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Ord, PartialOrd)]
@@ -72,6 +78,20 @@ impl Register {
     pub fn get_offset(&self) -> u64 {
         *self as u64 * 8
     }
+
+    /// Look up a register using its ABI name (eg. `a0` or `sp`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        const NAMES: [&str; 33] = [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3",
+            "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+            "t3", "t4", "t5", "t6", "pc",
+        ];
+
+        if name == "fp" {
+            return Some(Register::S0);
+        }
+        NAMES.iter().position(|&e| e == name).map(|i| Register::from(i as u32))
+    }
 }
 
 impl From<u32> for Register {
@@ -116,9 +136,16 @@ pub enum Fault {
     /// Fault occurs when there is no more room to service new allocations
     OOM,
 
+    /// Fault occurs when executable memory is written to while the `crash` policy for
+    /// self-modifying code is in use
+    CodeWrite(usize),
+
     /// Process called exit
     Exit,
 
+    /// Reached an address that was specified as a target
+    Success(usize),
+
     /// Snapshot taken for deterministic fuzzing
     Snapshot,
 
@@ -222,15 +249,48 @@ pub struct Emulator {
     /// Map of exit conditions that would cause the fuzzer to prematurely exit
     pub exit_conds: FxHashMap<usize, ExitType>,
 
+    /// Guest values that are used as additional coverage feedback
+    pub annotations: Vec<Annotation>,
+
     /// JIT-backing-address at which the injected code for the snapshot is located
     pub snapshot_addr: usize,
 
+    /// Address of the snapshot that is currently being taken alongside the number of times it
+    /// still needs to be hit before the snapshot is taken
+    pub snapshot_target: Option<(usize, usize)>,
+
     /// If a fuzz case reaches this amount of instructions it will be manually terminated
     pub timeout: u64,
 
     /// Thread-shared mutex that is used to lock compilation so that only one thread can compile
     /// code at a time
     pub prevent_rc: Arc<Mutex<usize>>,
+
+    /// Functions whose code was modified during the current fuzz case
+    pub modified_code: Vec<usize>,
+
+    /// Harness used to pass the fuzz-input to the target instead of having it read in a file
+    pub harness: Option<Harness>,
+
+    /// Edge hit counters of the current fuzz case, only allocated when hit-count coverage is used
+    pub hit_counts: HitCounts,
+
+    /// Blocks reached during the current fuzz case, indexed by address / 4. Only allocated when
+    /// blocks are traced
+    pub block_hits: HitCounts,
+
+    /// Comparisons executed during the current fuzz case, only recorded while enabled
+    pub cmp_log: CmpLog,
+
+    /// Indirect jumps that reached new coverage during the current fuzz case
+    pub indirect_jumps: IndirectJumps,
+
+    /// Coverage map keys that collided during the current fuzz case, only allocated with map stats
+    pub map_collisions: MapCollisions,
+
+    /// Shadow call stack holding the calling context hash of each active call, followed by the
+    /// return address of each call. Only allocated when call-stack coverage is used
+    pub call_stack: Vec<u64>,
 }
 
 impl Emulator {
@@ -246,9 +306,19 @@ impl Emulator {
             jit,
             fuzz_input: Vec::new(),
             exit_conds: FxHashMap::default(),
+            annotations: Vec::new(),
             snapshot_addr: 0,
+            snapshot_target: None,
             timeout: 0xffffffffffffffff,
             prevent_rc,
+            modified_code: Vec::new(),
+            harness: None,
+            hit_counts: HitCounts::default(),
+            block_hits: HitCounts::default(),
+            cmp_log:    CmpLog::default(),
+            indirect_jumps: IndirectJumps::default(),
+            map_collisions: MapCollisions::default(),
+            call_stack: Vec::new(),
         }
     }
 
@@ -265,9 +335,19 @@ impl Emulator {
             jit:        self.jit.clone(),
             fuzz_input: self.fuzz_input.clone(),
             exit_conds: self.exit_conds.clone(),
+            annotations: self.annotations.clone(),
             snapshot_addr: self.snapshot_addr,
+            snapshot_target: self.snapshot_target,
             timeout: self.timeout,
             prevent_rc: self.prevent_rc.clone(),
+            modified_code: Vec::new(),
+            harness: self.harness.clone(),
+            hit_counts: HitCounts::default(),
+            block_hits: HitCounts::default(),
+            cmp_log:    CmpLog::default(),
+            indirect_jumps: IndirectJumps::default(),
+            map_collisions: MapCollisions::default(),
+            call_stack: Vec::new(),
         }
     }
 
@@ -278,6 +358,24 @@ impl Emulator {
 
         self.fd_list = original.fd_list.clone();
         self.prevent_rc = original.prevent_rc.clone();
+
+        // Code modified during the last case was restored alongside the memory, so translations
+        // compiled from the modified code are no longer valid
+        for func in std::mem::take(&mut self.modified_code) {
+            self.invalidate_function(func);
+        }
+    }
+
+    /// Prepare the emulator to run the current fuzz-input. If a harness is in use, this passes the
+    /// input to the target
+    pub fn prepare_case(&mut self) -> Result<(), Fault> {
+        let harness = self.harness.take();
+        let ret = match &harness {
+            Some(v) => v.prepare(self),
+            None => Ok(()),
+        };
+        self.harness = harness;
+        ret
     }
 
     /// Allocate a new file in the emulator
@@ -316,6 +414,57 @@ impl Emulator {
         self.memory.free(addr)
     }
 
+    /// Returns the start address of the function that contains `addr`
+    pub fn function_containing(&self, addr: usize) -> Option<usize> {
+        if self.functions.contains_key(&addr) {
+            return Some(addr);
+        }
+        self.functions.iter()
+            .find(|(&start, &(size, _))| addr >= start && addr < start + size)
+            .map(|(&start, _)| start)
+    }
+
+    /// Hash of the code that currently makes up the function at `func`
+    fn code_hash(&self, func: usize) -> u32 {
+        let size = self.functions.get(&func).unwrap().0;
+        Hash32::hash(&self.memory.memory[func..func + size])
+    }
+
+    /// Remove the translation of a function from the jit lookup table so it is recompiled from
+    /// the current memory contents once it is reached again
+    fn invalidate_function(&self, func: usize) {
+        let size = self.functions.get(&func).unwrap().0;
+        let _v = self.prevent_rc.lock().unwrap();
+        self.jit.invalidate(func, func + size, true);
+    }
+
+    /// Handle a write to executable memory at `addr` that was performed by the instruction at `pc`
+    /// based on the configured self-modifying code policy
+    fn handle_code_write(&mut self, pc: usize, addr: usize) -> Option<Fault> {
+        match SMC_POLICY.get().unwrap() {
+            SmcPolicy::Ignore => None,
+            SmcPolicy::Crash => Some(Fault::CodeWrite(pc)),
+            SmcPolicy::Invalidate => {
+                let _v = self.prevent_rc.lock().unwrap();
+
+                // Translations that jump directly into the written page are dropped entirely
+                for func in self.jit.write_code_page(addr) {
+                    let size = self.functions.get(&func).map_or(4, |f| f.0);
+                    self.jit.invalidate(func, func + size, false);
+                }
+
+                if let Some(func) = self.function_containing(addr) {
+                    let size = self.functions.get(&func).unwrap().0;
+                    self.jit.invalidate(func, func + size, true);
+                    if !self.modified_code.contains(&func) {
+                        self.modified_code.push(func);
+                    }
+                }
+                None
+            },
+        }
+    }
+
     /// Runs the jit until exit/crash. It checks if the code at `pc` has already been compiled. If
     /// not it starts by initiating the procedure to compile the code. At this point it has the
     /// jitcache address of where `pc` is jit compiled too. Next it sets up various arguments and
@@ -324,15 +473,40 @@ impl Emulator {
     /// Once the jit exits it collects the reentry_pc (where to continue execution), and the exit
     /// code. It performs an appropriate operation based on the exit code and then continues with
     /// the loop to reenter the jit.
+    /// Returns the fault that ended the case alongside the amount of new coverage, new CmpCov, new
+    /// comparison progress, and new value-profile features that the case found
     pub fn run_jit(&mut self, corpus: &Corpus, instr_count: &mut u64, trace_arr: &mut [u64],
-                   trace_arr_len: &mut usize) -> (Option<Fault>, usize, usize) {
+                   trace_arr_len: &mut usize) -> (Option<Fault>, usize, usize, usize, usize) {
+        // Each emulator fork gets its own hit counters once it first runs a case. These are also
+        // used to compare the paths taken by the cases of the deterministic stage
+        let hit_counts = *COV_METHOD.get().unwrap() == CovMethod::HitCount ||
+            (*DETERMINISTIC.get().unwrap() && *COV_METHOD.get().unwrap() != CovMethod::None);
+        if hit_counts && self.hit_counts.counts.is_empty() {
+            self.hit_counts = HitCounts::new(corpus.coverage_bytemap.len());
+        }
+        if *TRACE_BLOCKS.get().unwrap() && self.block_hits.counts.is_empty() {
+            self.block_hits = HitCounts::new(self.memory.memory.len() / 4);
+        }
+        if self.cmp_log.enabled && self.cmp_log.entries.is_empty() {
+            self.cmp_log.entries = vec![CmpLogEntry::default(); CmpLog::MAX_ENTRIES];
+        }
+        if *COV_METHOD.get().unwrap() != CovMethod::None && self.indirect_jumps.entries.is_empty() {
+            self.indirect_jumps.entries = vec![[0; 2]; IndirectJumps::MAX_ENTRIES];
+        }
+        if *MAP_STATS.get().unwrap() && self.map_collisions.entries.is_empty() {
+            self.map_collisions.entries = vec![0; MapCollisions::MAX_ENTRIES];
+        }
+        if *COV_METHOD.get().unwrap() == CovMethod::CallStack && self.call_stack.is_empty() {
+            self.call_stack = vec![0; CALL_STACK_SIZE * 2];
+        }
+
         // Extra space when the available registers are not enough to pass sufficient
         // information in/out of the jit
         let mut scratchpad = [
             // 0 - 0x00 - Used to extract snapshot addr
             0usize,
 
-            // 1 - 0x08 - Free
+            // 1 - 0x08 - Address written to by a store that modified executable memory
             0usize,
 
             // 2 - 0x10 - CmpCov bitmap
@@ -350,7 +524,7 @@ impl Emulator {
             // 6 - 0x30 - Coverage byte-map
             corpus.coverage_bytemap.as_ptr() as usize,
 
-            // 7 - 0x38 - Accumulated coverage hash of current input
+            // 7 - 0x38 - Hash of the current calling context, used by call-stack coverage
             0usize,
 
             // 8 - 0x40 - Previous block
@@ -362,43 +536,167 @@ impl Emulator {
             // 10 - 0x50 - Used by coverage event, address that needs to be overwritten with a 1 to
             // indicate that the coverage event has already been hit
             0usize,
+
+            // 11 - 0x58 - Per-case edge hit counters
+            self.hit_counts.counts.as_mut_ptr() as usize,
+
+            // 12 - 0x60 - Indices of the edges that were hit during this case
+            self.hit_counts.touched.as_mut_ptr() as usize,
+
+            // 13 - 0x68 - Pointer to the number of edges that were hit during this case
+            &mut self.hit_counts.num_touched as *mut usize as usize,
+
+            // 14 - 0x70 - Per-case block hit counters
+            self.block_hits.counts.as_mut_ptr() as usize,
+
+            // 15 - 0x78 - Indices of the blocks that were hit during this case
+            self.block_hits.touched.as_mut_ptr() as usize,
+
+            // 16 - 0x80 - Pointer to the number of blocks that were hit during this case
+            &mut self.block_hits.num_touched as *mut usize as usize,
+
+            // 17 - 0x88 - Comparison progress counter
+            0usize,
+
+            // 18 - 0x90 - Comparison log, null while comparisons are not being logged
+            if self.cmp_log.enabled { self.cmp_log.entries.as_mut_ptr() as usize } else { 0 },
+
+            // 19 - 0x98 - Pointer to the number of comparisons executed during this case
+            &mut self.cmp_log.num_entries as *mut usize as usize,
+
+            // 20 - 0xa0 - Shadow call stack
+            self.call_stack.as_mut_ptr() as usize,
+
+            // 21 - 0xa8 - Depth of the shadow call stack
+            0usize,
+
+            // 22 - 0xb0 - Hash of the last N blocks, used by n-gram coverage
+            0usize,
+
+            // 23 - 0xb8 - Index of the oldest block hash in the n-gram ring
+            0usize,
+
+            // 24-31 - 0xc0-0xf8 - Ring of the hashes of the last N blocks
+            0usize, 0usize, 0usize, 0usize, 0usize, 0usize, 0usize, 0usize,
+
+            // 32 - 0x100 - Value-profile feature map
+            corpus.value_profile_map.as_ptr() as usize,
+
+            // 33 - 0x108 - Value-profile counter
+            0usize,
+
+            // 34 - 0x110 - Indirect jumps that reached new coverage during this case
+            self.indirect_jumps.entries.as_mut_ptr() as usize,
+
+            // 35 - 0x118 - Pointer to the number of indirect jumps that reached new coverage
+            &mut self.indirect_jumps.num_entries as *mut usize as usize,
+
+            // 36 - 0x120 - Best value observed for each annotation
+            corpus.annotation_best.as_ptr() as usize,
+
+            // 37 - 0x128 - Exact key behind each coverage map entry, only used with map stats
+            corpus.coverage_keys.as_ptr() as usize,
+
+            // 38 - 0x130 - Filter of the coverage map keys that were already logged as collisions
+            corpus.collision_filter.as_ptr() as usize,
+
+            // 39 - 0x138 - Pointer to the number of claimed coverage map entries
+            &corpus.map_keys as *const AtomicUsize as usize,
+
+            // 40 - 0x140 - Coverage map keys that collided during this case
+            self.map_collisions.entries.as_mut_ptr() as usize,
+
+            // 41 - 0x148 - Pointer to the number of coverage map keys that collided
+            &mut self.map_collisions.num_entries as *mut usize as usize,
+
+            // 42 - 0x150 - CmpCov bits reserved by the call site of a compare function, usize::MAX
+            // if it was called through a function pointer
+            usize::MAX,
+
+            // 43 - 0x158 - Bytemap of the (site, target) pairs of indirect jumps
+            corpus.indirect_bytemap.as_ptr() as usize,
         ];
 
+        // The fault that ended the case alongside the counters the JIT updated in the scratchpad
+        macro_rules! case_result {
+            ($fault: expr) => {
+                ($fault, scratchpad[9], scratchpad[3], scratchpad[17], scratchpad[33])
+            }
+        }
+
+        // JIT-address at which execution is resumed instead of the address that `pc` maps to. Used
+        // to skip over injected snapshot code that should not yet cause a snapshot
+        let mut resume_addr: Option<usize> = None;
+
         loop {
             let pc = self.get_reg(Register::Pc);
 
             // Error out if code was unaligned.
             // since Riscv instructions are always 4-byte aligned this is a bug
-            if pc & 3 != 0 { return (Some(Fault::ExecFault(pc)), scratchpad[9], scratchpad[3]); }
+            if pc & 3 != 0 {
+                return case_result!(Some(Fault::ExecFault(pc)));
+            }
+
+            // Check if an exit condition was reached. This is done here in addition to the JIT so
+            // exit conditions also work on functions that are hooked and thus never compiled
+            match self.exit_conds.get(&pc) {
+                Some(ExitType::Exit) => {
+                    return case_result!(Some(Fault::Exit));
+                },
+                Some(ExitType::Success) => {
+                    return case_result!(Some(Fault::Success(pc)));
+                },
+                _ => {},
+            }
 
             // Determine address of the jit-backing code for the current function, either by lookup,
             // or by compiling the function if it hasn't yet been compiled
-            let jit_addr = match (*self.jit).lookup(pc, None) {
+            let jit_addr = match resume_addr.take().or_else(|| (*self.jit).lookup(pc, None)) {
                 Option::None => {
-                    // IR instructions + labels at start of each control block
-                    let irgraph = self.lift_func(pc).unwrap();
-
-                    let leader_set: FxHashMap<usize, usize> = irgraph.get_leaders();
-
-                    let mut inputs: CompileInputs = CompileInputs {
-                        mem_size: self.memory.memory.len(),
-                        leaders: leader_set,
-                        exit_conds: &mut self.exit_conds,
-                        timeout: &self.timeout,
-                    };
+                    // Code whose translation was invalidated can be reentered in the middle of a
+                    // function, so always compile the entire function that contains `pc`
+                    let func = self.function_containing(pc).unwrap_or(pc);
 
-                    // Compile the previously lifted function. The lock is shared between all
-                    // threads and ensures that only one thread can compile code & insert it into
-                    // the shared JIT mapping at a time.
+                    // Compile the lifted function. The lock is shared between all threads and
+                    // ensures that only one thread can compile code & insert it into the shared
+                    // JIT mapping at a time.
                     //
                     // It is done this way instead of locking the entire JIT so that the threads
-                    // can still all access the JIT backing without issues or locks as long as they
-                    // don't need to compile new code.
+                    // can still all access the JIT backing without issues or locks as long as
+                    // they don't need to compile new code.
                     let mut v = self.prevent_rc.lock().unwrap();
-                    let ret = self.jit.compile(&irgraph, &self.hooks, &self.custom_lib,
-                                               &mut inputs);
-                    *v += 1;
-                    ret.unwrap()
+
+                    // If the function's code matches a previously invalidated translation, that
+                    // translation is reinstalled instead of recompiling the function. This is
+                    // done under the same lock as compilation so no other thread can install a
+                    // translation of different code in between
+                    let reinstalled = *SMC_POLICY.get().unwrap() == SmcPolicy::Invalidate && {
+                        let size = self.functions.get(&func).unwrap().0;
+                        self.jit.reinstall(func, func + size, self.code_hash(func))
+                    };
+
+                    if !reinstalled {
+                        // IR instructions + labels at start of each control block
+                        let irgraph = self.lift_func(func).unwrap();
+                        corpus.add_lifted_constants(&irgraph.constants);
+
+                        let leader_set: FxHashMap<usize, usize> = irgraph.get_leaders();
+
+                        let mut inputs: CompileInputs = CompileInputs {
+                            mem_size: self.memory.memory.len(),
+                            leaders: leader_set,
+                            exit_conds: &mut self.exit_conds,
+                            timeout: &self.timeout,
+                            annotations: &self.annotations,
+                        };
+
+                        self.jit.compile(&irgraph, &self.hooks, &self.custom_lib, &mut inputs)
+                            .unwrap();
+                        *v += 1;
+                    }
+                    drop(v);
+
+                    self.jit.lookup(pc, None).unwrap()
                 },
                 Some(addr) => addr
             };
@@ -407,7 +705,8 @@ impl Emulator {
             let reentry_pc: usize;
 
             // Invoke the JIT with appropriate arguments, push/pop rbx because it is being
-            // clobbered in the JIT and llvm requires it for its operations
+            // clobbered in the JIT and llvm requires it for its operations. rdx and rdi are used
+            // as temporaries by the JIT, so they are marked as clobbered
             unsafe {
                 let func = *(&jit_addr as *const usize as *const fn());
 
@@ -419,6 +718,8 @@ impl Emulator {
                 call_dest = in(reg) func,
                 out("rax")   exit_code,
                 out("rcx")   reentry_pc,
+                out("rdx")   _,
+                out("rdi")   _,
                 inout("rsi") *instr_count,
                 in("r8")     scratchpad.as_mut_ptr(),
                 inout("r9")  self.memory.dirty_size,
@@ -457,7 +758,7 @@ impl Emulator {
                             syscalls::fstat(self);
                         },
                         93 => {
-                            return (syscalls::exit(), scratchpad[9], scratchpad[3]);
+                            return case_result!(syscalls::exit());
                         },
                         169 => {
                             syscalls::gettimeofday(self);
@@ -470,35 +771,78 @@ impl Emulator {
                         },
                         _ => { panic!("Unimplemented syscall: {}", self.get_reg(Register::A7)); }
                     }
+
+                    // Syscalls write to memory through the mmu instead of the JIT, so writes to
+                    // executable memory need to be checked for separately
+                    for addr in std::mem::take(&mut self.memory.code_writes) {
+                        if let Some(fault) = self.handle_code_write(reentry_pc - 4, addr) {
+                            return case_result!(Some(fault));
+                        }
+                    }
                 },
                 3 => { /* Hooked function */
                     if let Some(callback) = self.hooks.get(&reentry_pc) {
                         match callback(self) {
-                            Err(v) => return (Some(v), scratchpad[9], scratchpad[3]),
+                            Err(v) => return case_result!(Some(v)),
                             _ => {},
                         }
                     } else {
                         error_exit("Attempted to hook invalid function");
                     }
+
+                    // Hooks return to the caller without executing a `ret`, so the call that led
+                    // here has to be popped off the shadow call stack manually. A tail-called
+                    // hook (`jal zero`) returns on behalf of the function that jumped to it, whose
+                    // call is the most recent one. The most recent call is only popped if the hook
+                    // returns to its return address, otherwise it is still active
+                    if *COV_METHOD.get().unwrap() == CovMethod::CallStack && scratchpad[21] != 0 {
+                        let depth = scratchpad[21] - 1;
+                        if depth >= CALL_STACK_SIZE {
+                            scratchpad[21] = depth;
+                        } else if self.call_stack[CALL_STACK_SIZE + depth] as usize ==
+                                self.get_reg(Register::Pc) {
+                            scratchpad[21] = depth;
+                            scratchpad[7] = self.call_stack[depth] as usize;
+                        }
+                    }
                 },
                 5 => { /* JIT exited to setup a snapshot */
-                    self.snapshot_addr = scratchpad[0];
-                    return (Some(Fault::Snapshot), scratchpad[9], scratchpad[3]);
+                    match &mut self.snapshot_target {
+                        Some((addr, 1)) if *addr == reentry_pc => {
+                            self.snapshot_addr = scratchpad[0];
+                            return case_result!(Some(Fault::Snapshot));
+                        },
+                        Some((addr, hits)) if *addr == reentry_pc => {
+                            *hits -= 1;
+                        },
+                        _ => {},
+                    }
+
+                    // Either this snapshot needs to be hit more often, or it belongs to a later
+                    // snapshot in a chain. Continue execution right after the injected code
+                    resume_addr = Some(scratchpad[0] +
+                                       self.jit.snapshot_inject_size.load(Ordering::SeqCst));
                 },
                 6 => { /* Divide by 0 */
-                    return (Some(Fault::DivZero(reentry_pc)), scratchpad[9], scratchpad[3]);
+                    return case_result!(Some(Fault::DivZero(reentry_pc)));
                 },
                 7 => { /* Fuzz case timed out */
-                    return (Some(Fault::Timeout), scratchpad[9], scratchpad[3]);
+                    return case_result!(Some(Fault::Timeout));
                 },
                 8 => { /* Attempted to read memory without read permissions */
-                    return (Some(Fault::ReadFault(reentry_pc)), scratchpad[9], scratchpad[3]);
+                    return case_result!(Some(Fault::ReadFault(reentry_pc)));
                 },
                 9 => { /* Attempted to write to memory without write permissions */
-                    return (Some(Fault::WriteFault(reentry_pc)), scratchpad[9], scratchpad[3]);
+                    return case_result!(Some(Fault::WriteFault(reentry_pc)));
                 },
                 10 => { /* Memory read/write request went completely out of bounds */
-                    return (Some(Fault::OutOfBounds(reentry_pc)), scratchpad[9], scratchpad[3]);
+                    return case_result!(Some(Fault::OutOfBounds(reentry_pc)));
+                },
+                12 => { /* Reached an exit condition, handled at the start of the loop */ },
+                11 => { /* Store instruction wrote to executable memory */
+                    if let Some(fault) = self.handle_code_write(reentry_pc - 4, scratchpad[1]) {
+                        return case_result!(Some(fault));
+                    }
                 },
                 _ => panic!("Invalid JIT return code: {:x}", exit_code),
             }
@@ -531,7 +875,7 @@ impl Emulator {
     }
 
     /// Lift a function into an intermediate representation using the lift helper function
-    fn lift_func(&self, mut pc: usize) -> Result<IRGraph, ()> {
+    pub fn lift_func(&self, mut pc: usize) -> Result<IRGraph, ()> {
         let mut irgraph = IRGraph::default();
         let mut instrs: Vec<Instr> = Vec::new();
 
@@ -568,6 +912,10 @@ impl Emulator {
     fn lift(&self, irgraph: &mut IRGraph, instrs: &[Instr], keys: &mut BTreeMap<usize, u8>,
             mut pc: usize) {
 
+        // Register and value of the last `lui`, so a following `addi` that completes the constant
+        // can be recorded as a whole
+        let mut upper: Option<(Register, i32)> = None;
+
         // Lift instructions until we reach the end of the function
         for instr in instrs {
 
@@ -577,6 +925,30 @@ impl Emulator {
                 irgraph.set_label(pc);
             }
 
+            // Record immediates that are likely compared against, these are used as dictionary
+            // tokens by the mutator
+            match *instr {
+                Instr::Addi  { rd: _, rs1, imm } |
+                Instr::Addiw { rd: _, rs1, imm } => {
+                    match upper {
+                        Some((reg, val)) if reg == rs1 => {
+                            irgraph.constants.push(val.wrapping_add(imm) as i64);
+                        },
+                        _ if rs1 == Register::Zero => irgraph.constants.push(imm as i64),
+                        _ => {},
+                    }
+                },
+                Instr::Lui   { rd: _, imm } |
+                Instr::Slti  { rd: _, rs1: _, imm } |
+                Instr::Sltiu { rd: _, rs1: _, imm } |
+                Instr::Xori  { rd: _, rs1: _, imm } => irgraph.constants.push(imm as i64),
+                _ => {},
+            }
+            upper = match *instr {
+                Instr::Lui { rd, imm } => Some((rd, imm)),
+                _ => None,
+            };
+
             match *instr {
                 Instr::Lui {rd, imm} => {
                     irgraph.movi32(rd, imm, Flag::Signed);
@@ -591,14 +963,24 @@ impl Emulator {
                     if rd != Register::Zero {
                         irgraph.movi64(rd, pc.wrapping_add(4) as i64, Flag::Unsigned);
                     }
-                    irgraph.jmp(jmp_target);
+                    let flags = if rd == Register::Ra { Flag::Call } else { Flag::NoFlag };
+                    irgraph.jmp(jmp_target, flags);
                 },
                 Instr::Jalr {rd, imm, rs1} => {
                     if rd != Register::Zero {
                         //irgraph.movi32(rd, (pc + 4) as i32, Flag::Signed);
                         irgraph.movi64(rd, pc.wrapping_add(4) as i64, Flag::Unsigned);
                     }
-                    irgraph.jmp_offset(rs1, imm);
+
+                    // Calls and returns are marked so the JIT can maintain a shadow call stack
+                    let flags = if rd == Register::Ra {
+                        Flag::Call
+                    } else if rd == Register::Zero && rs1 == Register::Ra && imm == 0 {
+                        Flag::Ret
+                    } else {
+                        Flag::NoFlag
+                    };
+                    irgraph.jmp_offset(rs1, imm, flags);
                 },
                 Instr::Beq  { rs1, rs2, imm, mode } |
                 Instr::Bne  { rs1, rs2, imm, mode } |
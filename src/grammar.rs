@@ -0,0 +1,466 @@
+use crate::{custom_mutator::MAX_INPUT_SIZE, mutator::unescape};
+
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoroshiro64Star;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// Maximum depth of newly generated subtrees. Once it is reached, only the alternatives that
+/// terminate the quickest are chosen
+const GEN_DEPTH: usize = 12;
+
+/// Maximum number of nodes in a derivation tree. Mutations that grow a tree beyond this are
+/// discarded
+pub const MAX_TREE_NODES: usize = 10_000;
+
+/// Symbol on the right-hand side of a grammar rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Symbol {
+    Terminal(Vec<u8>),
+    NonTerminal(usize),
+}
+
+/// Context-free grammar that is used to generate and mutate inputs. Nonterminals are referred to
+/// by their index into `names` and `rules`
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    /// Name of each nonterminal, including the angle brackets (eg. `<expr>`)
+    pub names: Vec<String>,
+
+    /// Alternatives of each nonterminal
+    pub rules: Vec<Vec<Vec<Symbol>>>,
+
+    /// Nonterminal that inputs are derived from
+    pub start: usize,
+
+    /// Minimum depth of a subtree that is derived using each alternative, indexed like `rules`
+    alt_depth: Vec<Vec<usize>>,
+}
+
+/// Depth of the subtree derived using `alt`, given the minimum depth of each nonterminal. Returns
+/// None if one of its nonterminals can not be derived (yet)
+fn alt_depth(alt: &[Symbol], depth: &[Option<usize>]) -> Option<usize> {
+    alt.iter().try_fold(1, |acc, e| match e {
+        Symbol::Terminal(_) => Some(acc),
+        Symbol::NonTerminal(id) => depth[*id].map(|d| acc.max(d + 1)),
+    })
+}
+
+/// Node of a derivation tree. It records which alternative was used to expand the nonterminal,
+/// and holds one child for each nonterminal of that alternative. Terminals are taken from the
+/// grammar when the tree is serialized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub nonterm: usize,
+    pub alt: usize,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    /// Number of nodes in this subtree
+    pub fn size(&self) -> usize {
+        1 + self.children.iter().map(|e| e.size()).sum::<usize>()
+    }
+
+    /// Nodes of this subtree in pre-order
+    pub fn preorder(&self) -> Vec<&Node> {
+        let mut nodes = vec![self];
+        for child in &self.children {
+            nodes.extend(child.preorder());
+        }
+        nodes
+    }
+
+    /// Mutable reference to the `idx`th node of this subtree in pre-order
+    pub fn nth_mut(&mut self, mut idx: usize) -> &mut Node {
+        if idx == 0 {
+            return self;
+        }
+        idx -= 1;
+        for child in &mut self.children {
+            let size = child.size();
+            if idx < size {
+                return child.nth_mut(idx);
+            }
+            idx -= size;
+        }
+        panic!("Node index out of bounds");
+    }
+}
+
+impl Grammar {
+    /// Load a grammar from `file_name`. Files that start with `{` are parsed as JSON grammars
+    /// that map each nonterminal to a list of alternatives (eg. `{"<start>": ["<digit>",
+    /// "<digit><start>"], "<digit>": ["0", "1"]}`), and everything else as BNF (eg. `<start> ::=
+    /// <digit> | <digit> <start>`, with terminals in double quotes). Inputs are derived from
+    /// `<start>`, or the first rule of a BNF grammar that does not define it
+    pub fn load(file_name: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(file_name)
+            .map_err(|e| format!("{}: {}", file_name, e))?;
+        if contents.trim_start().starts_with('{') {
+            Self::parse_json(&contents)
+        } else {
+            Self::parse_bnf(&contents)
+        }
+    }
+
+    /// Parse a JSON grammar. Occurrences of defined nonterminals within an alternative are
+    /// references, all other text is terminal
+    pub fn parse_json(contents: &str) -> Result<Self, String> {
+        let json: serde_json::Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        let map = json.as_object().ok_or("Expected an object of rules")?;
+
+        let names: Vec<String> = map.keys().cloned().collect();
+        let ids: FxHashMap<&str, usize> = names.iter().enumerate()
+            .map(|(i, e)| (e.as_str(), i)).collect();
+
+        let mut rules = Vec::new();
+        for (name, alts) in map {
+            let alts = alts.as_array().ok_or(format!("{}: Expected a list of alternatives", name))?;
+            let mut parsed = Vec::new();
+            for alt in alts {
+                let alt = alt.as_str().ok_or(format!("{}: Alternatives must be strings", name))?;
+                parsed.push(Self::split_json_alt(alt, &ids));
+            }
+            rules.push(parsed);
+        }
+
+        let start = *ids.get("<start>").ok_or("Grammar does not define <start>")?;
+        Self::new(names, rules, start)
+    }
+
+    /// Split an alternative of a JSON grammar into terminals and references to nonterminals
+    fn split_json_alt(alt: &str, ids: &FxHashMap<&str, usize>) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        let mut terminal = Vec::new();
+        let mut rest = alt;
+        while !rest.is_empty() {
+            let reference = rest.starts_with('<').then(|| rest.find('>')).flatten()
+                .and_then(|end| ids.get(&rest[..=end]).map(|&id| (id, end)));
+            match reference {
+                Some((id, end)) => {
+                    if !terminal.is_empty() {
+                        symbols.push(Symbol::Terminal(std::mem::take(&mut terminal)));
+                    }
+                    symbols.push(Symbol::NonTerminal(id));
+                    rest = &rest[end + 1..];
+                },
+                None => {
+                    let len = rest.chars().next().unwrap().len_utf8();
+                    terminal.extend_from_slice(rest[..len].as_bytes());
+                    rest = &rest[len..];
+                },
+            }
+        }
+        if !terminal.is_empty() {
+            symbols.push(Symbol::Terminal(terminal));
+        }
+        symbols
+    }
+
+    /// Parse a BNF grammar. Each rule starts on a new line with `<name> ::=`, and may continue on
+    /// the following lines. Alternatives are separated by `|`, terminals are quoted and may
+    /// contain the same escapes as dictionary entries. Lines starting with `#` are comments
+    pub fn parse_bnf(contents: &str) -> Result<Self, String> {
+        let mut defs: Vec<(String, String)> = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once("::=") {
+                Some((name, body)) => {
+                    let name = name.trim();
+                    if !name.starts_with('<') || !name.ends_with('>') {
+                        return Err(format!("line {}: Invalid rule name: {}", i + 1, name));
+                    }
+                    defs.push((name.to_string(), body.to_string()));
+                },
+                None => match defs.last_mut() {
+                    Some((_, body)) => {
+                        body.push(' ');
+                        body.push_str(line);
+                    },
+                    None => return Err(format!("line {}: Expected a rule", i + 1)),
+                },
+            }
+        }
+
+        let names: Vec<String> = defs.iter().map(|(name, _)| name.clone()).collect();
+        let ids: FxHashMap<&str, usize> = names.iter().enumerate()
+            .map(|(i, e)| (e.as_str(), i)).collect();
+        if ids.len() != names.len() {
+            return Err("Nonterminals may only be defined once".to_string());
+        }
+
+        let mut rules = Vec::new();
+        for (name, body) in &defs {
+            rules.push(Self::parse_bnf_body(body, &ids).map_err(|e| format!("{}: {}", name, e))?);
+        }
+
+        if names.is_empty() {
+            return Err("Grammar does not contain any rules".to_string());
+        }
+        let start = ids.get("<start>").copied().unwrap_or(0);
+        Self::new(names, rules, start)
+    }
+
+    /// Parse the alternatives on the right-hand side of a BNF rule
+    fn parse_bnf_body(body: &str, ids: &FxHashMap<&str, usize>)
+            -> Result<Vec<Vec<Symbol>>, String> {
+        let mut alts = vec![Vec::new()];
+        let mut rest = body.trim_start();
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '|' => {
+                    alts.push(Vec::new());
+                    rest = &rest[1..];
+                },
+                '<' => {
+                    let end = rest.find('>').ok_or("Unterminated nonterminal")?;
+                    let id = *ids.get(&rest[..=end])
+                        .ok_or(format!("Undefined nonterminal: {}", &rest[..=end]))?;
+                    alts.last_mut().unwrap().push(Symbol::NonTerminal(id));
+                    rest = &rest[end + 1..];
+                },
+                '"' => {
+                    // Find the closing quote, skipping escaped characters
+                    let mut end = None;
+                    let mut escaped = false;
+                    for (i, b) in rest.bytes().enumerate().skip(1) {
+                        match b {
+                            _ if escaped => escaped = false,
+                            b'\\' => escaped = true,
+                            b'"' => { end = Some(i); break; },
+                            _ => {},
+                        }
+                    }
+                    let end = end.ok_or("Unterminated terminal")?;
+                    alts.last_mut().unwrap().push(Symbol::Terminal(unescape(&rest[1..end])?));
+                    rest = &rest[end + 1..];
+                },
+                _ => return Err(format!("Unexpected character: {}", c)),
+            }
+            rest = rest.trim_start();
+        }
+
+        if alts.iter().any(|e| e.is_empty()) {
+            return Err("Empty alternative, use \"\" to derive the empty string".to_string());
+        }
+        Ok(alts)
+    }
+
+    /// Create a grammar from parsed rules. Fails if a nonterminal has no alternatives or can never
+    /// be fully derived
+    fn new(names: Vec<String>, rules: Vec<Vec<Vec<Symbol>>>, start: usize) -> Result<Self, String> {
+        if let Some(i) = rules.iter().position(|e| e.is_empty()) {
+            return Err(format!("{}: No alternatives", names[i]));
+        }
+
+        // Determine the minimum depth of each nonterminal by iterating until no more nonterminals
+        // can be derived
+        let mut depth: Vec<Option<usize>> = vec![None; rules.len()];
+        loop {
+            let mut changed = false;
+            for (i, alts) in rules.iter().enumerate() {
+                let min = alts.iter().filter_map(|e| alt_depth(e, &depth)).min();
+                if min.is_some() && min != depth[i] {
+                    depth[i] = min;
+                    changed = true;
+                }
+            }
+            if !changed { break; }
+        }
+        if let Some(i) = depth.iter().position(|e| e.is_none()) {
+            return Err(format!("{}: Can never be fully derived", names[i]));
+        }
+
+        let alt_depths = rules.iter().map(|alts| {
+            alts.iter().map(|e| alt_depth(e, &depth).unwrap()).collect()
+        }).collect();
+        Ok(Self { names, rules, start, alt_depth: alt_depths })
+    }
+
+    /// Randomly derive a subtree from `nonterm`. Once `max_depth` is exhausted or the subtree
+    /// holds `max_nodes` nodes, only the alternatives that terminate the quickest are chosen
+    pub fn generate<R: RngCore>(&self, nonterm: usize, max_depth: usize, max_nodes: usize,
+                                rng: &mut R) -> Node {
+        let mut budget = max_nodes;
+        self.generate_bounded(nonterm, max_depth, &mut budget, rng)
+    }
+
+    fn generate_bounded<R: RngCore>(&self, nonterm: usize, max_depth: usize, budget: &mut usize,
+                                    rng: &mut R) -> Node {
+        *budget = budget.saturating_sub(1);
+        let max_depth = if *budget == 0 { 0 } else { max_depth };
+
+        let depths = &self.alt_depth[nonterm];
+        let limit = max_depth.max(*depths.iter().min().unwrap());
+        let candidates: Vec<usize> = (0..depths.len()).filter(|&i| depths[i] <= limit).collect();
+        let alt = candidates[rng.next_u32() as usize % candidates.len()];
+
+        let children = self.rules[nonterm][alt].iter().filter_map(|e| match e {
+            Symbol::NonTerminal(id) => {
+                Some(self.generate_bounded(*id, max_depth.saturating_sub(1), budget, rng))
+            },
+            Symbol::Terminal(_) => None,
+        }).collect();
+        Node { nonterm, alt, children }
+    }
+
+    /// Generate up to `count` distinct derivation trees from the start symbol. Trees whose
+    /// serialized input would exceed `MAX_INPUT_SIZE` are skipped
+    pub fn seeds(&self, count: usize) -> Vec<Node> {
+        let mut rng = Xoroshiro64Star::seed_from_u64(0);
+        let mut seen: FxHashSet<Vec<u8>> = FxHashSet::default();
+        let mut seeds = Vec::new();
+
+        for _ in 0..count * 10 {
+            let tree = self.generate(self.start, GEN_DEPTH, MAX_TREE_NODES, &mut rng);
+            if tree.size() > MAX_TREE_NODES {
+                continue;
+            }
+            let data = self.unparse(&tree);
+            if data.len() <= MAX_INPUT_SIZE && seen.insert(data) {
+                seeds.push(tree);
+                if seeds.len() == count { break; }
+            }
+        }
+        seeds
+    }
+
+    /// Serialize a derivation tree into the input it represents
+    pub fn unparse(&self, tree: &Node) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.unparse_into(tree, &mut out);
+        out
+    }
+
+    fn unparse_into(&self, node: &Node, out: &mut Vec<u8>) {
+        let mut children = node.children.iter();
+        for symbol in &self.rules[node.nonterm][node.alt] {
+            match symbol {
+                Symbol::Terminal(bytes) => out.extend_from_slice(bytes),
+                Symbol::NonTerminal(_) => self.unparse_into(children.next().unwrap(), out),
+            }
+        }
+    }
+
+    /// Replace a random subtree with a freshly generated one
+    pub fn replace_subtree<R: RngCore>(&self, tree: &mut Node, rng: &mut R) {
+        let tree_size = tree.size();
+        let node = tree.nth_mut(rng.next_u32() as usize % tree_size);
+        let max_nodes = MAX_TREE_NODES.saturating_sub(tree_size - node.size());
+        *node = self.generate(node.nonterm, GEN_DEPTH / 2, max_nodes, rng);
+    }
+
+    /// Replace a random subtree with a subtree of `other` that was derived from the same
+    /// nonterminal
+    pub fn splice_subtree<R: RngCore>(&self, tree: &mut Node, other: &Node, rng: &mut R)
+            -> Result<(), ()> {
+        let node = tree.nth_mut(rng.next_u32() as usize % tree.size());
+        let candidates: Vec<&Node> = other.preorder().into_iter()
+            .filter(|e| e.nonterm == node.nonterm).collect();
+        if candidates.is_empty() { return Err(()); }
+
+        *node = candidates[rng.next_u32() as usize % candidates.len()].clone();
+        Ok(())
+    }
+
+    /// Pick a subtree that contains a nested subtree of the same nonterminal, and repeat the part
+    /// in between 2-4 times (eg. `(1)` becomes `((((1))))`)
+    pub fn recursive_expansion<R: RngCore>(&self, tree: &mut Node, rng: &mut R)
+            -> Result<(), ()> {
+        let nodes = tree.preorder();
+        let mut pairs: Vec<(usize, usize)> = Vec::new();
+        for (i, outer) in nodes.iter().enumerate() {
+            let end = i + outer.size();
+            pairs.extend((i + 1..end).filter(|&j| nodes[j].nonterm == outer.nonterm)
+                         .map(|j| (i, j)));
+        }
+        if pairs.is_empty() { return Err(()); }
+
+        let (outer, inner) = pairs[rng.next_u32() as usize % pairs.len()];
+        let layer = nodes[outer].clone();
+        let mut pumped = nodes[inner].clone();
+        for _ in 0..(2 + rng.next_u32() % 3) {
+            let mut next = layer.clone();
+            *next.nth_mut(inner - outer) = pumped;
+            pumped = next;
+        }
+
+        *tree.nth_mut(outer) = pumped;
+        Ok(())
+    }
+}
+
+/// Unit tests for parsing grammars and generating inputs from them
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BNF: &str = r#"
+        # Arithmetic expressions
+        <start> ::= <expr>
+        <expr>  ::= <term> "+" <expr>
+                  | <term>
+        <term>  ::= "(" <expr> ")" | <digit>
+        <digit> ::= "0" | "1" | "\x02"
+    "#;
+
+    #[test]
+    fn bnf_grammar() {
+        let grammar = Grammar::parse_bnf(BNF).unwrap();
+        assert_eq!(grammar.names, vec!["<start>", "<expr>", "<term>", "<digit>"]);
+        assert_eq!(grammar.rules[1].len(), 2);
+        assert_eq!(grammar.rules[3][2], vec![Symbol::Terminal(vec![2])]);
+    }
+
+    #[test]
+    fn json_grammar() {
+        let json = r#"{"<start>": ["<num>", "-<num>"], "<num>": ["1", "1<num>"]}"#;
+        let grammar = Grammar::parse_json(json).unwrap();
+        let num = grammar.names.iter().position(|e| e == "<num>").unwrap();
+        assert_eq!(grammar.names[grammar.start], "<start>");
+        assert!(grammar.rules[grammar.start].contains(
+            &vec![Symbol::Terminal(b"-".to_vec()), Symbol::NonTerminal(num)]));
+    }
+
+    #[test]
+    fn invalid_grammars() {
+        assert!(Grammar::parse_bnf("<start> ::= <undefined>").is_err());
+        assert!(Grammar::parse_bnf("<start> ::= \"a\" <start>").is_err());
+        assert!(Grammar::parse_bnf("<start> ::= \"a\" |").is_err());
+        assert!(Grammar::parse_bnf("<start> ::= \"a").is_err());
+        assert!(Grammar::parse_json(r#"{"<a>": ["b"]}"#).is_err());
+    }
+
+    #[test]
+    fn generated_inputs_match_grammar() {
+        let grammar = Grammar::parse_bnf(BNF).unwrap();
+        let mut rng = Xoroshiro64Star::seed_from_u64(1);
+
+        for mut tree in grammar.seeds(32) {
+            grammar.replace_subtree(&mut tree, &mut rng);
+            let _ = grammar.recursive_expansion(&mut tree, &mut rng);
+            let input = grammar.unparse(&tree);
+
+            let opened = input.iter().filter(|&&e| e == b'(').count();
+            let closed = input.iter().filter(|&&e| e == b')').count();
+            assert_eq!(opened, closed);
+            assert!(input.iter().all(|e| b"()+01\x02".contains(e)));
+        }
+    }
+
+    #[test]
+    fn generated_trees_are_bounded() {
+        // Without the node budget this grammar derives about 3^12 nodes per tree
+        let grammar = Grammar::parse_bnf(r#"<a> ::= <a><a><a><a><a><a> | "x""#).unwrap();
+        let mut rng = Xoroshiro64Star::seed_from_u64(0);
+
+        for _ in 0..32 {
+            let tree = grammar.generate(0, GEN_DEPTH, 1000, &mut rng);
+            assert!(tree.size() <= 1000 + 5 * GEN_DEPTH);
+        }
+        assert!(!grammar.seeds(8).is_empty());
+    }
+}
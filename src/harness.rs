@@ -0,0 +1,162 @@
+use crate::{
+    emulator::{Emulator, Register, Fault},
+    mmu::Perms,
+    resolve_location,
+};
+
+use rustc_hash::FxHashMap;
+use parse_int::parse;
+
+/// Location that a harness writes a value to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// Guest register
+    Reg(Register),
+
+    /// 64-bit value in guest memory
+    Addr(usize),
+}
+
+impl Location {
+    /// Parse a location from either a register name (`a0`) or an address/symbol
+    pub fn parse(sym_map: &FxHashMap<String, usize>, loc: &str) -> Option<Self> {
+        if let Some(reg) = Register::from_name(loc) {
+            return Some(Location::Reg(reg));
+        }
+        resolve_location(sym_map, loc).map(Location::Addr)
+    }
+
+    /// Write `val` to this location
+    pub fn write(&self, emu: &mut Emulator, val: usize) -> Result<(), Fault> {
+        match *self {
+            Location::Reg(reg) => {
+                emu.set_reg(reg, val);
+                Ok(())
+            },
+            Location::Addr(addr) => emu.memory.write_mem(addr, &val.to_le_bytes(), 8),
+        }
+    }
+}
+
+/// Argument that the function harness passes to the fuzzed function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+    /// Pointer to the part of the fuzz-input that was not consumed by scalar arguments
+    Buf,
+
+    /// Length of `Buf`
+    Len,
+
+    /// Little-endian integer of the given byte-size, taken from the front of the fuzz-input
+    Scalar(usize),
+
+    /// Fixed value that is passed in every case
+    Literal(usize),
+}
+
+impl Arg {
+    /// Parse an argument from `buf`, `len`, `u8`-`u64`, or an integer literal
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "buf" => Some(Arg::Buf),
+            "len" => Some(Arg::Len),
+            "u8"  => Some(Arg::Scalar(1)),
+            "u16" => Some(Arg::Scalar(2)),
+            "u32" => Some(Arg::Scalar(4)),
+            "u64" => Some(Arg::Scalar(8)),
+            _ => parse::<usize>(arg).ok().map(Arg::Literal),
+        }
+    }
+}
+
+/// Different ways in which a harness passes the fuzz-input to the target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Harness {
+    /// Place the input into an exactly-sized allocation once the snapshot is reached, and write
+    /// its address and optionally its length to the given locations (eg. `a0` and `a1`)
+    Inject {
+        ptr: Location,
+        len: Option<Location>,
+    },
+
+    /// Call a function with arguments derived from the fuzz-input, passed in `a0`-`a7`
+    Function {
+        args: Vec<Arg>,
+    },
+}
+
+impl Harness {
+    /// Parse an injection harness from a `PTR[,LEN]` specification
+    pub fn parse_inject(sym_map: &FxHashMap<String, usize>, spec: &str) -> Option<Self> {
+        let (ptr, len) = match spec.split_once(',') {
+            Some((ptr, len)) => (ptr, Some(Location::parse(sym_map, len)?)),
+            None => (spec, None),
+        };
+        Some(Harness::Inject { ptr: Location::parse(sym_map, ptr)?, len })
+    }
+
+    /// Parse a function harness from a comma separated argument list (eg. `buf,len,u32`). At
+    /// most 8 arguments are supported since they are all passed in registers
+    pub fn parse_function(spec: &str) -> Option<Self> {
+        let args = spec.split(',').map(Arg::parse).collect::<Option<Vec<Arg>>>()?;
+        if args.len() > 8 {
+            return None;
+        }
+        Some(Harness::Function { args })
+    }
+
+    /// Pass the emulator's current fuzz-input to the target
+    pub fn prepare(&self, emu: &mut Emulator) -> Result<(), Fault> {
+        match self {
+            Harness::Inject { ptr, len } => {
+                let buf = inject_input(emu, 0)?;
+                ptr.write(emu, buf)?;
+                if let Some(len) = len {
+                    len.write(emu, emu.fuzz_input.len())?;
+                }
+            },
+            Harness::Function { args } => {
+                // Scalar arguments are consumed from the front of the input in order. Inputs that
+                // are too short are padded with zeroes
+                let mut offset = 0;
+                let mut vals: Vec<usize> = args.iter().map(|arg| match *arg {
+                    Arg::Scalar(size) => {
+                        let mut bytes = [0u8; 8];
+                        let start = offset.min(emu.fuzz_input.len());
+                        let end = (offset + size).min(emu.fuzz_input.len());
+                        bytes[..end - start].copy_from_slice(&emu.fuzz_input[start..end]);
+                        offset += size;
+                        usize::from_le_bytes(bytes)
+                    },
+                    Arg::Literal(val) => val,
+                    Arg::Buf | Arg::Len => 0,
+                }).collect();
+
+                // The remainder of the input is passed as the buffer
+                let offset = offset.min(emu.fuzz_input.len());
+                let buf = if args.contains(&Arg::Buf) { inject_input(emu, offset)? } else { 0 };
+                for (val, arg) in vals.iter_mut().zip(args) {
+                    match arg {
+                        Arg::Buf => *val = buf,
+                        Arg::Len => *val = emu.fuzz_input.len() - offset,
+                        _ => {},
+                    }
+                }
+
+                for (i, val) in vals.into_iter().enumerate() {
+                    emu.set_reg(Register::from(Register::A0 as u32 + i as u32), val);
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Copy the fuzz-input starting at `offset` into a new allocation that is exactly as large as the
+/// copied data, so the byte-level permission checks catch any out of bounds accesses on the buffer
+fn inject_input(emu: &mut Emulator, offset: usize) -> Result<usize, Fault> {
+    let size = emu.fuzz_input.len() - offset;
+    let addr = emu.memory.allocate(size, Perms::READ | Perms::WRITE).ok_or(Fault::OOM)?;
+    emu.memory.write_mem(addr, &emu.fuzz_input[offset..], size)?;
+    Ok(addr)
+}
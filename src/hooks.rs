@@ -0,0 +1,107 @@
+use crate::{
+    mmu::Perms,
+    emulator::{Emulator, Register, Fault},
+    jit::LibFuncs,
+    pretty_printing::{log, LogType},
+};
+
+use rustc_hash::FxHashMap;
+
+/// Hook that makes use of sfuzz's mmu to perform a memory safe malloc operation
+pub fn malloc_hook(emu: &mut Emulator) -> Result<(), Fault> {
+    let alloc_size = emu.get_reg(Register::A1);
+
+    if let Some(addr) = emu.memory.allocate(alloc_size, Perms::READ | Perms::WRITE) {
+        emu.set_reg(Register::A0, addr);
+        emu.set_reg(Register::Pc, emu.get_reg(Register::Ra));
+        Ok(())
+    } else {
+        Err(Fault::OOM)
+    }
+}
+
+/// Hook that makes use of sfuzz's mmu to perform a memory safe calloc operation, pretty much same
+/// as malloc apart from how the size is calculated
+pub fn calloc_hook(emu: &mut Emulator) -> Result<(), Fault> {
+    let nmemb = emu.get_reg(Register::A1);
+    let size  = emu.get_reg(Register::A2);
+    let alloc_size = size * nmemb;
+
+    if let Some(addr) = emu.memory.allocate(alloc_size, Perms::READ | Perms::WRITE) {
+        emu.set_reg(Register::A0, addr);
+        emu.set_reg(Register::Pc, emu.get_reg(Register::Ra));
+        Ok(())
+    } else {
+        Err(Fault::OOM)
+    }
+}
+
+/// Hook that makes use of sfuzz's mmu to perform a memory safe free operation
+pub fn free_hook(emu: &mut Emulator) -> Result<(), Fault> {
+    let ptr = emu.get_reg(Register::A1);
+
+    emu.memory.free(ptr)?;
+    emu.set_reg(Register::Pc, emu.get_reg(Register::Ra));
+    Ok(())
+}
+
+/// Inserts various hooks into binary
+pub fn insert_hooks(sym_map: &FxHashMap<String, usize>, emu: &mut Emulator) {
+    match sym_map.get("_free_r") {
+        Some(v) => {
+            log(LogType::Success, "_free_r hooked");
+            emu.hooks.insert(*v, free_hook);
+        },
+        None => {
+            log(LogType::Neutral, "free_r does not exist in target so it could not be hooked"); 
+        }
+    }
+
+    match sym_map.get("_malloc_r") {
+        Some(v) => {
+            log(LogType::Success, "_malloc_r hooked");
+            emu.hooks.insert(*v, malloc_hook);
+        },
+        None => {
+            log(LogType::Neutral, "malloc_r does not exist in target so it could not be hooked"); 
+        }
+    }
+
+    match sym_map.get("_calloc_r") {
+        Some(v) => {
+            log(LogType::Success, "_calloc_r hooked");
+            emu.hooks.insert(*v, calloc_hook);
+        },
+        None => {
+            log(LogType::Neutral, "_calloc_r does not exist in target so it could not be hooked"); 
+        }
+    }
+
+    // Replacements for string and memory functions are required because the default libc
+    // variants go out of bounds. This is not a security issue since the functions verify that
+    // everything is properly aligned, but since this fuzzer notices byte level permission
+    // violations these are required. The compare functions additionally report their progress to
+    // CmpCov and the comparison log
+    let lib_funcs = [
+        ("strlen",  LibFuncs::STRLEN),
+        ("strcmp",  LibFuncs::STRCMP),
+        ("strncmp", LibFuncs::STRNCMP),
+        ("memcmp",  LibFuncs::MEMCMP),
+        ("memchr",  LibFuncs::MEMCHR),
+        ("memcpy",  LibFuncs::MEMCPY),
+        ("memset",  LibFuncs::MEMSET),
+    ];
+
+    for (name, func) in lib_funcs {
+        match sym_map.get(name) {
+            Some(v) => {
+                log(LogType::Success, &format!("{} replaced with safe implementation", name));
+                emu.custom_lib.insert(*v, func);
+            },
+            None => {
+                log(LogType::Neutral,
+                    &format!("{} does not exist in target so it could not be hooked", name));
+            }
+        }
+    }
+}
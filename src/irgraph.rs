@@ -86,6 +86,8 @@ impl Flag {
     pub const Word:     u16 = 0x80;
     pub const DWord:    u16 = 0x100;
     pub const QWord:    u16 = 0x200;
+    pub const Call:     u16 = 0x400;
+    pub const Ret:      u16 = 0x800;
 }
 
 /// The instructions used in the IR. Layed out in a way that is efficient memory wise and lets us
@@ -210,6 +212,9 @@ pub struct IRGraph {
     /// Labels indicating controlflow (instrs_index, pc)
     pub labels: FxHashMap<usize, usize>,
 
+    /// Immediates that the lifted code loads or compares against, used to build dictionary tokens
+    pub constants: Vec<i64>,
+
     /// Since multiple IR instructions can be mapped to a single original instruction, this is used
     /// to only assign the pc to the first IR-instruction is generated for an original instruction.
     cur_pc: Option<usize>,
@@ -226,6 +231,7 @@ impl IRGraph {
         IRGraph {
             instrs: Vec::new(),
             labels: FxHashMap::default(),
+            constants: Vec::new(),
             cur_pc: None,
         }
     }
@@ -280,24 +286,24 @@ impl IRGraph {
     }
 
     /// Jmp addr
-    pub fn jmp(&mut self, addr: usize) {
+    pub fn jmp(&mut self, addr: usize, flags: u16) {
         self.instrs.push( Instruction {
             op: Operation::Jmp(addr),
             i_reg: Vec::new(),
             o_reg: None,
-            flags: Flag::NoFlag,
+            flags,
             pc: self.cur_pc,
         });
         self.cur_pc = None;
     }
 
     /// Jmp (r1 + addr)
-    pub fn jmp_offset(&mut self, r1: PReg, addr: i32) {
+    pub fn jmp_offset(&mut self, r1: PReg, addr: i32, flags: u16) {
         self.instrs.push( Instruction {
             op: Operation::JmpOff(addr),
             i_reg: vec![Reg(r1)],
             o_reg: None,
-            flags: Flag::NoFlag,
+            flags,
             pc: self.cur_pc,
         });
         self.cur_pc = None;
@@ -630,4 +636,29 @@ impl IRGraph {
         }
         leader_set
     }
+
+    /// Return the number of instructions in each block that instruction counts are accounted for
+    /// in. These start at the passed in leaders, and after every jump or syscall since the JIT can
+    /// be reentered at these locations (eg. when returning from a function call)
+    pub fn get_block_sizes(&self, leaders: &FxHashMap<usize, usize>) -> FxHashMap<usize, usize> {
+        let mut block_sizes: FxHashMap<usize, usize> = FxHashMap::default();
+        let mut cur_block: Option<usize> = None;
+        let mut reentry = false;
+
+        for instr in &self.instrs {
+            if let Some(pc) = instr.pc {
+                if cur_block.is_none() || reentry || leaders.get(&pc).is_some() {
+                    cur_block = Some(pc);
+                }
+                *block_sizes.entry(cur_block.unwrap()).or_insert(0) += 1;
+                reentry = false;
+            }
+
+            match instr.op {
+                Operation::Jmp(_) | Operation::JmpOff(_) | Operation::Syscall => reentry = true,
+                _ => {},
+            }
+        }
+        block_sizes
+    }
 }
@@ -2,14 +2,17 @@ use crate::{
     irgraph::{IRGraph, Flag, Operation, Val},
     emulator::{Emulator, Fault, Register as PReg, ExitType},
     mmu::Perms,
-    config::{CovMethod, COV_METHOD, NO_PERM_CHECKS, FULL_TRACE, MAX_GUEST_ADDR, CMP_COV},
+    annotation::{Annotation, AnnotationMode},
+    config::{CovMethod, COV_METHOD, NO_PERM_CHECKS, FULL_TRACE, MAX_GUEST_ADDR, CMP_COV, SmcPolicy,
+        SMC_POLICY, TRACE_BLOCKS, CMP_LOG, VALUE_PROFILE, MAP_SIZE, MAP_STATS, DETERMINISTIC},
+    HitCounts, CmpLog, Corpus, IndirectJumps, MapCollisions,
 };
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use iced_x86::code_asm::*;
 
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicUsize, AtomicU32, Ordering};
 
 /// Allocate RWX memory for Linux systems
 #[cfg(target_os="linux")]
@@ -32,8 +35,24 @@ pub fn alloc_rwx(size: usize) -> &'static mut [u8] {
 pub enum LibFuncs {
     STRLEN,
     STRCMP,
+    STRNCMP,
+    MEMCMP,
+    MEMCHR,
+    MEMCPY,
+    MEMSET,
 }
 
+/// Longest matched prefix that the JIT-compiled compare functions distinguish
+const LIB_CMP_MAX_PREFIX: usize = 32;
+
+/// Number of calls that the shadow call stack used by call-stack coverage tracks. Deeper calls
+/// leave the calling context unchanged until execution returns below this depth
+pub const CALL_STACK_SIZE: usize = 1024;
+
+/// Granularity at which writes to executable memory stop jumps into the written code from being
+/// linked directly
+const SMC_PAGE_SIZE: usize = 0x1000;
+
 #[derive(Debug)]
 pub struct CompileInputs<'a> {
     /// Total size of allocated emulator memory
@@ -48,6 +67,29 @@ pub struct CompileInputs<'a> {
 
     /// Amount of instructions until a fuzz-case will be manually terminated
     pub timeout: &'a u64,
+
+    /// Guest values whose loads and stores are used as additional coverage feedback
+    pub annotations: &'a [Annotation],
+}
+
+/// State used to invalidate and reinstall translations of self-modifying code. All of it is kept
+/// behind a single lock so a translation is never invalidated halfway while another thread
+/// reinstalls it
+#[derive(Debug, Default)]
+pub struct SmcState {
+    /// Function address - hash of the code that the currently installed translation of the
+    /// function was compiled from
+    code_hashes: FxHashMap<usize, u32>,
+
+    /// Lookup entries of invalidated translations, keyed by function address and code hash. These
+    /// are reinstalled instead of recompiling the function if its code changes back
+    cache: FxHashMap<(usize, u32), Vec<(usize, usize)>>,
+
+    /// Code page - functions whose translations jump directly into the page
+    links: FxHashMap<usize, FxHashSet<usize>>,
+
+    /// Pages that executable memory was written to. Jumps into these are never linked directly
+    written_pages: FxHashSet<usize>,
 }
 
 /// Holds the backing that contains the just-in-time compiled code
@@ -63,6 +105,18 @@ pub struct Jit {
     pub snapshot_inject_size: AtomicUsize,
 
     pub cmpcov_count: AtomicUsize,
+
+    /// Number of value-profile entries that were handed out to comparisons
+    pub value_profile_count: AtomicUsize,
+
+    /// Instructions that are accounted for when an indirect jump lands in the middle of a block,
+    /// indexed the same way as the lookup array. Zero for block starts since they account for
+    /// themselves
+    pub entry_counts: Box<[AtomicU32]>,
+
+    /// Bookkeeping for translations of self-modifying code. Only used when modified code is
+    /// invalidated
+    pub smc: Mutex<SmcState>,
 }
 
 impl Jit {
@@ -75,9 +129,32 @@ impl Jit {
             }).collect::<Vec<_>>().into_boxed_slice(),
             snapshot_inject_size: AtomicUsize::new(0),
             cmpcov_count: AtomicUsize::new(0),
+            value_profile_count: AtomicUsize::new(0),
+            entry_counts: (0..(address_space_size + 3) / 4).map(|_| {
+                AtomicU32::new(0)
+            }).collect::<Vec<_>>().into_boxed_slice(),
+            smc: Mutex::new(SmcState::default()),
         }
     }
 
+    /// Reserve `n` consecutive bits in the cmpcov bitmap, which has one byte per coverage map
+    /// entry. Bits are handed out in the order comparisons are compiled, so once the bitmap is
+    /// exhausted this wraps around and later comparisons share bits with earlier ones. All
+    /// instrumentation that sets cmpcov bits has to reserve them here so it stays within the bitmap
+    fn alloc_cmpcov_bits(&self, n: usize) -> usize {
+        let bits = MAP_SIZE.get().unwrap() * 8;
+        assert!(n <= bits, "Reserved more cmpcov bits than the bitmap holds");
+        self.cmpcov_count.fetch_add(n, Ordering::SeqCst) % (bits - n + 1)
+    }
+
+    /// Reserve the 65 value-profile entries of a comparison, one for each possible number of
+    /// differing bits. Like cmpcov bits, ranges are handed out in the order comparisons are
+    /// compiled and wrap around once the feature map is exhausted
+    fn alloc_value_profile_range(&self) -> usize {
+        self.value_profile_count.fetch_add(65, Ordering::SeqCst) %
+            (Corpus::VALUE_PROFILE_MAP_SIZE - 65 + 1)
+    }
+
     /// Write opcodes to the JIT backing buffer and add a mapping to lookup table
     pub fn add_jitblock(&self, code: &[u8], pc: Option<usize>, 
             local_lookup_map: Option<FxHashMap<usize, usize>>) -> usize {
@@ -119,6 +196,13 @@ impl Jit {
         }
     }
 
+    /// Number of instructions in the compiled block that starts at `addr`. Instructions that follow
+    /// it within the same block have a non-zero entry count, which is the amount of instructions
+    /// left in the block from there
+    pub fn block_size(&self, addr: usize) -> usize {
+        1 + self.entry_counts.get(addr / 4 + 1).map_or(0, |e| e.load(Ordering::SeqCst) as usize)
+    }
+
     /// Look up jit address corresponding to a translated instruction. If a local_lookup_map is
     /// provided, also check if the address is mapped there
     pub fn lookup(&self, pc: usize, local_lookup_map: Option<&FxHashMap<usize, usize>>)
@@ -135,6 +219,87 @@ impl Jit {
         }
     }
 
+    /// Remove all lookup entries that point into the translation of the function at `start`. The
+    /// removed entries are cached alongside the hash of the code they were compiled from if
+    /// `cache` is set
+    pub fn invalidate(&self, start: usize, end: usize, cache: bool) {
+        self.invalidate_locked(&mut self.smc.lock().unwrap(), start, end, cache);
+    }
+
+    /// Invalidate a translation while the smc state is already locked by the caller
+    fn invalidate_locked(&self, smc: &mut SmcState, start: usize, end: usize, cache: bool) {
+        let hash = smc.code_hashes.remove(&start);
+
+        let entries: Vec<(usize, usize)> = (start / 4..(end + 3) / 4).filter_map(|i| {
+            match self.lookup_arr[i].swap(0, Ordering::SeqCst) {
+                0 => None,
+                addr => Some((i, addr)),
+            }
+        }).collect();
+
+        if let Some(h) = hash {
+            if cache && !entries.is_empty() {
+                smc.cache.insert((start, h), entries);
+            }
+        }
+    }
+
+    /// Record a write to the executable page that contains `addr`. Jumps into the page are no
+    /// longer linked directly from here on. Translations that already link into the page might
+    /// jump to stale code, so their cached entries are dropped and their start addresses are
+    /// returned so they can be invalidated
+    pub fn write_code_page(&self, addr: usize) -> Vec<usize> {
+        let mut smc = self.smc.lock().unwrap();
+        let page = addr / SMC_PAGE_SIZE;
+
+        if !smc.written_pages.insert(page) {
+            return Vec::new();
+        }
+
+        let linked: Vec<usize> = smc.links.remove(&page).unwrap_or_default().into_iter().collect();
+        smc.cache.retain(|(start, _), _| !linked.contains(start));
+        linked
+    }
+
+    /// Returns true if jumps to `addr` can be linked directly to the target's translation
+    fn can_link(&self, addr: usize) -> bool {
+        *SMC_POLICY.get().unwrap() != SmcPolicy::Invalidate ||
+            !self.smc.lock().unwrap().written_pages.contains(&(addr / SMC_PAGE_SIZE))
+    }
+
+    /// Record that the translation of the function at `start` jumps directly to `addr`
+    fn add_link(&self, start: usize, addr: usize) {
+        if *SMC_POLICY.get().unwrap() == SmcPolicy::Invalidate {
+            self.smc.lock().unwrap().links.entry(addr / SMC_PAGE_SIZE).or_default().insert(start);
+        }
+    }
+
+    /// Make sure the translation installed for the function at `start` was compiled from code with
+    /// the given hash, invalidating a translation that was compiled from different code. If a
+    /// translation for this exact code was previously invalidated, its lookup entries are
+    /// restored. This only runs when a lookup misses, so it can't catch other threads that still
+    /// reach a translation compiled from this thread's memory, which is why `--smc invalidate` is
+    /// limited to a single thread. Returns false if the function still needs to be compiled
+    pub fn reinstall(&self, start: usize, end: usize, hash: u32) -> bool {
+        let mut smc = self.smc.lock().unwrap();
+
+        match smc.code_hashes.get(&start) {
+            Some(&h) if h == hash => return self.lookup(start, None).is_some(),
+            Some(_) => self.invalidate_locked(&mut smc, start, end, true),
+            None => {},
+        }
+        smc.code_hashes.insert(start, hash);
+
+        if let Some(entries) = smc.cache.get(&(start, hash)) {
+            for (i, addr) in entries {
+                self.lookup_arr[*i].store(*addr, Ordering::SeqCst);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
     /// Add a new mapping to the local lookup table. This has the benefit of providing lookup
     /// mappings that can be used during compilation, but aren't presented to other threads until
     /// after the code is compiled
@@ -182,6 +347,17 @@ impl Jit {
         // Non thread-shared lookup-map that is used to save lookups while compiling.
         let mut local_lookup_map: FxHashMap<usize, usize> = FxHashMap::default();
 
+        // Instructions are accounted for once at the start of each block instead of individually.
+        // Exit conditions also start a new block so execution can resume from them after a
+        // snapshot with accurate instruction counts
+        let mut block_starts = compile_inputs.leaders.clone();
+        block_starts.extend(compile_inputs.exit_conds.keys().map(|&addr| (addr, 0)));
+        let block_sizes = irgraph.get_block_sizes(&block_starts);
+
+        // Instructions that come after the current instruction within its block. These have already
+        // been added to the instruction count, so they are subtracted again if a fault occurs
+        let mut block_tail: usize = 0;
+
         /// Returns the destination register for an operation
         macro_rules! get_reg_64 {
             ($reg: expr, $i: expr) => {
@@ -234,6 +410,17 @@ impl Jit {
             }
         }
 
+        /// Jit exit for faults that can occur in the middle of a block. Corrects the instruction
+        /// count for instructions of the block that have not been executed
+        macro_rules! fault_exit {
+            ($code: expr, $reentry: expr) => {
+                if block_tail != 0 {
+                    asm.sub(rsi, block_tail as i32).unwrap();
+                }
+                jit_exit1!($code, $reentry);
+            }
+        }
+
         /// Generate JIT-code to setup appropriate arguments for a snapshot before leaving JIT
         /// Call + ret() used to get current rip. This is then passed on to the emulator using the
         /// rdx register alongside the size, which then takes care of zeroing out the area.
@@ -250,15 +437,17 @@ impl Jit {
                     tmp_asm.mov(rcx, $reentry as u64).unwrap();
                     tmp_asm.call(here).unwrap();
 
+                    // The nop is only needed to place the label and is not part of the injected
+                    // code, so it is subtracted from both offsets again
                     tmp_asm.set_label(&mut here).unwrap();
                     tmp_asm.nop().unwrap();
-                    let off = tmp_asm.assemble(0x0).unwrap().len();
+                    let off = tmp_asm.assemble(0x0).unwrap().len() - 1;
                     tmp_asm.pop(rbx).unwrap();
                     tmp_asm.sub(rbx, off as i32).unwrap();
                     tmp_asm.mov(ptr(r8), rbx).unwrap();
                     tmp_asm.ret().unwrap();
 
-                    let end = tmp_asm.assemble(0x0).unwrap().len();
+                    let end = tmp_asm.assemble(0x0).unwrap().len() - 1;
                     (end, off)
                 };
 
@@ -282,6 +471,94 @@ impl Jit {
             }
         }
 
+        /// Insert code to increment the hit counter at index rbx, saturating at 255. The first time
+        /// a counter is hit during a case, its index is recorded so it can be cleared once the case
+        /// ends
+        /// r8 + $off        = hit_counts
+        /// r8 + $off + 0x08 = touched_indices
+        /// r8 + $off + 0x10 = num_touched_indices
+        macro_rules! increment_hit_counter {
+            ($off: expr) => {
+                let mut not_saturated = asm.create_label();
+                let mut done = asm.create_label();
+
+                asm.mov(rcx, ptr(r8 + $off)).unwrap();
+                asm.add(rcx, rbx).unwrap();
+                asm.add(byte_ptr(rcx), 1).unwrap();
+                asm.jnc(not_saturated).unwrap();
+                asm.mov(byte_ptr(rcx), 0xff).unwrap();
+                asm.set_label(&mut not_saturated).unwrap();
+                asm.cmp(byte_ptr(rcx), 1).unwrap();
+                asm.jne(done).unwrap();
+
+                // Record the index unless the list is already full
+                asm.mov(rcx, ptr(r8 + ($off + 0x10))).unwrap();
+                asm.mov(rax, ptr(rcx)).unwrap();
+                asm.add(qword_ptr(rcx), 1).unwrap();
+                asm.cmp(rax, HitCounts::MAX_TOUCHED as i32).unwrap();
+                asm.jae(done).unwrap();
+                asm.mov(rcx, ptr(r8 + ($off + 0x08))).unwrap();
+                asm.mov(dword_ptr(rcx + rax * 4), ebx).unwrap();
+
+                asm.set_label(&mut done).unwrap();
+                asm.nop().unwrap();
+            }
+        }
+
+        /// Insert code that records the exact key (rdx) behind the coverage map entry at index rbx.
+        /// Keys that find their entry claimed by a different key are logged once, so the rate at
+        /// which distinct edges collide in the map can be measured. rbx is preserved
+        /// r8 + 0x128 = coverage_keys
+        /// r8 + 0x130 = collision_filter
+        /// r8 + 0x138 = pointer to the number of claimed map entries
+        /// r8 + 0x140 = map_collisions
+        /// r8 + 0x148 = pointer to the number of map collisions
+        macro_rules! track_map_key {
+            () => {
+                let mut claim = asm.create_label();
+                let mut done = asm.create_label();
+
+                asm.mov(rcx, ptr(r8 + 0x128)).unwrap();
+                asm.mov(rax, qword_ptr(rcx + rbx * 8)).unwrap();
+                asm.test(rax, rax).unwrap();
+                asm.jz(claim).unwrap();
+                asm.cmp(rax, rdx).unwrap();
+                asm.je(done).unwrap();
+
+                // The entry belongs to a different key. Skip the key if the list is full or if it
+                // was already logged, using the top bits of a multiplicative hash of the key as
+                // its index into the filter
+                asm.mov(rcx, ptr(r8 + 0x148)).unwrap();
+                asm.cmp(qword_ptr(rcx), MapCollisions::MAX_ENTRIES as i32).unwrap();
+                asm.jae(done).unwrap();
+
+                asm.mov(rax, 0x9e3779b97f4a7c15u64).unwrap();
+                asm.imul_2(rax, rdx).unwrap();
+                asm.shr(rax, 64 - Corpus::COLLISION_FILTER_SIZE.trailing_zeros()).unwrap();
+                asm.mov(rcx, ptr(r8 + 0x130)).unwrap();
+                asm.cmp(qword_ptr(rcx + rax * 8), rdx).unwrap();
+                asm.je(done).unwrap();
+                asm.mov(qword_ptr(rcx + rax * 8), rdx).unwrap();
+
+                // Log the colliding key
+                asm.mov(rcx, ptr(r8 + 0x148)).unwrap();
+                asm.mov(rax, ptr(rcx)).unwrap();
+                asm.add(qword_ptr(rcx), 1).unwrap();
+                asm.mov(rcx, ptr(r8 + 0x140)).unwrap();
+                asm.mov(qword_ptr(rcx + rax * 8), rdx).unwrap();
+                asm.jmp(done).unwrap();
+
+                // First key to reach this entry
+                asm.set_label(&mut claim).unwrap();
+                asm.mov(qword_ptr(rcx + rbx * 8), rdx).unwrap();
+                asm.mov(rcx, ptr(r8 + 0x138)).unwrap();
+                asm.lock().add(qword_ptr(rcx), 1).unwrap();
+
+                asm.set_label(&mut done).unwrap();
+                asm.nop().unwrap();
+            }
+        }
+
         /// Insert code to check if new block-coverage was hit
         /// r8 + 0x30 = coverage_bytemap
         /// r8 + 0x48 = coverage_counter
@@ -289,8 +566,13 @@ impl Jit {
             ($pc: expr) => {
                 let mut fallthrough = asm.create_label();
 
-                // Extract bottom 24 bits of the current pc
-                asm.mov(rbx, ($pc as u64) & 0xffffff).unwrap();
+                // Index the map by the pc's instruction index, which is exact as long as the map
+                // has an entry for each instruction of the guest's address space
+                asm.mov(rbx, (($pc as u64) >> 2) & (*MAP_SIZE.get().unwrap() as u64 - 1)).unwrap();
+                if *MAP_STATS.get().unwrap() {
+                    asm.mov(rdx, $pc as u64).unwrap();
+                    track_map_key!();
+                }
 
                 // Use coverage bytemap to determine if edge has been hit before
                 asm.mov(rcx, ptr(r8 + 0x30)).unwrap();
@@ -305,65 +587,333 @@ impl Jit {
                 asm.add(eax, 1).unwrap();
                 asm.mov(ptr(r8+0x48), rax).unwrap();
 
-                // Not a new coverage case, do nothing
+                // Not a new coverage case, only count the hit for the deterministic stage
                 asm.set_label(&mut fallthrough).unwrap();
+                if *DETERMINISTIC.get().unwrap() {
+                    increment_hit_counter!(0x58);
+                }
+            }
+        }
+
+        /// Insert code that rewards the operands of a comparison getting closer to each other. The
+        /// distance between rax and rbx is bucketed by its number of leading zero-bytes, so each
+        /// additional matching high-order byte (or an exact match) sets a new bit in the cmpcov
+        /// bitmap. The 9 bits used by each comparison are reserved through `alloc_cmpcov_bits` so
+        /// they wrap around within the bitmap. rax and rbx are preserved
+        /// r8 + 0x10 = cmpcov_bitmap
+        /// r8 + 0x88 = cmp_progress_counter
+        macro_rules! compare_progress {
+            ($signed: expr) => {
+                let base = self.alloc_cmpcov_bits(9);
+                let mut positive = asm.create_label();
+                let mut record = asm.create_label();
+                let mut done = asm.create_label();
+
+                // rcx = |rax - rbx|
+                asm.mov(rcx, rax).unwrap();
+                asm.sub(rcx, rbx).unwrap();
+                asm.cmp(rax, rbx).unwrap();
+                if $signed {
+                    asm.jge(positive).unwrap();
+                } else {
+                    asm.jae(positive).unwrap();
+                }
+                asm.neg(rcx).unwrap();
+                asm.set_label(&mut positive).unwrap();
+
+                // rdx = number of leading zero-bytes of the distance, 8 if the operands are equal
+                asm.mov(edx, 8).unwrap();
+                asm.test(rcx, rcx).unwrap();
+                asm.jz(record).unwrap();
+                asm.bsr(rcx, rcx).unwrap();
+                asm.shr(ecx, 3).unwrap();
+                asm.mov(edx, 7).unwrap();
+                asm.sub(edx, ecx).unwrap();
+
+                // Use cmpcov bitmap to determine if this level was reached before
+                asm.set_label(&mut record).unwrap();
+                asm.mov(rcx, base as u64).unwrap();
+                asm.add(rdx, rcx).unwrap();
+                asm.mov(rcx, ptr(r8 + 0x10)).unwrap();
+                asm.bts(qword_ptr(rcx), rdx).unwrap();
+                asm.jc(done).unwrap();
+
+                // New comparison progress
+                asm.add(qword_ptr(r8 + 0x88), 1).unwrap();
+
+                asm.set_label(&mut done).unwrap();
+                asm.nop().unwrap();
+            }
+        }
+
+        /// Insert code to append the operands of a comparison (rax and rbx) to the comparison log.
+        /// Nothing is logged while the log pointer is null. rax and rbx are preserved
+        /// r8 + 0x90 = cmp_log_entries
+        /// r8 + 0x98 = num_cmp_log_entries
+        macro_rules! log_comparison {
+            ($pc: expr) => {
+                let mut done = asm.create_label();
+
+                asm.mov(rcx, ptr(r8 + 0x90)).unwrap();
+                asm.test(rcx, rcx).unwrap();
+                asm.jz(done).unwrap();
+
+                // Skip the comparison if the log is already full
+                asm.mov(rdx, ptr(r8 + 0x98)).unwrap();
+                asm.mov(rdx, ptr(rdx)).unwrap();
+                asm.cmp(rdx, CmpLog::MAX_ENTRIES as i32).unwrap();
+                asm.jae(done).unwrap();
+
+                // Write out the entry (pc, size, op1, op2)
+                asm.shl(rdx, 5).unwrap();
+                asm.add(rcx, rdx).unwrap();
+                asm.mov(rdx, $pc as u64).unwrap();
+                asm.mov(qword_ptr(rcx), rdx).unwrap();
+                asm.mov(qword_ptr(rcx + 0x08), 8).unwrap();
+                asm.mov(qword_ptr(rcx + 0x10), rax).unwrap();
+                asm.mov(qword_ptr(rcx + 0x18), rbx).unwrap();
+
+                asm.mov(rcx, ptr(r8 + 0x98)).unwrap();
+                asm.add(qword_ptr(rcx), 1).unwrap();
+
+                asm.set_label(&mut done).unwrap();
+                asm.nop().unwrap();
+            }
+        }
+
+        /// Insert code to record the number of differing bits between the operands of a
+        /// comparison (rax and rbx) in the value-profile feature map. Each comparison gets its own
+        /// range of 65 entries until the map is exhausted, so getting one more bit right counts as
+        /// a new feature. rax and rbx are preserved
+        /// r8 + 0x100 = value_profile_map
+        /// r8 + 0x108 = value_profile_counter
+        macro_rules! value_profile {
+            ($pc: expr) => {
+                let mut done = asm.create_label();
+                let base = self.alloc_value_profile_range();
+
+                // rcx = index of the feature, based on the number of differing bits
+                asm.mov(rcx, rax).unwrap();
+                asm.xor(rcx, rbx).unwrap();
+                asm.popcnt(rcx, rcx).unwrap();
+                asm.add(rcx, base as i32).unwrap();
+
+                // Use the feature map to determine if this feature was reached before
+                asm.mov(rdx, ptr(r8 + 0x100)).unwrap();
+                asm.cmp(byte_ptr(rdx + rcx), 0).unwrap();
+                asm.jne(done).unwrap();
+
+                // New value-profile feature
+                asm.mov(byte_ptr(rdx + rcx), 1).unwrap();
+                asm.add(qword_ptr(r8 + 0x108), 1).unwrap();
+
+                asm.set_label(&mut done).unwrap();
+                asm.nop().unwrap();
+            }
+        }
+
+        /// Insert code to check if the (jalr site, target) pair of an indirect jump is new
+        /// coverage, so reaching a new case of a switch table or a new callback is rewarded. The
+        /// pairs have their own bytemap so they don't share entries with the edges or blocks of
+        /// the coverage method in use. New pairs are recorded so the targets of each site can be
+        /// exported. The target register is preserved, so it can't be any of rax, rcx, rdx, or rdi
+        /// r8 + 0x48  = coverage_counter
+        /// r8 + 0x110 = indirect_jump_entries
+        /// r8 + 0x118 = num_indirect_jump_entries
+        /// r8 + 0x158 = indirect_bytemap
+        macro_rules! indirect_coverage {
+            ($pc: expr, $target: expr) => {
+                let mut done = asm.create_label();
+                let map_bits = Corpus::INDIRECT_MAP_SIZE.trailing_zeros();
+
+                // Use the top bits of a multiplicative hash of the pair as the bytemap index
+                asm.mov(rax, block_hash($pc)).unwrap();
+                asm.xor(rax, $target).unwrap();
+                asm.mov(rdx, 0x9e3779b97f4a7c15u64).unwrap();
+                asm.imul_2(rax, rdx).unwrap();
+                asm.shr(rax, 64 - map_bits).unwrap();
+
+                asm.mov(rcx, ptr(r8 + 0x158)).unwrap();
+                asm.cmp(byte_ptr(rcx + rax), 0).unwrap();
+                asm.jne(done).unwrap();
+
+                // New indirect target! Update bytemap and increment coverage counter
+                asm.mov(byte_ptr(rcx + rax), 1).unwrap();
+                asm.add(qword_ptr(r8 + 0x48), 1).unwrap();
+
+                // Record the pair unless the list is already full
+                asm.mov(rdx, ptr(r8 + 0x118)).unwrap();
+                asm.mov(rdi, ptr(rdx)).unwrap();
+                asm.cmp(rdi, IndirectJumps::MAX_ENTRIES as i32).unwrap();
+                asm.jae(done).unwrap();
+                asm.add(qword_ptr(rdx), 1).unwrap();
+
+                asm.shl(rdi, 4).unwrap();
+                asm.add(rdi, ptr(r8 + 0x110)).unwrap();
+                asm.mov(rax, $pc as u64).unwrap();
+                asm.mov(qword_ptr(rdi), rax).unwrap();
+                asm.mov(qword_ptr(rdi + 0x08), $target).unwrap();
+
+                asm.set_label(&mut done).unwrap();
+                asm.nop().unwrap();
+            }
+        }
+
+        /// Insert code that feeds the values of annotated locations that overlap the `$size`-byte
+        /// access at `$addr` into the coverage map. Values of `set` annotations are hashed into the
+        /// bytemap, while `max` and `min` annotations are compared against the best value seen so
+        /// far. Minimums are stored inverted so both can be maximized. The address register is
+        /// preserved, so it can't be any of rax, rcx, or rdx
+        /// r8 + 0x30  = coverage_bytemap
+        /// r8 + 0x48  = coverage_counter
+        /// r8 + 0x120 = annotation_best_values
+        macro_rules! annotation_coverage {
+            ($addr: expr, $size: expr) => {
+                let map_bits = MAP_SIZE.get().unwrap().trailing_zeros();
+
+                for (i, annotation) in compile_inputs.annotations.iter().enumerate() {
+                    let mut done = asm.create_label();
+
+                    // The access overlaps the annotation if `addr - (start - size + 1)` is below
+                    // `width + size - 1`
+                    asm.mov(rax, (annotation.addr as u64).wrapping_sub($size as u64 - 1)).unwrap();
+                    asm.mov(rcx, $addr).unwrap();
+                    asm.sub(rcx, rax).unwrap();
+                    asm.cmp(rcx, (annotation.width + $size - 1) as i32).unwrap();
+                    asm.jae(done).unwrap();
+
+                    let value = ptr(r13 + annotation.addr);
+                    match annotation.width {
+                        1 => asm.movzx(eax, byte_ptr(value)).unwrap(),
+                        2 => asm.movzx(eax, word_ptr(value)).unwrap(),
+                        4 => asm.mov(eax, dword_ptr(value)).unwrap(),
+                        _ => asm.mov(rax, qword_ptr(value)).unwrap(),
+                    }
+
+                    match annotation.mode {
+                        AnnotationMode::Set => {
+                            // Use the top bits of a multiplicative hash of the value as the
+                            // bytemap index
+                            asm.mov(rcx, block_hash(annotation.addr)).unwrap();
+                            asm.xor(rax, rcx).unwrap();
+                            asm.mov(rcx, 0x9e3779b97f4a7c15u64).unwrap();
+                            asm.imul_2(rax, rcx).unwrap();
+                            asm.shr(rax, 64 - map_bits).unwrap();
+
+                            asm.mov(rcx, ptr(r8 + 0x30)).unwrap();
+                            asm.cmp(byte_ptr(rcx + rax), 0).unwrap();
+                            asm.jne(done).unwrap();
+                            asm.mov(byte_ptr(rcx + rax), 1).unwrap();
+                        },
+                        AnnotationMode::Max | AnnotationMode::Min => {
+                            let mut retry = asm.create_label();
+                            if annotation.mode == AnnotationMode::Min {
+                                asm.not(rax).unwrap();
+                            }
+
+                            // The best values are shared between threads, so the new value is
+                            // only stored if the best value did not change in the meantime
+                            asm.mov(rdx, rax).unwrap();
+                            asm.mov(rcx, ptr(r8 + 0x120)).unwrap();
+                            asm.mov(rax, qword_ptr(rcx + i * 8)).unwrap();
+                            asm.set_label(&mut retry).unwrap();
+                            asm.cmp(rdx, rax).unwrap();
+                            asm.jbe(done).unwrap();
+                            asm.lock().cmpxchg(qword_ptr(rcx + i * 8), rdx).unwrap();
+                            asm.jne(retry).unwrap();
+                        },
+                    }
+
+                    // New value! Increment coverage counter
+                    asm.add(qword_ptr(r8 + 0x48), 1).unwrap();
+
+                    asm.set_label(&mut done).unwrap();
+                    asm.nop().unwrap();
+                }
             }
         }
 
         /// Insert code to check if new edge-coverage was hit
         /// r8 + 0x30 = coverage_bytemap
-        /// r8 + 0x38 = evolving_input_hash
+        /// r8 + 0x38 = calling_context_hash
         /// r8 + 0x40 = previous_block
         /// r8 + 0x48 = coverage_counter
+        /// r8 + 0x58 = hit_counts
+        /// r8 + 0xb0 = ngram_hash
+        /// r8 + 0xb8 = ngram_ring_index
+        /// r8 + 0xc0 = ngram_ring
         macro_rules! new_edge_coverage {
             ($pc: expr) => {
                 let mut fallthrough = asm.create_label();
 
-                asm.mov(rax, (($pc as u64) << 32)).unwrap();
-                asm.mov(rbx, ptr(r8+0x40)).unwrap();
-                asm.add(rbx, rax).unwrap();
+                if let CovMethod::NGram(n) = *COV_METHOD.get().unwrap() {
+                    Self::roll_ngram_hash(&mut asm, $pc, n);
+                } else {
+                    asm.mov(rax, (($pc as u64) << 32)).unwrap();
+                    asm.mov(rbx, ptr(r8+0x40)).unwrap();
+                    asm.add(rbx, rax).unwrap();
 
-                asm.mov(rax, rbx).unwrap();
+                    asm.mov(rax, rbx).unwrap();
 
-                asm.shl(rax, 13).unwrap();
-                asm.xor(rbx, rax).unwrap();
-                asm.mov(rax, rbx).unwrap();
+                    asm.shl(rax, 13).unwrap();
+                    asm.xor(rbx, rax).unwrap();
+                    asm.mov(rax, rbx).unwrap();
 
-                asm.shr(rax, 17).unwrap();
-                asm.xor(rbx, rax).unwrap();
-                asm.mov(rax, rbx).unwrap();
+                    asm.shr(rax, 17).unwrap();
+                    asm.xor(rbx, rax).unwrap();
+                    asm.mov(rax, rbx).unwrap();
 
-                asm.shl(rax, 43).unwrap();
-                asm.xor(rbx, rax).unwrap();
+                    asm.shl(rax, 43).unwrap();
+                    asm.xor(rbx, rax).unwrap();
+                }
 
                 if *COV_METHOD.get().unwrap() == CovMethod::CallStack {
-                    // Apply evolving call-stack hash to calculated hash and update this inputs 
-                    // evolving hash
+                    // Combine the edge with the hash of the shadow call stack so the same edge
+                    // reached from different calling contexts is tracked separately
                     asm.mov(rax, ptr(r8+0x38)).unwrap();
                     asm.xor(rbx, rax).unwrap();
-                    asm.mov(ptr(r8+0x38), rbx).unwrap();
                 }
 
-                // Extract only the bottom 24-bits for our hashtable index
-                asm.and(rbx, 0xffffff).unwrap();
-
-                // Use coverage bytemap to determine if edge has been hit before
-                asm.xor(eax, eax).unwrap();
-                asm.mov(rcx, ptr(r8 + 0x30)).unwrap();
-                asm.add(rcx, rbx).unwrap();
-                asm.mov(rax, byte_ptr(rcx)).unwrap();
-                asm.test(rax, rax).unwrap();
-                asm.jnz(fallthrough).unwrap();
+                // Extract only the bits that fit the coverage map for our hashtable index. The
+                // full 64-bit hash practically never collides, so it serves as the exact key
+                if *MAP_STATS.get().unwrap() {
+                    asm.mov(rdx, rbx).unwrap();
+                }
+                asm.and(rbx, (MAP_SIZE.get().unwrap() - 1) as i32).unwrap();
+                if *MAP_STATS.get().unwrap() {
+                    track_map_key!();
+                }
 
-                // New edge/coverage event! Update bytemap and increment coverage counter
-                asm.mov(byte_ptr(rcx), 1).unwrap();
-                asm.mov(rax, ptr(r8 + 0x48)).unwrap();
-                asm.add(eax, 1).unwrap();
-                asm.mov(ptr(r8+0x48), rax).unwrap();
+                if *COV_METHOD.get().unwrap() == CovMethod::HitCount {
+                    // New coverage is only determined once the case ends and the counters are
+                    // bucketed
+                    increment_hit_counter!(0x58);
+                } else {
+                    // Use coverage bytemap to determine if edge has been hit before
+                    asm.xor(eax, eax).unwrap();
+                    asm.mov(rcx, ptr(r8 + 0x30)).unwrap();
+                    asm.add(rcx, rbx).unwrap();
+                    asm.mov(rax, byte_ptr(rcx)).unwrap();
+                    asm.test(rax, rax).unwrap();
+                    asm.jnz(fallthrough).unwrap();
+
+                    // New edge/coverage event! Update bytemap and increment coverage counter
+                    asm.mov(byte_ptr(rcx), 1).unwrap();
+                    asm.mov(rax, ptr(r8 + 0x48)).unwrap();
+                    asm.add(eax, 1).unwrap();
+                    asm.mov(ptr(r8+0x48), rax).unwrap();
+                }
 
                 // Not a new coverage case, do standard hash updates
                 asm.set_label(&mut fallthrough).unwrap();
 
+                // The deterministic stage compares the hit counters of its cases to find the bytes
+                // that change the executed path
+                if *DETERMINISTIC.get().unwrap() &&
+                        *COV_METHOD.get().unwrap() != CovMethod::HitCount {
+                    increment_hit_counter!(0x58);
+                }
+
                 // Update the previous block indicator
                 asm.mov(dword_ptr(r8+0x40), $pc as u32).unwrap();
             }
@@ -376,7 +926,7 @@ impl Jit {
                 self.add_jitblock(&asm.assemble(0x0).unwrap(), Some(init_pc), None));
         }
 
-        // String library functions such as strlen() or strcmp() contain optimizations that go out
+        // String library functions such as strlen() or memcmp() contain optimizations that go out
         // of bounds because they always attempt to read 8 bytes at a time. This causes issues for
         // the byte-level permission checks that detect a bug. Since I don't want to incurr the
         // performance overhead of hooking all of them, I instead jit custom implementations of
@@ -425,8 +975,7 @@ impl Jit {
                     // Track coverage if coverage tracking is enabled
                     if *COV_METHOD.get().unwrap() == CovMethod::Block {
                         new_block_coverage!(pc);
-                    } else if *COV_METHOD.get().unwrap() == CovMethod::Edge || 
-                        *COV_METHOD.get().unwrap() == CovMethod::CallStack {
+                    } else if *COV_METHOD.get().unwrap() != CovMethod::None {
                         new_edge_coverage!(pc);
                     }
 
@@ -448,12 +997,26 @@ impl Jit {
                             compile_inputs.exit_conds.remove(&pc);
                             snapshot!(pc);
                         },
-                        _ => panic!("Don't yet support other exit conditions than snapshots"),
+                        ExitType::Exit | ExitType::Success => {
+                            jit_exit1!(12, pc);
+                        },
                     }
                 }
 
-                // Increment instruction counter
-                asm.add(rsi, 1).unwrap();
+                // Increment instruction counter by the size of the block that starts here
+                if let Some(&size) = block_sizes.get(&pc) {
+                    asm.add(rsi, size as i32).unwrap();
+                    block_tail = size - 1;
+
+                    // Record the exact address of this block so coverage can be exported
+                    if *TRACE_BLOCKS.get().unwrap() {
+                        asm.mov(ebx, (pc / 4) as u32).unwrap();
+                        increment_hit_counter!(0x70);
+                    }
+                } else {
+                    block_tail -= 1;
+                    self.entry_counts[pc / 4].store(block_tail as u32 + 1, Ordering::SeqCst);
+                }
             }
 
             match instr.op {
@@ -572,12 +1135,21 @@ impl Jit {
                     asm.mov(rax, ptr(r14 + vr_in1.get_offset())).unwrap();
                     asm.mov(rbx, ptr(r14 + vr_in2.get_offset())).unwrap();
 
-                    // Select wether CmpCov should be enabled for branch-if-equal instructions
+                    if *CMP_LOG.get().unwrap() {
+                        log_comparison!(instr.pc.unwrap());
+                    }
+                    if *VALUE_PROFILE.get().unwrap() {
+                        value_profile!(instr.pc.unwrap());
+                    }
+
+                    // Select wether CmpCov should be enabled for branch instructions. Equality
+                    // checks are split up into byte-compares, while ordered comparisons record how
+                    // close the operands are
                     if *CMP_COV.get().unwrap() {
                         // Separately compare each of the bytes used in the comparison
                         match instr.flags {
                             0b000101 => {   /* Signed | Equal */
-                                let base = self.cmpcov_count.fetch_add(8, Ordering::SeqCst);
+                                let base = self.alloc_cmpcov_bits(8);
                                 for i in 0..8 {
                                     shifted_cmp!(i*8);
                                     cond_jump!();
@@ -585,7 +1157,7 @@ impl Jit {
                                 }
                             },
                             0b001001 => {   /* Signed | NEqual */
-                                let base = self.cmpcov_count.fetch_add(8, Ordering::SeqCst);
+                                let base = self.alloc_cmpcov_bits(8);
                                 let mut shorted_jmp = asm.create_label();
                                 for i in 0..8 {
                                     shifted_cmp!(i*8);
@@ -596,6 +1168,7 @@ impl Jit {
                                 asm.set_label(&mut shorted_jmp).unwrap();
                             },
                             _ => {
+                                compare_progress!(instr.flags & 0b1 != 0);
                                 asm.cmp(rax, rbx).unwrap();
                                 cond_jump!();
                             }
@@ -609,6 +1182,15 @@ impl Jit {
 
                     let shifted = t * 2;
                     asm.mov(rbx, ptr(r15 + shifted)).unwrap();
+
+                    // The target's translation may have been invalidated by self-modifying code
+                    if *SMC_POLICY.get().unwrap() == SmcPolicy::Invalidate {
+                        let mut jump = asm.create_label();
+                        asm.test(rbx, rbx).unwrap();
+                        asm.jnz(jump).unwrap();
+                        jit_exit1!(1, t);
+                        asm.set_label(&mut jump).unwrap();
+                    }
                     asm.jmp(rbx).unwrap();
 
                     // This means the comparison failed
@@ -617,7 +1199,29 @@ impl Jit {
 
                 },
                 Operation::Jmp(addr) => {
-                    if let Some(jit_addr) = self.lookup(addr, Some(&local_lookup_map)) {
+                    if instr.flags & Flag::Call != 0 {
+                        Self::push_call_stack(&mut asm, pc);
+
+                        // Compare functions report their matched prefix to CmpCov separately for
+                        // each call site, so the bits for this call site are reserved here
+                        if *CMP_COV.get().unwrap() && matches!(custom_lib.get(&addr),
+                                Some(LibFuncs::MEMCMP) | Some(LibFuncs::STRNCMP)) {
+                            let base = self.alloc_cmpcov_bits(LIB_CMP_MAX_PREFIX + 1);
+                            asm.mov(rax, base as u64).unwrap();
+                            asm.mov(ptr(r8 + 0x150), rax).unwrap();
+                        }
+                    }
+
+                    // Jumps into code that has been modified are not linked directly since the
+                    // target's translation may be invalidated again later
+                    let direct = if self.can_link(addr) {
+                        self.lookup(addr, Some(&local_lookup_map))
+                    } else {
+                        None
+                    };
+
+                    if let Some(jit_addr) = direct {
+                        self.add_link(init_pc, addr);
                         asm.mov(rbx, jit_addr as u64).unwrap();
                         asm.jmp(rbx).unwrap();
                     } else {
@@ -635,6 +1239,12 @@ impl Jit {
                 Operation::JmpOff(addr) => {
                     let mut jit_exit = asm.create_label();
                     let mut fallthrough = asm.create_label();
+
+                    if instr.flags & Flag::Call != 0 {
+                        Self::push_call_stack(&mut asm, pc);
+                    } else if instr.flags & Flag::Ret != 0 {
+                        Self::pop_call_stack(&mut asm);
+                    }
                     let reg = get_reg_64!(extract_reg!(instr.i_reg[0]), 0);
 
                     asm.add(reg, addr as i32).unwrap();
@@ -647,6 +1257,26 @@ impl Jit {
                     jit_exit2!(10, reg);
 
                     asm.set_label(&mut fallthrough).unwrap();
+
+                    // Returns are already distinguished by the edge at the return address
+                    if instr.flags & Flag::Ret == 0 &&
+                            *COV_METHOD.get().unwrap() != CovMethod::None {
+                        indirect_coverage!(pc, reg);
+                    }
+
+                    // Account for the rest of the block if the target lies in its middle. Targets
+                    // that are misaligned or lie past the instruction counts are not accounted for
+                    let mut skip_count = asm.create_label();
+                    asm.test(reg, 3).unwrap();
+                    asm.jnz(skip_count).unwrap();
+                    asm.mov(rcx, (self.entry_counts.len() * 4) as u64).unwrap();
+                    asm.cmp(reg, rcx).unwrap();
+                    asm.jae(skip_count).unwrap();
+                    asm.mov(rcx, self.entry_counts.as_ptr() as u64).unwrap();
+                    asm.mov(ecx, dword_ptr(rcx + reg)).unwrap();
+                    asm.add(rsi, rcx).unwrap();
+                    asm.set_label(&mut skip_count).unwrap();
+
                     asm.shl(reg, 1u32).unwrap();
                     asm.mov(rcx, ptr(r15 + reg)).unwrap();
                     asm.test(rcx, rcx).unwrap();
@@ -705,12 +1335,12 @@ impl Jit {
                         asm.and(rax, rcx).unwrap();
                         asm.cmp(rax, rcx).unwrap();
                         asm.je(fallthrough).unwrap();
-                        jit_exit1!(9, pc as u64);
+                        fault_exit!(9, pc as u64);
                     }
 
                     // Fault because the access went completely out of bounds
                     asm.set_label(&mut fault).unwrap();
-                    jit_exit1!(10, pc as u64);
+                    fault_exit!(10, pc as u64);
 
                     // Check if the page has already been dirtied, if not set in bitmap and continue
                     asm.set_label(&mut fallthrough).unwrap();
@@ -745,6 +1375,30 @@ impl Jit {
                         },
                         _ => panic!("Unimplemented flag for store operation used"),
                     }
+
+                    annotation_coverage!(r_in1, sz);
+
+                    // Check if the store modified executable memory. If so leave the JIT with the
+                    // written address in `r8 + 0x08` so the emulator can handle the modified code
+                    if *SMC_POLICY.get().unwrap() != SmcPolicy::Ignore {
+                        let mut no_code_write = asm.create_label();
+                        match sz {
+                            1 => asm.movzx(eax, byte_ptr(r_in1 + r12)).unwrap(),
+                            2 => asm.movzx(eax, word_ptr(r_in1 + r12)).unwrap(),
+                            4 => asm.mov(eax, dword_ptr(r_in1 + r12)).unwrap(),
+                            _ => asm.mov(rax, qword_ptr(r_in1 + r12)).unwrap(),
+                        };
+                        let exec_mask = (0..sz).fold(0u64, |acc, i| {
+                            acc + ((Perms::EXECUTE as u64) << (8*i))
+                        });
+                        asm.mov(rcx, exec_mask).unwrap();
+                        asm.test(rax, rcx).unwrap();
+                        asm.jz(no_code_write).unwrap();
+                        asm.mov(ptr(r8 + 0x08), r_in1).unwrap();
+                        jit_exit1!(11, pc as u64 + 4);
+                        asm.set_label(&mut no_code_write).unwrap();
+                        asm.nop().unwrap();
+                    }
                 },
                 Operation::Load => {
                     let vr_out = instr.o_reg.unwrap();
@@ -804,14 +1458,14 @@ impl Jit {
                         asm.and(rax, rcx).unwrap();
                         asm.cmp(rax, rcx).unwrap();
                         asm.je(fallthrough).unwrap();
-                        jit_exit1!(8, pc as u64);
+                        fault_exit!(8, pc as u64);
                     } else {
                         asm.jmp(fallthrough).unwrap();
                     }
 
                     // Fault because the access went completely out of bounds
                     asm.set_label(&mut fault).unwrap();
-                    jit_exit1!(10, pc as u64);
+                    fault_exit!(10, pc as u64);
 
                     asm.set_label(&mut fallthrough).unwrap();
 
@@ -852,6 +1506,8 @@ impl Jit {
                     if vr_out.is_spilled() {
                         asm.mov(ptr(r14 + vr_out.get_offset()), rcx).unwrap();
                     }
+
+                    annotation_coverage!(r_in1, sz);
                 },
                 Operation::Add => {
                     let vr_out = instr.o_reg.unwrap();
@@ -956,7 +1612,7 @@ impl Jit {
                     // Exit JIT with fault if a divide by zero would occur
                     asm.cmp(r_in2, 0).unwrap();
                     asm.je(fallthrough).unwrap();
-                    jit_exit1!(6, pc);
+                    fault_exit!(6, pc);
                     asm.set_label(&mut fallthrough).unwrap();
 
                     match instr.flags {
@@ -1259,6 +1915,32 @@ impl Jit {
                     let vr_out = instr.o_reg.unwrap();
                     let vr_in1 = extract_reg!(instr.i_reg[0]);
                     let in2    = instr.i_reg[1];
+
+                    // Record how close the operands are and log them, including comparisons against
+                    // immediates
+                    if *CMP_COV.get().unwrap() || *CMP_LOG.get().unwrap() ||
+                            *VALUE_PROFILE.get().unwrap() {
+                        let r_in1 = get_reg_64!(vr_in1, 0);
+                        asm.mov(rax, r_in1).unwrap();
+                        match in2 {
+                            Val::Reg(v) => {
+                                let r_in2 = get_reg_64!(v, 0);
+                                asm.mov(rbx, r_in2).unwrap();
+                            },
+                            Val::Imm(v) => asm.mov(rbx, v as i64 as u64).unwrap(),
+                            _ => unreachable!(),
+                        }
+                        if *CMP_COV.get().unwrap() {
+                            compare_progress!(instr.flags == Flag::Signed);
+                        }
+                        if *CMP_LOG.get().unwrap() {
+                            log_comparison!(instr.pc.unwrap());
+                        }
+                        if *VALUE_PROFILE.get().unwrap() {
+                            value_profile!(instr.pc.unwrap());
+                        }
+                    }
+
                     let r_in1  = get_reg_64!(vr_in1, 0);
 
                     // Need an extra register for this operation, use r15 and restore it after instr
@@ -1303,6 +1985,95 @@ impl Jit {
         Some(self.add_jitblock(&asm.assemble(0x0).unwrap(), Some(init_pc), Some(local_lookup_map)))
     }
 
+    /// Emit code that rolls the block at `pc` into the hash of the last `n` blocks and leaves the
+    /// new hash in rbx. The hash is the xor of each block's hash rotated by its distance from the
+    /// current block, so the block that falls out of the window can be removed again using the
+    /// ring of the last `n` block hashes. Clobbers rax, rcx and rdx
+    /// r8 + 0xb0 = ngram_hash
+    /// r8 + 0xb8 = ngram_ring_index
+    /// r8 + 0xc0 = ngram_ring
+    fn roll_ngram_hash(asm: &mut CodeAssembler, pc: usize, n: usize) {
+        asm.mov(rcx, ptr(r8+0xb8)).unwrap();
+        asm.mov(rax, ptr(r8 + rcx * 8 + 0xc0)).unwrap();
+        asm.mov(rbx, block_hash(pc)).unwrap();
+        asm.mov(ptr(r8 + rcx * 8 + 0xc0), rbx).unwrap();
+
+        asm.inc(rcx).unwrap();
+        asm.xor(edx, edx).unwrap();
+        asm.cmp(rcx, n as i32).unwrap();
+        asm.cmove(rcx, rdx).unwrap();
+        asm.mov(ptr(r8+0xb8), rcx).unwrap();
+
+        asm.rol(rax, n as u32).unwrap();
+        asm.xor(rax, rbx).unwrap();
+        asm.mov(rbx, ptr(r8+0xb0)).unwrap();
+        asm.rol(rbx, 1).unwrap();
+        asm.xor(rbx, rax).unwrap();
+        asm.mov(ptr(r8+0xb0), rbx).unwrap();
+    }
+
+    /// Emit code to push a call made from `pc` onto the shadow call stack if call-stack coverage
+    /// is in use. The caller's context hash is saved so it can be restored on return, and the call
+    /// site is mixed into the current context hash. The return address of each call is stored
+    /// `CALL_STACK_SIZE` entries after its context hash
+    /// r8 + 0x38 = calling_context_hash
+    /// r8 + 0xa0 = call_stack
+    /// r8 + 0xa8 = call_stack_depth
+    fn push_call_stack(asm: &mut CodeAssembler, pc: usize) {
+        if *COV_METHOD.get().unwrap() != CovMethod::CallStack {
+            return;
+        }
+        let mut done = asm.create_label();
+
+        asm.mov(rax, ptr(r8 + 0xa8)).unwrap();
+        asm.add(qword_ptr(r8 + 0xa8), 1).unwrap();
+        asm.cmp(rax, CALL_STACK_SIZE as i32).unwrap();
+        asm.jae(done).unwrap();
+
+        asm.mov(rcx, ptr(r8 + 0xa0)).unwrap();
+        asm.mov(rbx, (pc + 4) as u64).unwrap();
+        asm.mov(ptr(rcx + rax * 8 + (CALL_STACK_SIZE * 8) as i32), rbx).unwrap();
+        asm.mov(rbx, ptr(r8 + 0x38)).unwrap();
+        asm.mov(ptr(rcx + rax * 8), rbx).unwrap();
+
+        // Rotate the hash so recursive calls from the same call site don't cancel each other out
+        asm.rol(rbx, 5).unwrap();
+        asm.mov(rax, (pc as u64).wrapping_mul(0x9e3779b97f4a7c15)).unwrap();
+        asm.xor(rbx, rax).unwrap();
+        asm.mov(ptr(r8 + 0x38), rbx).unwrap();
+
+        asm.set_label(&mut done).unwrap();
+        asm.nop().unwrap();
+    }
+
+    /// Emit code to pop the most recent call off the shadow call stack if call-stack coverage is
+    /// in use, restoring the caller's context hash. Returns that were not preceded by a call
+    /// during this case are ignored
+    /// r8 + 0x38 = calling_context_hash
+    /// r8 + 0xa0 = call_stack
+    /// r8 + 0xa8 = call_stack_depth
+    fn pop_call_stack(asm: &mut CodeAssembler) {
+        if *COV_METHOD.get().unwrap() != CovMethod::CallStack {
+            return;
+        }
+        let mut done = asm.create_label();
+
+        asm.mov(rax, ptr(r8 + 0xa8)).unwrap();
+        asm.test(rax, rax).unwrap();
+        asm.jz(done).unwrap();
+        asm.dec(rax).unwrap();
+        asm.mov(ptr(r8 + 0xa8), rax).unwrap();
+        asm.cmp(rax, CALL_STACK_SIZE as i32).unwrap();
+        asm.jae(done).unwrap();
+
+        asm.mov(rcx, ptr(r8 + 0xa0)).unwrap();
+        asm.mov(rbx, ptr(rcx + rax * 8)).unwrap();
+        asm.mov(ptr(r8 + 0x38), rbx).unwrap();
+
+        asm.set_label(&mut done).unwrap();
+        asm.nop().unwrap();
+    }
+
     // TODO permission checks
     /// JIT-compiled strcmp implementation
     fn compile_strcmp(&self, pc: usize) -> Option<usize> {
@@ -1335,22 +2106,14 @@ impl Jit {
         asm.xor(rcx, rcx).unwrap();
         asm.inc(rcx).unwrap();
         asm.mov(ptr(r14 + PReg::A0.get_offset()), rcx).unwrap();
-        // return
-        asm.mov(rbx, ptr(r14 + PReg::Ra.get_offset())).unwrap();
-        asm.shl(rbx, 1).unwrap();
-        asm.mov(rbx, ptr(r15 + rbx)).unwrap();
-        asm.jmp(rbx).unwrap();
+        Self::lib_return(&mut asm);
 
         // Strings not equal exit condition -1
         asm.set_label(&mut end_below).unwrap();
         asm.xor(rcx, rcx).unwrap();
         asm.dec(rcx).unwrap();
         asm.mov(ptr(r14 + PReg::A0.get_offset()), rcx).unwrap();
-        // return
-        asm.mov(rbx, ptr(r14 + PReg::Ra.get_offset())).unwrap();
-        asm.shl(rbx, 1).unwrap();
-        asm.mov(rbx, ptr(r15 + rbx)).unwrap();
-        asm.jmp(rbx).unwrap();
+        Self::lib_return(&mut asm);
 
         // If both strings are at a nullbyte when this is hit, return 0
         asm.set_label(&mut end_equal).unwrap();
@@ -1358,11 +2121,7 @@ impl Jit {
         asm.jnz(end_below).unwrap();
         asm.xor(rcx, rcx).unwrap();
         asm.mov(ptr(r14 + PReg::A0.get_offset()), rcx).unwrap();
-        // Return
-        asm.mov(rbx, ptr(r14 + PReg::Ra.get_offset())).unwrap();
-        asm.shl(rbx, 1).unwrap();
-        asm.mov(rbx, ptr(r15 + rbx)).unwrap();
-        asm.jmp(rbx).unwrap();
+        Self::lib_return(&mut asm);
 
         Some(self.add_jitblock(&asm.assemble(0x0).unwrap(), Some(pc), None))
     }
@@ -1390,23 +2149,365 @@ impl Jit {
         asm.sub(rax, rbx).unwrap();
         asm.mov(ptr(r14 + PReg::A0.get_offset()), rax).unwrap();
 
-        // Return
+        Self::lib_return(&mut asm);
+
+        Some(self.add_jitblock(&asm.assemble(0x0).unwrap(), Some(pc), None))
+    }
+
+    /// Emit code that verifies that the guest address in `addr` lies within the guest's address
+    /// space and has the `perm` permission, jumping to `oob` or `fault` otherwise
+    fn lib_check_access(asm: &mut CodeAssembler, addr: AsmRegister64, perm: u8, oob: CodeLabel,
+                        fault: CodeLabel) {
+        asm.cmp(addr, MAX_GUEST_ADDR as i32).unwrap();
+        asm.jae(oob).unwrap();
+        if !*NO_PERM_CHECKS.get().unwrap() {
+            asm.test(byte_ptr(r12 + addr), perm as i32).unwrap();
+            asm.jz(fault).unwrap();
+        }
+    }
+
+    /// Emit code that marks the page containing the guest address in `addr` as dirty. Clobbers rdx
+    fn lib_mark_dirty(asm: &mut CodeAssembler, addr: AsmRegister64) {
+        let mut skip = asm.create_label();
+
+        asm.mov(rdx, addr).unwrap();
+        asm.shr(rdx, 12).unwrap();
+        asm.bts(qword_ptr(r11), rdx).unwrap();
+        asm.jc(skip).unwrap();
+        asm.mov(qword_ptr(r10 + (r9*8)), rdx).unwrap();
+        asm.add(r9, 1).unwrap();
+
+        asm.set_label(&mut skip).unwrap();
+        asm.nop().unwrap();
+    }
+
+    /// Emit a JIT exit with the given code that reports the return address of the library
+    /// function, so faults are attributed to the caller
+    fn lib_exit(asm: &mut CodeAssembler, label: &mut CodeLabel, code: u64) {
+        asm.set_label(label).unwrap();
+        asm.mov(rax, code).unwrap();
+        asm.mov(rcx, ptr(r14 + PReg::Ra.get_offset())).unwrap();
+        asm.ret().unwrap();
+    }
+
+    /// Emit code to return from a library function to the address in `ra`, leaving the JIT to
+    /// compile it if it has not been compiled yet
+    fn lib_return(asm: &mut CodeAssembler) {
+        let mut in_bounds    = asm.create_label();
+        let mut not_compiled = asm.create_label();
+
+        Self::pop_call_stack(asm);
+
+        // Check that the return address lies within the guest's address space
         asm.mov(rbx, ptr(r14 + PReg::Ra.get_offset())).unwrap();
-        asm.shl(rbx, 1).unwrap();
-        asm.mov(rbx, ptr(r15 + rbx)).unwrap();
-        asm.jmp(rbx).unwrap();
+        asm.mov(rcx, MAX_GUEST_ADDR as u64).unwrap();
+        asm.cmp(rbx, rcx).unwrap();
+        asm.jb(in_bounds).unwrap();
+        asm.mov(rax, 10u64).unwrap();
+        asm.mov(rcx, rbx).unwrap();
+        asm.ret().unwrap();
+
+        asm.set_label(&mut in_bounds).unwrap();
+        asm.mov(rcx, ptr(r15 + rbx * 2)).unwrap();
+        asm.test(rcx, rcx).unwrap();
+        asm.jz(not_compiled).unwrap();
+        asm.jmp(rcx).unwrap();
+
+        asm.set_label(&mut not_compiled).unwrap();
+        asm.mov(rax, 1u64).unwrap();
+        asm.mov(rcx, rbx).unwrap();
+        asm.ret().unwrap();
+    }
+
+    /// JIT-compiled memcmp/strncmp implementation. Each byte is permission checked before it is
+    /// accessed, so unlike the word-at-a-time libc versions this never reads past the compared
+    /// buffers. The length of the matched prefix is reported to CmpCov separately for each call
+    /// site, and the compared buffers are added to the comparison log
+    /// r8 + 0x10 = cmpcov_bitmap
+    /// r8 + 0x18 = cmpcov_counter
+    /// r8 + 0x150 = cmpcov bits reserved by the call site
+    /// r8 + 0x90 = cmp_log_entries
+    /// r8 + 0x98 = num_cmp_log_entries
+    fn compile_memcmp(&self, pc: usize, stop_at_nul: bool) -> Option<usize> {
+        let mut asm = CodeAssembler::new(64).unwrap();
+        let mut loop_start = asm.create_label();
+        let mut finish     = asm.create_label();
+        let mut store      = asm.create_label();
+        let mut oob        = asm.create_label();
+        let mut fault      = asm.create_label();
+
+        // rax = s1, rbx = s2, rdi = n, rcx = number of matching bytes
+        asm.mov(rax, ptr(r14 + PReg::A0.get_offset())).unwrap();
+        asm.mov(rbx, ptr(r14 + PReg::A1.get_offset())).unwrap();
+        asm.mov(rdi, ptr(r14 + PReg::A2.get_offset())).unwrap();
+        asm.xor(ecx, ecx).unwrap();
+
+        // Main loop, compare byte by byte until a mismatch is found or n bytes were compared
+        asm.set_label(&mut loop_start).unwrap();
+        asm.cmp(rcx, rdi).unwrap();
+        asm.jae(finish).unwrap();
+        Self::lib_check_access(&mut asm, rax, Perms::READ, oob, fault);
+        Self::lib_check_access(&mut asm, rbx, Perms::READ, oob, fault);
+        asm.movzx(edx, byte_ptr(r13 + rax)).unwrap();
+        asm.cmp(dl, byte_ptr(r13 + rbx)).unwrap();
+        asm.jne(finish).unwrap();
+        if stop_at_nul {
+            asm.test(dl, dl).unwrap();
+            asm.jz(finish).unwrap();
+        }
+        asm.inc(rax).unwrap();
+        asm.inc(rbx).unwrap();
+        asm.inc(rcx).unwrap();
+        asm.jmp(loop_start).unwrap();
+
+        asm.set_label(&mut finish).unwrap();
+        asm.push(rcx).unwrap();
+
+        // Reward longer matched prefixes at this call site by setting a new bit in the cmpcov
+        // bitmap for each prefix length. Direct calls reserve the bits for their call site, calls
+        // through function pointers share the bits reserved here
+        if *CMP_COV.get().unwrap() {
+            let shared = self.alloc_cmpcov_bits(LIB_CMP_MAX_PREFIX + 1);
+            let mut capped   = asm.create_label();
+            let mut reserved = asm.create_label();
+            let mut done     = asm.create_label();
+
+            asm.mov(rdi, ptr(r8 + 0x150)).unwrap();
+            asm.mov(qword_ptr(r8 + 0x150), -1).unwrap();
+            asm.cmp(rdi, -1).unwrap();
+            asm.jne(reserved).unwrap();
+            asm.mov(rdi, shared as u64).unwrap();
+            asm.set_label(&mut reserved).unwrap();
+
+            asm.cmp(rcx, LIB_CMP_MAX_PREFIX as i32).unwrap();
+            asm.jbe(capped).unwrap();
+            asm.mov(ecx, LIB_CMP_MAX_PREFIX as u32).unwrap();
+            asm.set_label(&mut capped).unwrap();
+            asm.add(rdi, rcx).unwrap();
+
+            asm.mov(rdx, ptr(r8 + 0x10)).unwrap();
+            asm.bts(qword_ptr(rdx), rdi).unwrap();
+            asm.jc(done).unwrap();
+            asm.add(qword_ptr(r8 + 0x18), 1).unwrap();
+
+            asm.set_label(&mut done).unwrap();
+            asm.nop().unwrap();
+        }
+
+        // Log up to the first `LIB_CMP_MAX_PREFIX` bytes of both buffers in 8-byte chunks. These
+        // loads are not permission checked since they are not part of the guest's semantics, the
+        // entry's size limits the chunk to the compared bytes
+        if *CMP_LOG.get().unwrap() {
+            let mut done = asm.create_label();
+
+            asm.mov(rcx, ptr(r8 + 0x90)).unwrap();
+            asm.test(rcx, rcx).unwrap();
+            asm.jz(done).unwrap();
+
+            for off in (0..LIB_CMP_MAX_PREFIX).step_by(8) {
+                let mut sized = asm.create_label();
+
+                // rdi = size of this chunk
+                asm.mov(rdi, ptr(r14 + PReg::A2.get_offset())).unwrap();
+                asm.cmp(rdi, off as i32).unwrap();
+                asm.jbe(done).unwrap();
+                asm.sub(rdi, off as i32).unwrap();
+                asm.cmp(rdi, 8).unwrap();
+                asm.jbe(sized).unwrap();
+                asm.mov(edi, 8).unwrap();
+                asm.set_label(&mut sized).unwrap();
+
+                asm.mov(rax, ptr(r14 + PReg::A0.get_offset())).unwrap();
+                asm.add(rax, off as i32).unwrap();
+                asm.cmp(rax, (MAX_GUEST_ADDR - 8) as i32).unwrap();
+                asm.ja(done).unwrap();
+                asm.mov(rbx, ptr(r14 + PReg::A1.get_offset())).unwrap();
+                asm.add(rbx, off as i32).unwrap();
+                asm.cmp(rbx, (MAX_GUEST_ADDR - 8) as i32).unwrap();
+                asm.ja(done).unwrap();
+                asm.mov(rax, qword_ptr(r13 + rax)).unwrap();
+                asm.mov(rbx, qword_ptr(r13 + rbx)).unwrap();
+
+                // Skip the chunk if the log is already full
+                asm.mov(rdx, ptr(r8 + 0x98)).unwrap();
+                asm.mov(rdx, ptr(rdx)).unwrap();
+                asm.cmp(rdx, CmpLog::MAX_ENTRIES as i32).unwrap();
+                asm.jae(done).unwrap();
+
+                // Write out the entry (pc, size, op1, op2), using the call site as pc
+                asm.mov(rcx, ptr(r8 + 0x90)).unwrap();
+                asm.shl(rdx, 5).unwrap();
+                asm.add(rcx, rdx).unwrap();
+                asm.mov(rdx, ptr(r14 + PReg::Ra.get_offset())).unwrap();
+                asm.mov(qword_ptr(rcx), rdx).unwrap();
+                asm.mov(qword_ptr(rcx + 0x08), rdi).unwrap();
+                asm.mov(qword_ptr(rcx + 0x10), rax).unwrap();
+                asm.mov(qword_ptr(rcx + 0x18), rbx).unwrap();
+
+                asm.mov(rcx, ptr(r8 + 0x98)).unwrap();
+                asm.add(qword_ptr(rcx), 1).unwrap();
+            }
+
+            asm.set_label(&mut done).unwrap();
+            asm.nop().unwrap();
+        }
+
+        // Return the difference of the first mismatching bytes, or 0 if none were found
+        asm.pop(rcx).unwrap();
+        asm.xor(eax, eax).unwrap();
+        asm.cmp(rcx, ptr(r14 + PReg::A2.get_offset())).unwrap();
+        asm.jae(store).unwrap();
+        asm.mov(rax, ptr(r14 + PReg::A0.get_offset())).unwrap();
+        asm.mov(rbx, ptr(r14 + PReg::A1.get_offset())).unwrap();
+        asm.add(rax, rcx).unwrap();
+        asm.add(rbx, rcx).unwrap();
+        asm.movzx(eax, byte_ptr(r13 + rax)).unwrap();
+        asm.movzx(ebx, byte_ptr(r13 + rbx)).unwrap();
+        asm.sub(rax, rbx).unwrap();
+        asm.set_label(&mut store).unwrap();
+        asm.mov(ptr(r14 + PReg::A0.get_offset()), rax).unwrap();
+        Self::lib_return(&mut asm);
+
+        Self::lib_exit(&mut asm, &mut oob, 10);
+        Self::lib_exit(&mut asm, &mut fault, 8);
+
+        Some(self.add_jitblock(&asm.assemble(0x0).unwrap(), Some(pc), None))
+    }
+
+    /// JIT-compiled memchr implementation that permission checks each byte it reads
+    fn compile_memchr(&self, pc: usize) -> Option<usize> {
+        let mut asm = CodeAssembler::new(64).unwrap();
+        let mut loop_start = asm.create_label();
+        let mut found      = asm.create_label();
+        let mut oob        = asm.create_label();
+        let mut fault      = asm.create_label();
+
+        // rax = s, rbx = c, rdi = n, rcx = index
+        asm.mov(rax, ptr(r14 + PReg::A0.get_offset())).unwrap();
+        asm.mov(rbx, ptr(r14 + PReg::A1.get_offset())).unwrap();
+        asm.mov(rdi, ptr(r14 + PReg::A2.get_offset())).unwrap();
+        asm.xor(ecx, ecx).unwrap();
+
+        asm.set_label(&mut loop_start).unwrap();
+        asm.cmp(rcx, rdi).unwrap();
+        asm.jae(found).unwrap();
+        Self::lib_check_access(&mut asm, rax, Perms::READ, oob, fault);
+        asm.cmp(byte_ptr(r13 + rax), bl).unwrap();
+        asm.je(found).unwrap();
+        asm.inc(rax).unwrap();
+        asm.inc(rcx).unwrap();
+        asm.jmp(loop_start).unwrap();
+
+        // Return a pointer to the found byte, or NULL if all n bytes were searched
+        asm.set_label(&mut found).unwrap();
+        asm.xor(edx, edx).unwrap();
+        asm.cmp(rcx, rdi).unwrap();
+        asm.cmovae(rax, rdx).unwrap();
+        asm.mov(ptr(r14 + PReg::A0.get_offset()), rax).unwrap();
+        Self::lib_return(&mut asm);
+
+        Self::lib_exit(&mut asm, &mut oob, 10);
+        Self::lib_exit(&mut asm, &mut fault, 8);
+
+        Some(self.add_jitblock(&asm.assemble(0x0).unwrap(), Some(pc), None))
+    }
+
+    /// JIT-compiled memcpy/memset implementation. Each byte is permission checked before it is
+    /// accessed and the written pages are marked as dirty. Writes to executable memory are handled
+    /// like stores to it: once the function completes, the JIT is left with the first written code
+    /// address in `r8 + 0x08`
+    fn compile_memcpy(&self, pc: usize, is_memset: bool) -> Option<usize> {
+        let mut asm = CodeAssembler::new(64).unwrap();
+        let mut loop_start  = asm.create_label();
+        let mut done        = asm.create_label();
+        let mut oob         = asm.create_label();
+        let mut read_fault  = asm.create_label();
+        let mut write_fault = asm.create_label();
+        let check_smc = *SMC_POLICY.get().unwrap() != SmcPolicy::Ignore;
+
+        // rax = dst, rbx = src/c, rdi = n, rcx = index
+        asm.mov(rax, ptr(r14 + PReg::A0.get_offset())).unwrap();
+        asm.mov(rbx, ptr(r14 + PReg::A1.get_offset())).unwrap();
+        asm.mov(rdi, ptr(r14 + PReg::A2.get_offset())).unwrap();
+        asm.xor(ecx, ecx).unwrap();
+
+        // No code has been written yet
+        if check_smc {
+            asm.mov(qword_ptr(r8 + 0x08), -1).unwrap();
+        }
+
+        asm.set_label(&mut loop_start).unwrap();
+        asm.cmp(rcx, rdi).unwrap();
+        asm.jae(done).unwrap();
+        if !is_memset {
+            Self::lib_check_access(&mut asm, rbx, Perms::READ, oob, read_fault);
+        }
+        Self::lib_check_access(&mut asm, rax, Perms::WRITE, oob, write_fault);
+        Self::lib_mark_dirty(&mut asm, rax);
+        if is_memset {
+            asm.mov(byte_ptr(r13 + rax), bl).unwrap();
+        } else {
+            asm.movzx(edx, byte_ptr(r13 + rbx)).unwrap();
+            asm.mov(byte_ptr(r13 + rax), dl).unwrap();
+            asm.inc(rbx).unwrap();
+        }
+        if check_smc {
+            let mut no_code_write = asm.create_label();
+            asm.test(byte_ptr(r12 + rax), Perms::EXECUTE as i32).unwrap();
+            asm.jz(no_code_write).unwrap();
+            asm.cmp(qword_ptr(r8 + 0x08), -1).unwrap();
+            asm.jne(no_code_write).unwrap();
+            asm.mov(qword_ptr(r8 + 0x08), rax).unwrap();
+            asm.set_label(&mut no_code_write).unwrap();
+        }
+        asm.inc(rax).unwrap();
+        asm.inc(rcx).unwrap();
+        asm.jmp(loop_start).unwrap();
+
+        // Both functions return dst, which is still in a0. If code was written, the emulator
+        // handles it and then resumes execution at the return address
+        asm.set_label(&mut done).unwrap();
+        if check_smc {
+            let mut no_code_write = asm.create_label();
+            asm.cmp(qword_ptr(r8 + 0x08), -1).unwrap();
+            asm.je(no_code_write).unwrap();
+            Self::pop_call_stack(&mut asm);
+            asm.mov(rax, 11u64).unwrap();
+            asm.mov(rcx, ptr(r14 + PReg::Ra.get_offset())).unwrap();
+            asm.ret().unwrap();
+            asm.set_label(&mut no_code_write).unwrap();
+        }
+        Self::lib_return(&mut asm);
+
+        Self::lib_exit(&mut asm, &mut oob, 10);
+        Self::lib_exit(&mut asm, &mut read_fault, 8);
+        Self::lib_exit(&mut asm, &mut write_fault, 9);
 
         Some(self.add_jitblock(&asm.assemble(0x0).unwrap(), Some(pc), None))
     }
 
     fn compile_lib(&self, pc: usize, func: LibFuncs) -> Option<usize> {
         match func {
-            LibFuncs::STRLEN => self.compile_strlen(pc),
-            LibFuncs::STRCMP => self.compile_strcmp(pc),
+            LibFuncs::STRLEN  => self.compile_strlen(pc),
+            LibFuncs::STRCMP  => self.compile_strcmp(pc),
+            LibFuncs::STRNCMP => self.compile_memcmp(pc, true),
+            LibFuncs::MEMCMP  => self.compile_memcmp(pc, false),
+            LibFuncs::MEMCHR  => self.compile_memchr(pc),
+            LibFuncs::MEMCPY  => self.compile_memcpy(pc, false),
+            LibFuncs::MEMSET  => self.compile_memcpy(pc, true),
         }
     }
 }
 
+/// Hash a block's address so every bit of the result depends on it, used to build n-gram hashes
+/// from the rotated hashes of consecutive blocks
+fn block_hash(pc: usize) -> u64 {
+    let mut hash = (pc as u64).wrapping_add(0x9e3779b97f4a7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
 #[allow(non_upper_case_globals)]
 fn to_32(reg: AsmRegister64) -> AsmRegister32 {
     match reg {
@@ -1502,4 +2603,175 @@ mod tests {
         assert_ne!(result3, 0);
         assert_ne!(result4, 0);
     }
+
+    #[test]
+    fn block_instr_counts() {
+        let mut irgraph = IRGraph::new();
+
+        // Block ending in a jump, followed by a block that is entered when returning to it
+        irgraph.init_instr(0x1000);
+        irgraph.movi32(PReg::A0, 1, Flag::NoFlag);
+        irgraph.init_instr(0x1004);
+        irgraph.addi(PReg::A0, PReg::A0, 1, Flag::NoFlag);
+        irgraph.init_instr(0x1008);
+        irgraph.jmp(0x1014, Flag::Call);
+        irgraph.init_instr(0x100c);
+        irgraph.movi32(PReg::A7, 93, Flag::NoFlag);
+        irgraph.init_instr(0x1010);
+        irgraph.syscall();
+
+        // Block that starts at a label
+        irgraph.set_label(0x1014);
+        irgraph.init_instr(0x1014);
+        irgraph.movi32(PReg::A1, 2, Flag::NoFlag);
+        irgraph.init_instr(0x1018);
+        irgraph.mov(PReg::A2, PReg::A1, Flag::NoFlag);
+
+        let mut leaders = irgraph.get_leaders();
+        let block_sizes = irgraph.get_block_sizes(&leaders);
+        assert_eq!(block_sizes.len(), 3);
+        assert_eq!(block_sizes[&0x1000], 3);
+        assert_eq!(block_sizes[&0x100c], 2);
+        assert_eq!(block_sizes[&0x1014], 2);
+
+        // Exit conditions split the block they are in
+        leaders.insert(0x1018, 0);
+        let block_sizes = irgraph.get_block_sizes(&leaders);
+        assert_eq!(block_sizes[&0x1014], 1);
+        assert_eq!(block_sizes[&0x1018], 1);
+
+        // Compiling the function records how many instructions of the block are left at each
+        // instruction that does not start a block
+        COV_METHOD.get_or_init(|| CovMethod::CallStack);
+        CMP_COV.get_or_init(|| false);
+        CMP_LOG.get_or_init(|| false);
+        VALUE_PROFILE.get_or_init(|| false);
+        FULL_TRACE.get_or_init(|| false);
+        TRACE_BLOCKS.get_or_init(|| false);
+        MAP_SIZE.get_or_init(|| 1 << 16);
+        MAP_STATS.get_or_init(|| false);
+        NO_PERM_CHECKS.get_or_init(|| false);
+        DETERMINISTIC.get_or_init(|| false);
+        SMC_POLICY.get_or_init(|| SmcPolicy::Ignore);
+
+        let jit = Jit::new(16 * 1024 * 1024);
+        let mut exit_conds = FxHashMap::default();
+        let mut inputs = CompileInputs {
+            mem_size: 16 * 1024 * 1024,
+            leaders: irgraph.get_leaders(),
+            exit_conds: &mut exit_conds,
+            timeout: &u64::MAX,
+            annotations: &[],
+        };
+        jit.compile(&irgraph, &FxHashMap::default(), &FxHashMap::default(), &mut inputs).unwrap();
+
+        let counts: Vec<u32> = jit.entry_counts[0x1000 / 4..0x101c / 4].iter()
+            .map(|e| e.load(Ordering::SeqCst)).collect();
+        assert_eq!(counts, [0, 2, 1, 0, 1, 0, 1]);
+        assert_eq!(jit.block_size(0x1000), 3);
+        assert_eq!(jit.block_size(0x100c), 2);
+        assert_eq!(jit.block_size(0x1014), 2);
+    }
+
+    #[test]
+    fn call_stack() {
+        COV_METHOD.get_or_init(|| CovMethod::CallStack);
+        assert_eq!(*COV_METHOD.get().unwrap(), CovMethod::CallStack);
+
+        let jit = Jit::new(16 * 1024 * 1024);
+        let mut call_stack = vec![0u64; CALL_STACK_SIZE * 2];
+        let mut scratchpad = [0usize; 22];
+        scratchpad[7]  = 0x1234;
+        scratchpad[20] = call_stack.as_mut_ptr() as usize;
+        let scratchpad_ptr = scratchpad.as_mut_ptr();
+
+        // Runs the code built by `f` on the scratchpad
+        let run = |f: fn(&mut CodeAssembler)| {
+            let mut asm = CodeAssembler::new(64).unwrap();
+            f(&mut asm);
+            asm.ret().unwrap();
+            let addr = jit.add_jitblock(&asm.assemble(0x0).unwrap(), None, None);
+
+            unsafe {
+                let func = *(&addr as *const usize as *const fn());
+                asm!(r#"
+                    push rbx
+                    call {call_dest}
+                    pop rbx
+                "#,
+                call_dest = in(reg) func,
+                out("rax") _,
+                out("rcx") _,
+                in("r8") scratchpad_ptr,
+                );
+            }
+        };
+
+        let hash1 = 0x1234u64.rotate_left(5) ^ 0x1000u64.wrapping_mul(0x9e3779b97f4a7c15);
+        let hash2 = hash1.rotate_left(5) ^ 0x2000u64.wrapping_mul(0x9e3779b97f4a7c15);
+
+        // Each call saves the caller's context and its return address
+        run(|asm| {
+            Jit::push_call_stack(asm, 0x1000);
+            Jit::push_call_stack(asm, 0x2000);
+        });
+        assert_eq!(scratchpad[21], 2);
+        assert_eq!(scratchpad[7] as u64, hash2);
+        assert_eq!(&call_stack[..2], &[0x1234, hash1]);
+        assert_eq!(&call_stack[CALL_STACK_SIZE..CALL_STACK_SIZE + 2], &[0x1004, 0x2004]);
+
+        // Returns restore the caller's context, returns without a call leave it unchanged
+        run(|asm| Jit::pop_call_stack(asm));
+        assert_eq!(scratchpad[21], 1);
+        assert_eq!(scratchpad[7] as u64, hash1);
+
+        run(|asm| {
+            Jit::pop_call_stack(asm);
+            Jit::pop_call_stack(asm);
+        });
+        assert_eq!(scratchpad[21], 0);
+        assert_eq!(scratchpad[7], 0x1234);
+    }
+
+    #[test]
+    fn ngram_hash() {
+        const N: usize = 3;
+        let jit = Jit::new(16 * 1024 * 1024);
+
+        // Runs the n-gram hash over `blocks` on a fresh scratchpad and returns the final hash
+        let run = |blocks: &[usize]| -> u64 {
+            let mut asm = CodeAssembler::new(64).unwrap();
+            for &pc in blocks {
+                Jit::roll_ngram_hash(&mut asm, pc, N);
+            }
+            asm.ret().unwrap();
+            let addr = jit.add_jitblock(&asm.assemble(0x0).unwrap(), None, None);
+
+            let mut scratchpad = [0usize; 32];
+            unsafe {
+                let func = *(&addr as *const usize as *const fn());
+                asm!(r#"
+                    push rbx
+                    call {call_dest}
+                    pop rbx
+                "#,
+                call_dest = in(reg) func,
+                out("rax") _,
+                out("rcx") _,
+                out("rdx") _,
+                in("r8") scratchpad.as_mut_ptr(),
+                );
+            }
+            scratchpad[22] as u64
+        };
+
+        // The hash only depends on the last N blocks, each rotated by its distance
+        let expected = block_hash(0x1014).rotate_left(2) ^ block_hash(0x1018).rotate_left(1) ^
+            block_hash(0x101c);
+        assert_eq!(run(&[0x1014, 0x1018, 0x101c]), expected);
+        assert_eq!(run(&[0x1000, 0x1004, 0x1008, 0x1010, 0x1014, 0x1018, 0x101c]), expected);
+
+        // The order of the blocks within the window matters
+        assert_ne!(run(&[0x1018, 0x1014, 0x101c]), expected);
+    }
 }
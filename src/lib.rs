@@ -15,25 +15,42 @@ pub mod irgraph;
 pub mod mutator;
 pub mod config;
 pub mod pretty_printing;
+pub mod harness;
+pub mod coverage;
+pub mod hooks;
+pub mod annotation;
+pub mod custom_mutator;
+pub mod grammar;
 
 extern crate iced_x86;
 
 use elfparser::{self, ARCH64, ELFMAGIC, LITTLEENDIAN, TYPEEXEC, RISCV};
-use emulator::{Emulator, Register, Fault};
-use mutator::Mutator;
+use emulator::{Emulator, Register, Fault, ExitType, FileType};
+use mmu::Perms;
+use mutator::{Mutator, Mutation, MutationStats, DetProgress, DetStage, constant_tokens};
 use my_libs::sorted_vec::*;
-use config::{FULL_TRACE, OUTPUT_DIR};
+use config::{FULL_TRACE, OUTPUT_DIR, COV_METHOD, CovMethod, TRACE_BLOCKS, CMP_LOG, MAP_STATS,
+    VALUE_PROFILE, DETERMINISTIC, CUSTOM_MUTATOR, CUSTOM_MUTATOR_PROB, SNAPSHOTS, INPUT_PREFIX,
+    EXIT_POINTS, TARGET_POINTS, INJECT, FUNCTION, FUNCTION_ARGS};
+use custom_mutator::{CustomMutator, SharedLibMutator, trim_with};
+use grammar::{Grammar, Node};
+use coverage::CoverageExport;
+use harness::Harness;
 
 use std::process;
+use std::io::Write;
 use std::sync::Arc;
 use std::sync::mpsc::Sender;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicUsize, AtomicU8, AtomicU64, Ordering};
 use std::arch::asm;
+use std::collections::{BTreeMap, BTreeSet};
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use fasthash::{xx::Hash32, FastHash};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
+use parse_int::parse;
+use byteorder::{LittleEndian, WriteBytesExt};
 
 const SAVE_CRASHES: bool = true;
 
@@ -147,6 +164,12 @@ pub fn load_elf_segments(filename: &str, emu_inst: &mut Emulator)
                                     sym_entry.sym_value as isize);
             func_names.insert(sym_entry.sym_value, sym_name.to_string());
         }
+
+        // Data objects can be referred to by name as well (eg. for annotations), but functions
+        // take precedence if the names collide
+        if sym_entry.sym_info == 0x1 || sym_entry.sym_info == 0x11 {
+            symbol_map.entry(sym_name.to_string()).or_insert(sym_entry.sym_value);
+        }
     }
 
     // Some functions such as `frame_dummy` have a size of 0 listed in their metadata. This causes
@@ -165,6 +188,88 @@ pub fn load_elf_segments(filename: &str, emu_inst: &mut Emulator)
     Some(symbol_map)
 }
 
+/// Extract runs of printable characters from the target's read-only loadable segments to be used
+/// as dictionary tokens. Runs shorter than 4 bytes are mostly noise and longer runs are truncated
+/// since the mutator only uses tokens that fit into the input
+pub fn elf_strings(filename: &str) -> Option<Vec<Vec<u8>>> {
+    let target = std::fs::read(filename).ok()?;
+    let elf_hdr = elfparser::Header::new(&target)?;
+    let mut strings: FxHashSet<Vec<u8>> = FxHashSet::default();
+
+    for i in 0..elf_hdr.phnum as usize {
+        let offset = elf_hdr.phoff.checked_add(i * elf_hdr.phentsize as usize)?;
+        let program_hdr = elfparser::ProgramHeader::new(target.get(offset..)?)?;
+
+        if program_hdr.seg_type != elfparser::LOADSEGMENT ||
+                program_hdr.flags as u8 & Perms::WRITE != 0 {
+            continue;
+        }
+
+        let data = target.get(program_hdr.offset..
+            program_hdr.offset.checked_add(program_hdr.filesz)?)?;
+        for run in data.split(|e| !e.is_ascii_graphic() && *e != b' ') {
+            if run.len() >= 4 {
+                strings.insert(run[..run.len().min(64)].to_vec());
+            }
+        }
+    }
+
+    let mut strings: Vec<Vec<u8>> = strings.into_iter().collect();
+    strings.sort();
+    Some(strings)
+}
+
+/// Setup the stack and push the target's argc, argv & envp onto it
+pub fn setup_stack(emu: &mut Emulator, args: &[String]) {
+    let stack = emu.allocate(1024 * 1024, Perms::READ | Perms::WRITE)
+        .expect("Error allocating stack");
+    emu.set_reg(Register::Sp, (stack + (1024 * 1024)) - 8);
+
+    // Setup arguments
+    //let arguments = vec!["test_cases/harder_test\0".to_string(), "fuzz_input\0".to_string()];
+    let argv: Vec<usize> = args.iter().map(|e| {
+        let addr = emu.allocate(64, Perms::READ | Perms::WRITE)
+            .expect("Allocating an argument failed");
+        emu.memory.write_mem(addr, e.as_bytes(), e.len()).expect("Writing to argv[0] failed");
+        addr
+    }).collect();
+
+    // Macro to push 64-bit integers onto the stack
+    macro_rules! push {
+        ($expr:expr) => {
+            let sp = emu.get_reg(Register::Sp) - 8;
+            let mut wtr = vec![];
+            wtr.write_u64::<LittleEndian>($expr as u64).unwrap();
+            emu.memory.write_mem(sp, &wtr, 8).unwrap();
+            emu.set_reg(Register::Sp, sp);
+        }
+    }
+
+    // Setup argc, argv & envp
+    push!(0u64);            // Auxp
+    push!(0u64);            // Envp
+    push!(0u64);            // Null-terminate Argv
+    for arg in argv.iter().rev() {
+        push!(*arg);
+    }
+    push!(argv.len());    // Argc
+}
+
+/// Resolve a location in the target to an address. Locations can either be raw addresses
+/// (`0x1234`), function symbols (`parse_header`), or function symbols with an offset
+/// (`parse_header+0x1c`)
+pub fn resolve_location(sym_map: &FxHashMap<String, usize>, loc: &str) -> Option<usize> {
+    if let Ok(addr) = parse::<usize>(loc) {
+        return Some(addr);
+    }
+
+    let (sym, offset) = match loc.split_once('+') {
+        Some((sym, offset)) => (sym, parse::<usize>(offset).ok()?),
+        None => (loc, 0),
+    };
+    sym_map.get(sym).map(|addr| addr + offset)
+}
+
 
 /// Holds various information related to tracking statistics for the fuzzer
 #[derive(Default, Debug, Deserialize, Serialize)]
@@ -184,11 +289,20 @@ pub struct Statistics {
     /// Number of times new coverage was gained by splitting up comparison instructions
     pub cmpcov: usize,
 
+    /// Number of times the operands of an ordered comparison got closer to each other than before
+    pub cmp_progress: usize,
+
+    /// Number of new (comparison, differing bits) pairs found through value-profile feedback
+    pub value_profile: usize,
+
     /// Number of instructions executed
     pub instr_count: u64,
 
     /// How often a fuzz-input times out due to taking too long
     pub timeouts: u64,
+
+    /// Number of distinct targets that have been reached
+    pub targets: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -214,10 +328,38 @@ pub struct Input {
     /// Counter incremented whenever a mutation on this case finds a new crash. Unlike similar
     /// crashes, new unique crashes increase a cases energy
     ucrashes: usize,
+
+    /// Set once the comparisons of this case have been logged and used for input-to-state
+    /// replacement, so this is only done once per case
+    cmplog_done: bool,
+
+    /// Progress of the deterministic stage. This is taken by the thread that is currently running
+    /// the stage, and None once the stage is done
+    det_progress: Option<DetProgress>,
+
+    /// Set once the deterministic stage is done, or if it is disabled
+    det_done: bool,
+
+    /// Set once the custom mutator trimmed this case
+    trimmed: bool,
+
+    /// Derivation tree of cases that were generated from the grammar. These are mutated on the
+    /// tree instead of byte-wise
+    tree: Option<Node>,
 }
 
 impl Input {
     pub fn new(data: Vec<u8>, exec_time: Option<u64>) -> Self {
+        // Resume the deterministic stage if it was already started on the same input
+        let det_progress = if *DETERMINISTIC.get().unwrap() {
+            Some(DetProgress::load(&det_progress_path(&data))
+                .filter(|e| e.effector.len() == data.len())
+                .unwrap_or_else(|| DetProgress::new(data.len())))
+        } else {
+            None
+        };
+        let det_done = det_progress.as_ref().map_or(true, |e| e.stage == DetStage::Done);
+
         Self {
             data: data.to_vec(),
             size: data.len(),
@@ -225,9 +367,20 @@ impl Input {
             cov_finds: 0,
             crashes: 0,
             ucrashes: 0,
+            cmplog_done: false,
+            det_progress: if det_done { None } else { det_progress },
+            det_done,
+            trimmed: false,
+            tree: None,
         }
     }
 
+    /// Attach the derivation tree that `data` was serialized from
+    pub fn with_tree(mut self, tree: Option<Node>) -> Self {
+        self.tree = tree;
+        self
+    }
+
     pub fn calculate_energy(&self, corpus: &Corpus) -> usize {
         let mut energy: isize = 80000;
         let num_inputs = corpus.inputs.read().len();
@@ -274,17 +427,80 @@ pub struct Corpus {
     pub inputs: RwLock<Vec<Input>>,
 
     /// Bytemap used in jits to determine if an edge has already been hit
-    pub coverage_bytemap: Vec<usize>,
+    pub coverage_bytemap: Vec<u8>,
 
     /// Counter that keeps track of current coverage
     pub cov_counter: AtomicUsize,
 
+    /// Bitmap used in jits to determine if a byte of a comparison has already been matched. It
+    /// has one byte per coverage map entry
     pub cmpcov_bitmap: Vec<u8>,
     pub cmpcov_counter: AtomicUsize,
 
+    /// Bytemap of the (comparison, differing bits) pairs that have been reached. Only allocated
+    /// with value-profile feedback
+    pub value_profile_map: Vec<u8>,
+
+    /// Hit-count classes that have already been seen for each edge. Only allocated with hit-count
+    /// coverage
+    pub virgin_hit_counts: Vec<AtomicU8>,
+
     /// Used to dedup crashses and only save off unique crashes
     pub crash_mapping: RwLock<FxHashMap<Fault, u8>>,
 
+    /// Targets that have already been reached, only the first input to reach a target is saved
+    pub targets_reached: RwLock<FxHashMap<usize, u8>>,
+
+    /// Exact blocks reached by the corpus, mapping each block's address to its size in bytes and
+    /// the amount of corpus entries that reached it. Only populated while tracing blocks
+    pub block_coverage: RwLock<FxHashMap<usize, (usize, usize)>>,
+
+    /// Used to write the traced blocks out in formats that other tools can load
+    pub cov_export: Option<CoverageExport>,
+
+    /// Best value observed so far for each annotation, indexed like `Emulator::annotations`. Only
+    /// used by `max` and `min` annotations, minimums are stored inverted. The JIT updates these
+    /// atomically since they are shared between all threads
+    pub annotation_best: Vec<AtomicU64>,
+
+    /// Bytemap of the (site, target) pairs of indirect jumps that have been reached. Only
+    /// allocated while coverage is tracked
+    pub indirect_bytemap: Vec<u8>,
+
+    /// Distinct targets that were reached from each indirect jump (jalr) site
+    pub indirect_targets: RwLock<BTreeMap<usize, BTreeSet<usize>>>,
+
+    /// Exact key of the edge or block that first claimed each entry of the coverage map. Only
+    /// allocated with map stats
+    pub coverage_keys: Vec<u64>,
+
+    /// Keys that were already logged as collisions, used by the JIT to only log each key once
+    pub collision_filter: Vec<u64>,
+
+    /// Number of coverage map entries that were claimed by a key
+    pub map_keys: AtomicUsize,
+
+    /// Distinct keys that found their coverage map entry claimed by a different key
+    pub map_collisions: RwLock<FxHashSet<u64>>,
+
+    /// Uses and finds of each mutation strategy across all threads, indexed like `Mutation::ALL`
+    pub mutation_stats: Vec<MutationStats>,
+
+    /// Dictionary that each thread's mutator starts off with. This holds the user-supplied tokens
+    /// followed by the printable strings found in the target's read-only segments
+    pub dictionary: Vec<Vec<u8>>,
+
+    /// Grammar that cases with a derivation tree were generated from
+    pub grammar: Option<Grammar>,
+
+    /// Tokens built from the constants in lifted code, in the order they were found. Mutators
+    /// periodically append the new ones to their dictionary
+    pub lifted_tokens: RwLock<Vec<Vec<u8>>>,
+
+    /// Every token that is part of the dictionary or was lifted, used to skip duplicates without
+    /// searching either list
+    known_tokens: RwLock<FxHashSet<Vec<u8>>>,
+
     /// Total size of the inputs in this corpus
     pub total_size: AtomicUsize,
 
@@ -293,15 +509,53 @@ pub struct Corpus {
 }
 
 impl Corpus {
+    /// Number of entries in the value-profile feature map
+    pub const VALUE_PROFILE_MAP_SIZE: usize = 1 << 20;
+
+    /// Number of entries in the bytemap of indirect jump (site, target) pairs
+    pub const INDIRECT_MAP_SIZE: usize = 1 << 20;
+
+    /// Number of entries in the filter of logged collisions
+    pub const COLLISION_FILTER_SIZE: usize = 1 << 20;
+
     /// Start a new corpus. Initialize fields based on what type of coverage method is in use.
     pub fn new(size: usize) -> Self {
         Self {
             inputs:           RwLock::new(Vec::new()),
             coverage_bytemap: vec![0; size],
             cov_counter:      AtomicUsize::new(0),
-            cmpcov_bitmap:    vec![0; 10000000],
+            cmpcov_bitmap:    vec![0; size],
             cmpcov_counter:   AtomicUsize::new(0),
+            value_profile_map: if *VALUE_PROFILE.get().unwrap() {
+                vec![0; Self::VALUE_PROFILE_MAP_SIZE]
+            } else {
+                Vec::new()
+            },
+            virgin_hit_counts: if *COV_METHOD.get().unwrap() == CovMethod::HitCount {
+                (0..size).map(|_| AtomicU8::new(0)).collect()
+            } else {
+                Vec::new()
+            },
             crash_mapping:    RwLock::new(FxHashMap::default()),
+            targets_reached:  RwLock::new(FxHashMap::default()),
+            block_coverage:   RwLock::new(FxHashMap::default()),
+            cov_export:       None,
+            annotation_best:  Vec::new(),
+            indirect_bytemap: if *COV_METHOD.get().unwrap() != CovMethod::None {
+                vec![0; Self::INDIRECT_MAP_SIZE]
+            } else {
+                Vec::new()
+            },
+            indirect_targets: RwLock::new(BTreeMap::new()),
+            coverage_keys:    Vec::new(),
+            collision_filter: Vec::new(),
+            map_keys:         AtomicUsize::new(0),
+            map_collisions:   RwLock::new(FxHashSet::default()),
+            mutation_stats:   Mutation::ALL.iter().map(|_| MutationStats::default()).collect(),
+            dictionary:       Vec::new(),
+            grammar:          None,
+            lifted_tokens:    RwLock::new(Vec::new()),
+            known_tokens:     RwLock::new(FxHashSet::default()),
             total_size:       AtomicUsize::new(0),
             total_exec_time:  AtomicUsize::new(0),
         }
@@ -316,12 +570,252 @@ impl Corpus {
 
         self.cmpcov_bitmap = vec![0; self.cmpcov_bitmap.len()];
         self.cmpcov_counter = AtomicUsize::new(0);
+
+        self.value_profile_map = vec![0; self.value_profile_map.len()];
+        self.indirect_bytemap = vec![0; self.indirect_bytemap.len()];
+        self.annotation_best.iter_mut().for_each(|e| *e.get_mut() = 0);
+
+        self.coverage_keys = vec![0; self.coverage_keys.len()];
+        self.collision_filter = vec![0; self.collision_filter.len()];
+        self.map_keys = AtomicUsize::new(0);
+        self.map_collisions = RwLock::new(FxHashSet::default());
+
+        self.virgin_hit_counts.iter_mut().for_each(|e| *e.get_mut() = 0);
+    }
+
+    /// Append a token to the dictionary unless it is already part of it
+    pub fn add_dictionary_token(&mut self, token: Vec<u8>) {
+        if self.known_tokens.get_mut().insert(token.clone()) {
+            self.dictionary.push(token);
+        }
+    }
+
+    /// Add the tokens for the constants of a freshly lifted function that are not yet part of the
+    /// dictionary
+    pub fn add_lifted_constants(&self, constants: &[i64]) {
+        let tokens: Vec<Vec<u8>> = constants.iter().flat_map(|&e| constant_tokens(e)).collect();
+        if tokens.is_empty() { return; }
+
+        // Most constants were already seen, so the write locks are only taken for new ones
+        let known = self.known_tokens.read();
+        let tokens: Vec<Vec<u8>> = tokens.into_iter().filter(|e| !known.contains(e)).collect();
+        drop(known);
+        if tokens.is_empty() { return; }
+
+        let mut known = self.known_tokens.write();
+        let mut lifted = self.lifted_tokens.write();
+        for token in tokens {
+            if known.insert(token.clone()) {
+                lifted.push(token);
+            }
+        }
+    }
+
+    /// Bucket the hit counters of the case that just finished and compare them against the
+    /// classes that were already seen. Returns the number of new edge/class pairs and clears the
+    /// counters for the next case. Without hit-count coverage the counters are only cleared
+    pub fn update_hit_counts(&self, hit_counts: &mut HitCounts) -> usize {
+        if self.virgin_hit_counts.is_empty() {
+            hit_counts.drain(|_, _| {});
+            return 0;
+        }
+
+        let mut new_cov = 0;
+        hit_counts.drain(|idx, count| {
+            let class = hit_count_class(count);
+            if self.virgin_hit_counts[idx].fetch_or(class, Ordering::SeqCst) & class == 0 {
+                new_cov += 1;
+            }
+        });
+        new_cov
+    }
+}
+
+/// Per-case hit counters that are updated by the JIT, used for hit-count coverage and block
+/// tracing. These are updated without any synchronization, so each emulator has its own
+#[derive(Debug, Default, Clone)]
+pub struct HitCounts {
+    /// Number of times each index was hit during the current case, saturating at 255
+    pub counts: Vec<u8>,
+
+    /// Indices that were hit during the current case, so only these have to be processed and
+    /// cleared once the case ends
+    pub touched: Vec<u32>,
+
+    /// Number of indices that were hit during the current case. This can exceed the size of
+    /// `touched`, in which case the additional indices are not recorded
+    pub num_touched: usize,
+}
+
+impl HitCounts {
+    /// Number of indices per case that are recorded in `touched`
+    pub const MAX_TOUCHED: usize = 1 << 16;
+
+    /// Allocate hit counters for a coverage map of the given size
+    pub fn new(size: usize) -> Self {
+        Self {
+            counts:      vec![0; size],
+            touched:     vec![0; Self::MAX_TOUCHED],
+            num_touched: 0,
+        }
+    }
+
+    /// Checksum of the indices that were hit during the current case and their bucketed counts.
+    /// Cases that take the same path have the same checksum, regardless of the order in which the
+    /// indices were hit
+    pub fn checksum(&self) -> u64 {
+        let mix = |idx: usize, count: u8| {
+            ((idx as u64) << 8 | hit_count_class(count) as u64).wrapping_mul(0x9e3779b97f4a7c15)
+                .rotate_left(31)
+        };
+        if self.num_touched <= self.touched.len() {
+            self.touched[..self.num_touched].iter()
+                .fold(0, |acc, &idx| acc ^ mix(idx as usize, self.counts[idx as usize]))
+        } else {
+            self.counts.iter().enumerate().filter(|(_, e)| **e != 0)
+                .fold(0, |acc, (idx, &count)| acc ^ mix(idx, count))
+        }
+    }
+
+    /// Pass each index that was hit during the current case alongside its count to `f`, and
+    /// clear the counters for the next case
+    pub fn drain(&mut self, mut f: impl FnMut(usize, u8)) {
+        // If more indices were hit than could be recorded, the entire map has to be scanned
+        if self.num_touched <= self.touched.len() {
+            for &idx in &self.touched[..self.num_touched] {
+                f(idx as usize, self.counts[idx as usize]);
+                self.counts[idx as usize] = 0;
+            }
+        } else {
+            for (idx, count) in self.counts.iter_mut().enumerate().filter(|(_, e)| **e != 0) {
+                f(idx, *count);
+                *count = 0;
+            }
+        }
+        self.num_touched = 0;
+    }
+}
+
+/// Operands of a comparison that was executed while comparisons were being logged. These are
+/// written directly by the JIT, so the layout has to remain fixed
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct CmpLogEntry {
+    /// Address of the comparison instruction
+    pub pc: usize,
+
+    /// Width of the comparison in bytes
+    pub size: usize,
+
+    /// First operand of the comparison
+    pub op1: u64,
+
+    /// Second operand of the comparison
+    pub op2: u64,
+}
+
+/// Comparisons executed during the current fuzz case. Logging is only enabled for a single run of
+/// each corpus entry since recording every comparison is expensive
+#[derive(Debug, Default, Clone)]
+pub struct CmpLog {
+    /// Set while the current case should log its comparisons
+    pub enabled: bool,
+
+    /// Logged comparisons, only allocated once logging is first enabled
+    pub entries: Vec<CmpLogEntry>,
+
+    /// Number of comparisons that were executed during the current case. This can exceed the size
+    /// of `entries`, in which case the additional comparisons are not recorded
+    pub num_entries: usize,
+}
+
+impl CmpLog {
+    /// Number of comparisons per case that are recorded
+    pub const MAX_ENTRIES: usize = 1 << 14;
+
+    /// Return the comparisons logged during the current case, and clear the log for the next one
+    pub fn take(&mut self) -> Vec<CmpLogEntry> {
+        let num_entries = self.num_entries.min(self.entries.len());
+        self.num_entries = 0;
+        self.entries[..num_entries].to_vec()
+    }
+}
+
+/// Indirect jumps (jalr site, target) that reached new coverage during the current fuzz case
+#[derive(Debug, Default, Clone)]
+pub struct IndirectJumps {
+    /// Recorded (site, target) pairs, only allocated once coverage is first collected
+    pub entries: Vec<[usize; 2]>,
+
+    /// Number of pairs that were found during the current case. This can exceed the size of
+    /// `entries`, in which case the additional pairs are not recorded
+    pub num_entries: usize,
+}
+
+impl IndirectJumps {
+    /// Number of pairs per case that are recorded
+    pub const MAX_ENTRIES: usize = 1 << 10;
+
+    /// Return the pairs recorded during the current case, and clear them for the next one
+    pub fn take(&mut self) -> Vec<[usize; 2]> {
+        let num_entries = self.num_entries.min(self.entries.len());
+        self.num_entries = 0;
+        self.entries[..num_entries].to_vec()
+    }
+}
+
+/// Coverage map keys that collided with the key that claimed their entry during the current fuzz
+/// case. Only used with map stats
+#[derive(Debug, Default, Clone)]
+pub struct MapCollisions {
+    /// Recorded keys, only allocated once coverage is first collected
+    pub entries: Vec<u64>,
+
+    /// Number of keys that were found during the current case. This can exceed the size of
+    /// `entries`, in which case the additional keys are not recorded
+    pub num_entries: usize,
+}
+
+impl MapCollisions {
+    /// Number of keys per case that are recorded
+    pub const MAX_ENTRIES: usize = 1 << 10;
+
+    /// Return the keys recorded during the current case, and clear them for the next one
+    pub fn take(&mut self) -> Vec<u64> {
+        let num_entries = self.num_entries.min(self.entries.len());
+        self.num_entries = 0;
+        self.entries[..num_entries].to_vec()
+    }
+}
+
+/// Bucket a hit count into one of AFL's classes (1, 2, 3, 4-7, 8-15, 16-31, 32-127, 128+), each
+/// of which is represented by a separate bit
+fn hit_count_class(count: u8) -> u8 {
+    match count {
+        0 => 0,
+        1 => 1,
+        2 => 2,
+        3 => 4,
+        4..=7 => 8,
+        8..=15 => 16,
+        16..=31 => 32,
+        32..=127 => 64,
+        _ => 128,
     }
 }
 
-/// Run the emulator until a Snapshot fault is returned, at which point the injected code is
-/// overwritten with nops, and the 'advanced' emulator is returned back to main
-pub fn snapshot(emu: &mut Emulator, corpus: &Corpus) {
+/// Run the emulator until the snapshot at `addr` has been hit `hits` times, at which point the
+/// injected code is overwritten with nops, and the 'advanced' emulator is returned back to main.
+/// If no input is passed in, each of the initial seeds is attempted until one reaches the
+/// snapshot. The input that reached the snapshot is returned
+pub fn snapshot(emu: &mut Emulator, corpus: &Corpus, addr: usize, hits: usize,
+                input: Option<Vec<u8>>) -> Vec<u8> {
+    let original = emu.fork();
+    let inputs: Vec<Vec<u8>> = match input {
+        Some(v) => vec![v],
+        None => corpus.inputs.read().iter().map(|e| e.data.clone()).collect(),
+    };
+
     // Setup data-structures for tracing, unnecessary for calibration, but required for run_jit
     // function
     let mut trace_arr: Vec<u64> = if *FULL_TRACE.get().unwrap() {
@@ -330,21 +824,200 @@ pub fn snapshot(emu: &mut Emulator, corpus: &Corpus) {
         Vec::new()
     };
     let mut trace_arr_len: usize = 0;
-    let mut tmp = 0;
 
-    // Run jit until finish and collect how long this input needed
-    let case_res = emu.run_jit(corpus, &mut tmp, &mut trace_arr, &mut trace_arr_len);
-    match case_res.0.unwrap() {
-        Fault::Snapshot => {
+    for input in inputs {
+        emu.fuzz_input.clear();
+        emu.fuzz_input.extend_from_slice(&input);
+        emu.snapshot_target = Some((addr, hits));
+
+        // Run jit until the snapshot is reached or the input finishes without reaching it
+        let mut tmp = 0;
+        let case_res = emu.run_jit(corpus, &mut tmp, &mut trace_arr, &mut trace_arr_len);
+
+        // Blocks and edges reached on the way to the snapshot are not part of any fuzz case
+        emu.block_hits.drain(|_, _| {});
+        emu.hit_counts.drain(|_, _| {});
+
+        if case_res.0 == Some(Fault::Snapshot) {
+            emu.snapshot_target = None;
+
             // Overwrite the snapshot code with nops so we dont break there again.
             emu.jit.nop_code(emu.snapshot_addr, None);
-            println!("Snapshot taken");
+            println!("Snapshot taken at {:#x}", addr);
+            return input;
+        }
+        emu.reset(&original);
+    }
+    error_exit(&format!("Failed to reach snapshot at {:#x}, make sure it is reachable", addr));
+}
+
+/// Setup the exit points, snapshots, and harness that every case is run with. If no input prefix
+/// is used, the snapshots are reached using the inputs that are already in the corpus. This is
+/// shared between the fuzzer and `sfuzz-cov` so inputs are replayed the same way they were fuzzed
+pub fn setup_harness(emu: &mut Emulator, corpus: &Corpus, sym_map: &FxHashMap<String, usize>) {
+    // Insert exit conditions that end fuzz cases early, either as if exit() was called or by
+    // reporting that a target was reached
+    for loc in EXIT_POINTS.get().unwrap() {
+        let addr = resolve_location(sym_map, loc).unwrap_or_else(|| {
+            error_exit(&format!("Could not resolve exit point: {}", loc));
+        });
+        emu.exit_conds.insert(addr, ExitType::Exit);
+    }
+    for loc in TARGET_POINTS.get().unwrap() {
+        let addr = resolve_location(sym_map, loc).unwrap_or_else(|| {
+            error_exit(&format!("Could not resolve target: {}", loc));
+        });
+        emu.exit_conds.insert(addr, ExitType::Success);
+    }
+
+    // Setup snapshot fuzzing at a point before the fuzz-input is read in
+    if !SNAPSHOTS.get().unwrap().is_empty() {
+        println!("Activated snapshot-based fuzzing");
+
+        // Resolve the snapshot locations alongside their hit counts (`LOC[:HITS]`)
+        let snapshots: Vec<(usize, usize)> = SNAPSHOTS.get().unwrap().iter().map(|ss| {
+            let (loc, hits) = match ss.split_once(':') {
+                Some((loc, hits)) => (loc, parse::<usize>(hits).ok().filter(|&v| v > 0)),
+                None => (ss.as_str(), Some(1)),
+            };
+            match (resolve_location(sym_map, loc), hits) {
+                (Some(addr), Some(hits)) => (addr, hits),
+                _ => error_exit(&format!("Could not resolve snapshot: {}", ss)),
+            }
+        }).collect();
+
+        // Insert snapshot fuzzer exit conditions. These are all inserted before any snapshot is
+        // taken since code compiled while advancing to an earlier snapshot is not compiled again
+        for (addr, _) in &snapshots {
+            emu.exit_conds.insert(*addr, ExitType::Snapshot);
+        }
+
+        // Snapshot the emulator. Each snapshot starts off of the previous one using the input
+        // that reached it, or the input prefix if one was provided
+        let mut input = INPUT_PREFIX.get().unwrap().clone();
+        for (addr, hits) in snapshots {
+            input = Some(snapshot(emu, corpus, addr, hits, input));
+        }
+        emu.fuzz_input.clear();
+
+        // Fuzz-inputs directly follow the prefix, so the cursors of the already opened fuzz-input
+        // files are moved back to the start of the input. If the target did not consume the
+        // entire prefix, the fuzz-inputs could not be placed right after it
+        if let Some(prefix) = INPUT_PREFIX.get().unwrap() {
+            for file in emu.fd_list.iter_mut().filter(|e| e.ftype == FileType::FUZZINPUT) {
+                let cursor = file.cursor.unwrap();
+                if cursor != prefix.len() {
+                    error_exit(&format!("Target only consumed {} bytes of the {} byte input \
+                                        prefix before the snapshot", cursor, prefix.len()));
+                }
+                file.cursor = Some(0);
+            }
+        }
+    }
+
+    // Directly inject fuzz-inputs into the target once the snapshot is reached
+    if let Some(spec) = INJECT.get().unwrap() {
+        let harness = Harness::parse_inject(sym_map, spec).unwrap_or_else(|| {
+            error_exit(&format!("Could not resolve injection locations: {}", spec));
+        });
+        emu.harness = Some(harness);
+    }
 
-        },
-        _ => panic!("Failed to reach snapshot address, make sure it is trivially reachable"),
+    // Call the fuzzed function directly, starting each case from the function's entry (or the
+    // snapshot if one was taken)
+    if let Some(loc) = FUNCTION.get().unwrap() {
+        let func = resolve_location(sym_map, loc).unwrap_or_else(|| {
+            error_exit(&format!("Could not resolve function: {}", loc));
+        });
+        let harness = Harness::parse_function(FUNCTION_ARGS.get().unwrap()).unwrap_or_else(|| {
+            error_exit(&format!("Invalid function arguments: {}", FUNCTION_ARGS.get().unwrap()));
+        });
+
+        // The function returns to an address without any permissions that ends the fuzz case
+        let sentinel = emu.allocate(4, Perms::UNSET).expect("Allocating the return address failed");
+        emu.exit_conds.insert(sentinel, ExitType::Exit);
+
+        emu.set_reg(Register::Ra, sentinel);
+        emu.set_reg(Register::Sp, emu.get_reg(Register::Sp) & !0xf);
+        emu.set_reg(Register::Pc, func);
+        emu.harness = Some(harness);
     }
 }
 
+/// Export the blocks that the last case reached as a drcov log for the corpus entry `name`, and
+/// merge them into the blocks reached by the entire corpus. The lcov report is rewritten whenever
+/// the corpus reaches new blocks
+pub fn export_blocks(emu: &mut Emulator, corpus: &Corpus, name: &str) {
+    let jit = emu.jit.clone();
+    let mut blocks: Vec<(usize, usize)> = Vec::new();
+    emu.block_hits.drain(|idx, _| blocks.push((idx * 4, jit.block_size(idx * 4) * 4)));
+    blocks.sort_unstable();
+
+    let export = match &corpus.cov_export {
+        Some(v) => v,
+        None => return,
+    };
+    let cov_dir = format!("{}/coverage", OUTPUT_DIR.get().unwrap());
+    export.write_drcov(&format!("{}/{}.drcov", cov_dir, name), &blocks).unwrap();
+
+    let new_blocks = {
+        let mut block_coverage = corpus.block_coverage.write();
+        let num_blocks = block_coverage.len();
+        for (addr, size) in blocks {
+            block_coverage.entry(addr).or_insert((size, 0)).1 += 1;
+        }
+        block_coverage.len() != num_blocks
+    };
+
+    // The report is written from a copy of the reached blocks so other threads can keep updating
+    // them in the meantime
+    if new_blocks {
+        let _v = export.lcov_lock.lock().unwrap();
+        let block_coverage = corpus.block_coverage.read().clone();
+        export.write_lcov(&format!("{}/coverage.info", cov_dir), &block_coverage).unwrap();
+    }
+}
+
+/// Add the indirect jumps that reached new coverage during the last case to the targets reached by
+/// each jalr site, and append the new (site, target) pairs to `indirect_targets` in the output
+/// directory
+pub fn export_indirect_targets(emu: &mut Emulator, corpus: &Corpus) {
+    let jumps = emu.indirect_jumps.take();
+    if jumps.is_empty() {
+        return;
+    }
+
+    let new_targets: Vec<[usize; 2]> = {
+        let mut indirect_targets = corpus.indirect_targets.write();
+        jumps.into_iter()
+            .filter(|[site, target]| indirect_targets.entry(*site).or_default().insert(*target))
+            .collect()
+    };
+    if new_targets.is_empty() {
+        return;
+    }
+
+    // One line per pair, listing the function the site belongs to. The lines of a case are
+    // appended in a single write so they don't interleave with those of other threads
+    let mut out = String::new();
+    for [site, target] in new_targets {
+        let func = emu.function_containing(site)
+            .map_or("?", |e| emu.functions.get(&e).unwrap().1.as_str());
+        out.push_str(&format!("{:#x} ({}): {:#x}\n", site, func, target));
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}/indirect_targets", OUTPUT_DIR.get().unwrap()))
+        .and_then(|mut file| file.write_all(out.as_bytes()))
+        .unwrap();
+}
+
+/// File that the progress of the deterministic stage on `data` is saved to
+fn det_progress_path(data: &[u8]) -> String {
+    format!("{}/deterministic/{:08x}", OUTPUT_DIR.get().unwrap(), Hash32::hash(data))
+}
+
 /// Callibrate how long the initial seeds take to run and use it to determine timeout
 pub fn calibrate_seeds(emu: &mut Emulator, corpus: &Corpus) -> u64 {
     let original = emu.fork();
@@ -365,7 +1038,15 @@ pub fn calibrate_seeds(emu: &mut Emulator, corpus: &Corpus) -> u64 {
         let mut trace_arr_len: usize = 0;
 
         // Run jit until finish and collect how long this input needed
-        emu.run_jit(corpus, &mut instr_count, &mut trace_arr, &mut trace_arr_len);
+        if emu.prepare_case().is_ok() {
+            emu.run_jit(corpus, &mut instr_count, &mut trace_arr, &mut trace_arr_len);
+        }
+
+        // Clear the hit counters so they don't carry over into the next seed
+        corpus.update_hit_counts(&mut emu.hit_counts);
+        if *TRACE_BLOCKS.get().unwrap() {
+            export_blocks(emu, corpus, &format!("seed_{:06}", i));
+        }
 
         let mut inputs = corpus.inputs.write();
         inputs[i].exec_time = Some(instr_count);
@@ -430,21 +1111,138 @@ fn emit_trace(trace_arr: &[u64], trace_arr_len: usize) {
     }
 }
 
+/// Run `input` once with comparison logging enabled. Returns the logged comparisons alongside the
+/// number of instructions that were executed and the result of the case
+fn trace_comparisons(emu: &mut Emulator, original: &Emulator, corpus: &Corpus, input: &[u8])
+        -> (Vec<CmpLogEntry>, u64, (Option<Fault>, usize, usize, usize, usize)) {
+    emu.reset(original);
+    emu.fuzz_input.clear();
+    emu.fuzz_input.extend_from_slice(input);
+
+    let mut instr_count = 0;
+    let mut trace_arr_len = 0;
+    emu.cmp_log.enabled = true;
+    let case_res = match emu.prepare_case() {
+        Ok(()) => emu.run_jit(corpus, &mut instr_count, &mut [], &mut trace_arr_len),
+        Err(fault) => (Some(fault), 0, 0, 0, 0),
+    };
+    emu.cmp_log.enabled = false;
+
+    // This input is already part of the corpus, so the counters are only cleared
+    corpus.update_hit_counts(&mut emu.hit_counts);
+    emu.block_hits.drain(|_, _| {});
+
+    (emu.cmp_log.take(), instr_count, case_res)
+}
+
+/// Run an input that is already part of the corpus once and return the number of instructions it
+/// executed alongside the checksum of its hit counters
+fn measure_trace(emu: &mut Emulator, original: &Emulator, corpus: &Corpus, input: &[u8])
+        -> (u64, u64) {
+    emu.reset(original);
+    emu.fuzz_input.clear();
+    emu.fuzz_input.extend_from_slice(input);
+
+    let mut instr_count = 0;
+    let mut trace_arr_len = 0;
+    if emu.prepare_case().is_ok() {
+        emu.run_jit(corpus, &mut instr_count, &mut [], &mut trace_arr_len);
+    }
+
+    // This input is already part of the corpus, so the counters are only cleared
+    let checksum = emu.hit_counts.checksum();
+    corpus.update_hit_counts(&mut emu.hit_counts);
+    emu.block_hits.drain(|_, _| {});
+
+    (instr_count, checksum)
+}
+
+/// Trim `input` using the custom mutator. Each candidate is run once and kept if it exits after
+/// executing as many instructions as the input, which is used as a proxy for the executed path.
+/// Candidates that find new coverage are added to the corpus. Returns the trimmed input alongside
+/// the number of cases that were run, the instructions they executed, and the coverage they found
+fn trim_input(emu: &mut Emulator, original: &Emulator, corpus: &Corpus,
+              custom: &mut dyn CustomMutator, input: &[u8], exec_time: u64)
+        -> (Vec<u8>, usize, u64, (usize, usize, usize, usize)) {
+    let mut total_instrs = 0;
+    let mut cov = (0, 0, 0, 0);
+
+    let (trimmed, num_cases) = trim_with(custom, input, |candidate| {
+        emu.reset(original);
+        emu.fuzz_input.clear();
+        emu.fuzz_input.extend_from_slice(candidate);
+
+        let mut instr_count = 0;
+        let mut trace_arr_len = 0;
+        let mut case_res = match emu.prepare_case() {
+            Ok(()) => emu.run_jit(corpus, &mut instr_count, &mut [], &mut trace_arr_len),
+            Err(fault) => (Some(fault), 0, 0, 0, 0),
+        };
+        case_res.1 += corpus.update_hit_counts(&mut emu.hit_counts);
+
+        // The coverage was already claimed by this candidate, so it has to be kept
+        let new_entry = if case_res.1 > 0 || case_res.2 > 0 || case_res.3 > 0 || case_res.4 > 0 {
+            Some(add_corpus_entry(corpus, Input::new(candidate.to_vec(), Some(instr_count))))
+        } else {
+            None
+        };
+        match new_entry {
+            Some(id) if *TRACE_BLOCKS.get().unwrap() => {
+                export_blocks(emu, corpus, &format!("id_{:06}", id));
+            },
+            _ => emu.block_hits.drain(|_, _| {}),
+        }
+
+        total_instrs += instr_count;
+        cov = (cov.0 + case_res.1, cov.1 + case_res.2, cov.2 + case_res.3, cov.3 + case_res.4);
+        case_res.0 == Some(Fault::Exit) && instr_count == exec_time
+    });
+
+    (trimmed, num_cases, total_instrs, cov)
+}
+
+/// Add a case that found new coverage to the corpus and save it to the queue so the corpus can
+/// later be replayed (eg. using `sfuzz-cov`). Returns the index of the new entry
+fn add_corpus_entry(corpus: &Corpus, input: Input) -> usize {
+    let mut inputs = corpus.inputs.write();
+    let id = inputs.len();
+    std::fs::write(format!("{}/queue/id_{:06}", OUTPUT_DIR.get().unwrap(), id), &input.data)
+        .unwrap();
+
+    // Add this case's stats to an overall pool that is used to average these values and
+    // calculate the energy for each case.
+    corpus.total_size.fetch_add(input.data.len(), Ordering::SeqCst);
+    corpus.total_exec_time.fetch_add(input.exec_time.unwrap() as usize, Ordering::SeqCst);
+    inputs.push(input);
+    id
+}
+
 /// Wrapper function for each emulator, takes care of running the emulator, memory resets, etc
-pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender<Statistics>) {
+pub fn worker(thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender<Statistics>) {
     // Maintain an original copy of the passed in emulator so it can later be referenced
     let original = emu.fork();
 
     // Initialize a mutator that will be in charge of randomly corrupting input
-    let mut mutator = Mutator::default();
+    let mut mutator = Mutator::new(corpus.dictionary.clone());
+
+    // Each thread gets its own instance of the custom mutator
+    if let Some(lib) = CUSTOM_MUTATOR.get().unwrap() {
+        let custom = SharedLibMutator::load(lib).unwrap_or_else(|e| {
+            error_exit(&format!("Could not load custom mutator: {}", e));
+        });
+        mutator.set_custom(Box::new(custom), thr_id as u32, *CUSTOM_MUTATOR_PROB.get().unwrap());
+    }
 
     // Locally count the number of crashes, total and unique
     let mut local_total_crashes = 0;
     let mut local_unique_crashes = 0;
     let mut local_coverage_count = 0;
     let mut local_cmpcov_count = 0;
+    let mut local_cmp_progress = 0;
+    let mut local_value_profile = 0;
     let mut local_instr_count = 0;
     let mut local_timeouts = 0;
+    let mut local_targets = 0;
 
     // Current index into the input array of the corpus
     let mut input_index = 0;
@@ -481,21 +1279,157 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
         input_index = (input_index + 1) % corpus.inputs.read().len();
         let seed_energy = corpus.inputs.read()[input_index].calculate_energy(&corpus);
 
-        for _ in 0..seed_energy {
+        // The first time a case is picked, the custom mutator gets to trim it. Cases whose
+        // deterministic stage is currently run by a different thread are trimmed later, and the
+        // stage's progress is held while trimming so no other thread starts it on the old data.
+        // Cases with a derivation tree are never trimmed since the tree would no longer match
+        // the trimmed data
+        let mut trim_cases = 0;
+        let untrimmed = corpus.inputs.read().get(input_index)
+            .map_or(false, |e| !e.trimmed && e.tree.is_none());
+        if mutator.custom().map_or(false, |e| e.can_trim()) && untrimmed {
+            let mut inputs = corpus.inputs.write();
+            let entry = &mut inputs[input_index];
+            if !entry.trimmed && (entry.det_done || entry.det_progress.is_some()) {
+                entry.trimmed = true;
+                let data = entry.data.clone();
+                let exec_time = entry.exec_time.unwrap_or(0);
+                let det_progress = entry.det_progress.take();
+                drop(inputs);
+
+                let (trimmed, num_cases, instr_count, cov) = trim_input(&mut emu, &original,
+                    &corpus, mutator.custom().unwrap(), &data, exec_time);
+                trim_cases = num_cases;
+                local_coverage_count += cov.0;
+                local_cmpcov_count += cov.1;
+                local_cmp_progress += cov.2;
+                local_value_profile += cov.3;
+                local_instr_count += instr_count;
+
+                // The trimmed case replaces the entry, and its deterministic stage starts over
+                let mut inputs = corpus.inputs.write();
+                let entry = &mut inputs[input_index];
+                if trimmed.len() < data.len() {
+                    let _ = corpus.total_size.fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+                        |e| Some(e.saturating_sub(data.len() - trimmed.len())));
+
+                    let mut trimmed_entry = Input::new(trimmed, entry.exec_time);
+                    trimmed_entry.cov_finds = entry.cov_finds;
+                    trimmed_entry.crashes = entry.crashes;
+                    trimmed_entry.ucrashes = entry.ucrashes;
+                    trimmed_entry.cmplog_done = entry.cmplog_done;
+                    trimmed_entry.trimmed = true;
+                    *entry = trimmed_entry;
+                } else {
+                    entry.det_progress = det_progress;
+                }
+            }
+        }
+
+        // The first time a case is picked, log its comparisons and queue up inputs in which the
+        // operands found in the input are replaced with the values they were compared against
+        let mut cmplog_cases: Vec<Vec<u8>> = Vec::new();
+        if *CMP_LOG.get().unwrap() &&
+                !std::mem::replace(&mut corpus.inputs.write()[input_index].cmplog_done, true) {
+            let data = corpus.inputs.read()[input_index].data.clone();
+            let (cmp_log, instr_count, case_res) = trace_comparisons(&mut emu, &original,
+                                                                     &corpus, &data);
+            cmplog_cases = mutator.input_to_state(&data, &cmp_log);
+
+            // Coverage that was not yet claimed by other cases is still reported
+            local_coverage_count += case_res.1;
+            local_cmpcov_count += case_res.2;
+            local_cmp_progress += case_res.3;
+            local_value_profile += case_res.4;
+            local_instr_count += instr_count;
+        }
+
+        // Entries that were not yet mutated deterministically run through the deterministic stage
+        // first. The progress is taken out of the entry while this thread works on it, and entries
+        // whose stage is currently run by a different thread are mutated randomly instead
+        let mut det_progress: Option<DetProgress> = None;
+        let mut det_input: Vec<u8> = Vec::new();
+        let mut det_cases = 0;
+        if !corpus.inputs.read()[input_index].det_done {
+            let mut inputs = corpus.inputs.write();
+            if let Some(progress) = inputs[input_index].det_progress.take() {
+                det_progress = Some(progress);
+                det_input = inputs[input_index].data.clone();
+            }
+        }
+
+        // The effector map compares the path each case takes against the path of the unmodified
+        // entry, so the entry is traced first
+        let mut det_baseline = (0, 0);
+        if det_progress.is_some() {
+            det_baseline = measure_trace(&mut emu, &original, &corpus, &det_input);
+            corpus.inputs.write()[input_index].exec_time.get_or_insert(det_baseline.0);
+            local_instr_count += det_baseline.0;
+        }
+        let entry_tree = corpus.inputs.read()[input_index].tree.clone();
+
+        let num_cases = seed_energy + cmplog_cases.len();
+
+        for _ in 0..num_cases {
             // Reset the emulator state
             emu.reset(&original);
             emu.fuzz_input.clear();
 
-            emu.fuzz_input.extend_from_slice(&corpus.inputs.read()[input_index].data);
-
-            // Mutate the previously chosen seed
-            mutator.mutate(&mut emu.fuzz_input);
+            // Inputs generated from the comparison log are run before any random mutations,
+            // followed by the deterministic stage
+            let det_case = match (cmplog_cases.is_empty(), det_progress.as_mut()) {
+                (true, Some(progress)) => mutator.deterministic(&det_input, progress),
+                _ => None,
+            };
+            let mut case_tree: Option<Node> = None;
+            let mutated = if let Some(case) = cmplog_cases.pop() {
+                emu.fuzz_input.extend_from_slice(&case);
+                false
+            } else if let Some(case) = &det_case {
+                emu.fuzz_input.extend_from_slice(case);
+                false
+            } else if let Some((tree, data)) = entry_tree.as_ref()
+                    .and_then(|e| mutator.mutate_tree(e, &corpus)) {
+                // Cases generated from the grammar are mutated on their derivation tree, and
+                // byte-wise if no tree mutation succeeds
+                emu.fuzz_input.extend_from_slice(&data);
+                case_tree = Some(tree);
+                true
+            } else {
+                emu.fuzz_input.extend_from_slice(&corpus.inputs.read()[input_index].data);
+
+                // Mutate the previously chosen seed
+                mutator.mutate(&mut emu.fuzz_input, &corpus);
+                true
+            };
+            mutator.post_process(&mut emu.fuzz_input);
 
             // Execute actual fuzz case and save off status
             let mut case_instr_count: u64 = 0;
             let mut trace_arr_len: usize = 0;
-            let case_res = emu.run_jit(&corpus, &mut case_instr_count, &mut trace_arr,
-                                       &mut trace_arr_len);
+            let mut case_res = match emu.prepare_case() {
+                Ok(()) => emu.run_jit(&corpus, &mut case_instr_count, &mut trace_arr,
+                                      &mut trace_arr_len),
+                Err(fault) => (Some(fault), 0, 0, 0, 0),
+            };
+
+            // A deterministic mutation is considered to have changed the executed path if it
+            // changed the edges that were hit or their bucketed hit counts. The instruction count
+            // is compared as well since no edges are recorded without coverage tracking
+            if let (Some(progress), true) = (det_progress.as_mut(), det_case.is_some()) {
+                progress.record_effect(emu.hit_counts.checksum() != det_baseline.1 ||
+                                       case_instr_count != det_baseline.0);
+
+                // Periodically save the progress so it is not lost if the fuzzer is stopped
+                det_cases += 1;
+                if det_cases % 1000 == 0 {
+                    progress.save(&det_progress_path(&det_input)).unwrap();
+                }
+            }
+
+            // With hit-count coverage, new coverage is determined once the case has finished. The
+            // counters are also kept for the deterministic stage, so they are always cleared
+            case_res.1 += corpus.update_hit_counts(&mut emu.hit_counts);
 
             // Write out a trace on the first fuzz case if requested
             if *FULL_TRACE.get().unwrap() && first_trace {
@@ -512,6 +1446,7 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
                 Fault::ExecFault(_)    |
                 Fault::InvalidFree(_)  |
                 Fault::DivZero(_)  |
+                Fault::CodeWrite(_)  |
                 Fault::OutOfBounds(_) => {
                     let mut crash_map = corpus.crash_mapping.write();
                     if crash_map.get(&case_res.0.unwrap()).is_none() {
@@ -533,6 +1468,10 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
                             Fault::DivZero(v)   => {
                                 format!("{}/crashes/div_{:x}_{}", OUTPUT_DIR.get().unwrap(), v, h)
                             },
+                            Fault::CodeWrite(v)   => {
+                                format!("{}/crashes/code_write_{:x}_{}", 
+                                        OUTPUT_DIR.get().unwrap(), v, h)
+                            },
                             Fault::InvalidFree(v)   => {
                                 format!("{}/crashes/invalid_free_{:x}_{}", 
                                         OUTPUT_DIR.get().unwrap(), v, h)
@@ -547,6 +1486,18 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
                     }
                     local_total_crashes += 1;
                 },
+                // Reached a target for the first time, save off the input that reached it
+                Fault::Success(addr) => {
+                    let mut targets = corpus.targets_reached.write();
+                    if targets.get(&addr).is_none() {
+                        targets.insert(addr, 0);
+                        let h = Hash32::hash(&emu.fuzz_input);
+                        let target_file = format!("{}/targets/target_{:x}_{}", 
+                                                  OUTPUT_DIR.get().unwrap(), addr, h);
+                        std::fs::write(&target_file, &emu.fuzz_input).unwrap();
+                        local_targets += 1;
+                    }
+                },
                 Fault::Timeout => local_timeouts += 1,
                 Fault::Snapshot => panic!("Hit snapshot during execution, this should not happen"),
                 Fault::OOM => panic!("OOM: Emulator ran out of available memory"),
@@ -555,7 +1506,8 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
             }
 
             // This input found new coverage
-            if case_res.1 > 0 || case_res.2 > 0 {
+            let mut new_entry: Option<usize> = None;
+            if case_res.1 > 0 || case_res.2 > 0 || case_res.3 > 0 || case_res.4 > 0 {
                 let mut corp_inputs = corpus.inputs.write();
 
                 // New coverage
@@ -568,26 +1520,72 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
                     local_cmpcov_count += case_res.2;
                 }
 
+                // New comparison progress
+                if case_res.3 > 0 {
+                    local_cmp_progress += case_res.3;
+                }
+
+                // New value-profile features
+                if case_res.4 > 0 {
+                    local_value_profile += case_res.4;
+                }
+
                 corp_inputs[input_index].cov_finds += 1;
-                corp_inputs.push(Input::new(emu.fuzz_input.clone(), Some(case_instr_count)));
+                drop(corp_inputs);
+
+                // Reward the mutation strategies that produced this case
+                if mutated {
+                    mutator.credit();
+                }
+                new_entry = Some(add_corpus_entry(&corpus,
+                    Input::new(emu.fuzz_input.clone(), Some(case_instr_count))
+                        .with_tree(case_tree)));
+            }
+
+            export_indirect_targets(&mut emu, &corpus);
+
+            // Collect the keys that collided in the coverage map
+            if *MAP_STATS.get().unwrap() {
+                let keys = emu.map_collisions.take();
+                if !keys.is_empty() {
+                    corpus.map_collisions.write().extend(keys);
+                }
+            }
 
-                // Add this case's stats to an overall pool that is used to average these values
-                // and calculate the energy for each case.
-                corpus.total_size.fetch_add(emu.fuzz_input.len(), Ordering::SeqCst);
-                corpus.total_exec_time.fetch_add(case_instr_count as usize, Ordering::SeqCst);
+            // Export the exact blocks reached by inputs that were added to the corpus
+            if *TRACE_BLOCKS.get().unwrap() {
+                match new_entry {
+                    Some(id) => export_blocks(&mut emu, &corpus, &format!("id_{:06}", id)),
+                    None => emu.block_hits.drain(|_, _| {}),
+                }
             }
             local_instr_count += case_instr_count;
         }
 
+        // Save the progress of the deterministic stage and hand it back to the entry so it can
+        // be resumed the next time the entry is picked
+        if let Some(progress) = det_progress {
+            progress.save(&det_progress_path(&det_input)).unwrap();
+
+            let mut inputs = corpus.inputs.write();
+            inputs[input_index].det_done = progress.stage == DetStage::Done;
+            if !inputs[input_index].det_done {
+                inputs[input_index].det_progress = Some(progress);
+            }
+        }
+
         // Populate statistics that will be sent to the main thread
         let stats = Statistics {
-            total_cases: seed_energy,
+            total_cases: num_cases + trim_cases,
             crashes:     local_total_crashes,
             ucrashes:    local_unique_crashes,
             coverage:    local_coverage_count,
             cmpcov:      local_cmpcov_count,
+            cmp_progress: local_cmp_progress,
+            value_profile: local_value_profile,
             instr_count: local_instr_count,
             timeouts:    local_timeouts,
+            targets:     local_targets,
         };
 
         // Send stats over to the main thread
@@ -598,8 +1596,11 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
         local_unique_crashes = 0;
         local_coverage_count = 0;
         local_cmpcov_count = 0;
+        local_cmp_progress = 0;
+        local_value_profile = 0;
         local_instr_count = 0;
         local_timeouts = 0;
+        local_targets = 0;
     }
 
     // Restore callee saved registers before returning
@@ -618,3 +1619,49 @@ pub fn worker(_thr_id: usize, mut emu: Emulator, corpus: Arc<Corpus>, tx: Sender
         );
     }
 }
+
+/// Unit tests for building the dictionary and comparing the paths of cases
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hit counters in which `hits` were recorded in order
+    fn hit_counts(hits: &[u32]) -> HitCounts {
+        let mut hit_counts = HitCounts::new(16);
+        for &idx in hits {
+            hit_counts.counts[idx as usize] += 1;
+            if hit_counts.counts[idx as usize] == 1 {
+                hit_counts.touched[hit_counts.num_touched] = idx;
+                hit_counts.num_touched += 1;
+            }
+        }
+        hit_counts
+    }
+
+    #[test]
+    fn hit_count_checksum() {
+        // The order of the hits and counts within the same class don't change the checksum
+        let checksum = hit_counts(&[1, 2, 3, 3, 3, 3]).checksum();
+        assert_eq!(hit_counts(&[3, 2, 3, 1, 3, 3, 3]).checksum(), checksum);
+
+        // Different edges, or counts in a different class do
+        assert_ne!(hit_counts(&[1, 2, 4, 4, 4, 4]).checksum(), checksum);
+        assert_ne!(hit_counts(&[1, 2, 3, 3, 3]).checksum(), checksum);
+        assert_ne!(hit_counts(&[1, 3, 3, 3, 3]).checksum(), checksum);
+    }
+
+    #[test]
+    fn elf_strings_read_only() {
+        let strings = elf_strings("test_cases/simple_test").unwrap();
+        assert!(strings.contains(&b"AAAAAAAA".to_vec()));
+        assert!(strings.iter().all(|e| (4..=64).contains(&e.len())));
+        assert!(strings.iter().flatten().all(|e| e.is_ascii_graphic() || *e == b' '));
+        assert!(strings.windows(2).all(|e| e[0] < e[1]));
+
+        // Sections that are not loaded, like the symbol names, are not searched
+        assert!(!strings.contains(&b"register_fini".to_vec()));
+
+        assert_eq!(elf_strings("test_cases/missing"), None);
+        assert_eq!(elf_strings("test_cases/simple_test.c"), None);
+    }
+}
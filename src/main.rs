@@ -1,118 +1,30 @@
 #![feature(once_cell)]
 
 use sfuzz::{
-    mmu::Perms,
-    emulator::{Emulator, Register, Fault, ExitType},
-    jit::{Jit, LibFuncs},
-    pretty_printing::{print_stats, log, LogType},
-    Input, Corpus, Statistics, error_exit, load_elf_segments, worker, snapshot, calibrate_seeds,
-    config::{handle_cli, Cli, SNAPSHOT_ADDR, OVERRIDE_TIMEOUT, NUM_THREADS, MAX_GUEST_ADDR, 
-        RUN_CASES},
+    emulator::Emulator,
+    jit::Jit,
+    pretty_printing::print_stats,
+    hooks::insert_hooks,
+    Input, Corpus, Statistics, error_exit, load_elf_segments, worker, calibrate_seeds,
+    setup_harness, setup_stack, elf_strings,
+    config::{handle_cli, Cli, OVERRIDE_TIMEOUT, NUM_THREADS, MAX_GUEST_ADDR, RUN_CASES,
+        TRACE_BLOCKS, ANNOTATIONS, MAP_SIZE, MAP_STATS, DICT_FILE, DICT_LEVEL, GRAMMAR},
+    coverage::CoverageExport,
+    annotation::Annotation,
+    mutator::parse_dict,
+    grammar::Grammar,
 };
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::AtomicU64;
 use std::time::{Duration, Instant};
 
-use byteorder::{LittleEndian, WriteBytesExt};
-use rustc_hash::FxHashMap;
 use console::Term;
 use clap::Parser;
 
-/// Hook that makes use of sfuzz's mmu to perform a memory safe malloc operation
-fn malloc_hook(emu: &mut Emulator) -> Result<(), Fault> {
-    let alloc_size = emu.get_reg(Register::A1);
-
-    if let Some(addr) = emu.memory.allocate(alloc_size, Perms::READ | Perms::WRITE) {
-        emu.set_reg(Register::A0, addr);
-        emu.set_reg(Register::Pc, emu.get_reg(Register::Ra));
-        Ok(())
-    } else {
-        Err(Fault::OOM)
-    }
-}
-
-/// Hook that makes use of sfuzz's mmu to perform a memory safe calloc operation, pretty much same
-/// as malloc apart from how the size is calculated
-fn calloc_hook(emu: &mut Emulator) -> Result<(), Fault> {
-    let nmemb = emu.get_reg(Register::A1);
-    let size  = emu.get_reg(Register::A2);
-    let alloc_size = size * nmemb;
-
-    if let Some(addr) = emu.memory.allocate(alloc_size, Perms::READ | Perms::WRITE) {
-        emu.set_reg(Register::A0, addr);
-        emu.set_reg(Register::Pc, emu.get_reg(Register::Ra));
-        Ok(())
-    } else {
-        Err(Fault::OOM)
-    }
-}
-
-/// Hook that makes use of sfuzz's mmu to perform a memory safe free operation
-fn free_hook(emu: &mut Emulator) -> Result<(), Fault> {
-    let ptr = emu.get_reg(Register::A1);
-
-    emu.memory.free(ptr)?;
-    emu.set_reg(Register::Pc, emu.get_reg(Register::Ra));
-    Ok(())
-}
-
-/// Inserts various hooks into binary
-fn insert_hooks(sym_map: &FxHashMap<String, usize>, emu: &mut Emulator) {
-    match sym_map.get("_free_r") {
-        Some(v) => {
-            log(LogType::Success, "_free_r hooked");
-            emu.hooks.insert(*v, free_hook);
-        },
-        None => {
-            log(LogType::Neutral, "free_r does not exist in target so it could not be hooked"); 
-        }
-    }
-
-    match sym_map.get("_malloc_r") {
-        Some(v) => {
-            log(LogType::Success, "_malloc_r hooked");
-            emu.hooks.insert(*v, malloc_hook);
-        },
-        None => {
-            log(LogType::Neutral, "malloc_r does not exist in target so it could not be hooked"); 
-        }
-    }
-
-    match sym_map.get("_calloc_r") {
-        Some(v) => {
-            log(LogType::Success, "_calloc_r hooked");
-            emu.hooks.insert(*v, calloc_hook);
-        },
-        None => {
-            log(LogType::Neutral, "_calloc_r does not exist in target so it could not be hooked"); 
-        }
-    }
-
-    // Hooks for strlen and strcmp are required because the default libc variants go out of bounds.
-    // This is not a security issue since the functions verify that everything is properly aligned,
-    // but since this fuzzer notices byte level permission violations these are required.
-
-    match sym_map.get("strlen") {
-        Some(v) => {
-            log(LogType::Success, "strlen replaced with safe implementation");
-            emu.custom_lib.insert(*v, LibFuncs::STRLEN);
-        },
-        None => {
-            log(LogType::Neutral, "strlen does not exist in target so it could not be hooked"); 
-        }
-    }
-
-    match sym_map.get("strcmp") {
-        Some(v) => {
-            log(LogType::Success, "strcmp replaced with safe implementation");
-            emu.custom_lib.insert(*v, LibFuncs::STRCMP);
-        },
-        None => { 
-            log(LogType::Neutral, "strcmp does not exist in target so it could not be hooked"); 
-        }
-    }
-}
+/// Number of seeds that are generated from the grammar
+const GRAMMAR_SEEDS: usize = 64;
 
 /// Setup the root emulator's segments and stack before cloning the emulator into multiple threads
 /// to run multiple emulators at the same time
@@ -123,9 +35,6 @@ fn main() -> std::io::Result<()> {
     // Thread-shared mutex that is used to lock JIT-compilation
     let prevent_rc: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
 
-    // Thread-shared structure that holds fuzz-inputs and coverage information
-    let mut corpus: Corpus = Corpus::new(16*1024*1024);
-
     // Each thread gets its own forked emulator. The jit-cache is shared between them however
     let mut emu = Emulator::new(MAX_GUEST_ADDR, jit, prevent_rc);
 
@@ -143,11 +52,46 @@ fn main() -> std::io::Result<()> {
     let mut args = Cli::parse();
     handle_cli(&mut args);
 
+    // Thread-shared structure that holds fuzz-inputs and coverage information
+    let mut corpus: Corpus = Corpus::new(*MAP_SIZE.get().unwrap());
+    if *MAP_STATS.get().unwrap() {
+        corpus.coverage_keys = vec![0; *MAP_SIZE.get().unwrap()];
+        corpus.collision_filter = vec![0; Corpus::COLLISION_FILTER_SIZE];
+    }
+
     // Insert loadable segments into emulator address space and retrieve symbol table information
     let sym_map = load_elf_segments(&args.fuzzed_app[0], &mut emu).unwrap_or_else(||{
         error_exit("Unrecoverable error while loading elf segments");
     });
 
+    // Build the dictionary from the user-supplied tokens followed by the strings found in the
+    // target's read-only segments
+    if let Some(dict) = DICT_FILE.get().unwrap() {
+        let tokens = parse_dict(dict, *DICT_LEVEL.get().unwrap()).unwrap_or_else(|e| {
+            error_exit(&format!("Invalid dictionary: {}", e));
+        });
+        for token in tokens {
+            corpus.add_dictionary_token(token);
+        }
+    }
+    for token in elf_strings(&args.fuzzed_app[0]).unwrap_or_default() {
+        corpus.add_dictionary_token(token);
+    }
+
+    // Parse the target's line information so reached blocks can be mapped back to source lines
+    if *TRACE_BLOCKS.get().unwrap() {
+        corpus.cov_export = Some(CoverageExport::new(&args.fuzzed_app[0]).unwrap_or_else(|| {
+            error_exit("Unrecoverable error while parsing the target for coverage export");
+        }));
+    }
+
+    // Load the grammar that additional seeds are generated from
+    if let Some(file) = GRAMMAR.get().unwrap() {
+        corpus.grammar = Some(Grammar::load(file).unwrap_or_else(|e| {
+            error_exit(&format!("Invalid grammar: {}", e));
+        }));
+    }
+
     // Initialize corpus with files from input directory
     let mut w = corpus.inputs.write();
     for filename in std::fs::read_dir(args.input_dir)? {
@@ -157,56 +101,33 @@ fn main() -> std::io::Result<()> {
         // Add the corpus input to the corpus
         w.push(Input::new(data, None));
     }
-    if w.is_empty() { panic!("Please supply at least 1 initial seed"); }
-    drop(w);
 
-    // Setup Stack
-    let stack = emu.allocate(1024 * 1024, Perms::READ | Perms::WRITE)
-        .expect("Error allocating stack");
-    emu.set_reg(Register::Sp, (stack + (1024 * 1024)) - 8);
-
-    // Setup arguments
-    //let arguments = vec!["test_cases/harder_test\0".to_string(), "fuzz_input\0".to_string()];
-    let argv: Vec<usize> = args.fuzzed_app.iter().map(|e| {
-        let addr = emu.allocate(64, Perms::READ | Perms::WRITE)
-            .expect("Allocating an argument failed");
-        emu.memory.write_mem(addr, e.as_bytes(), e.len()).expect("Writing to argv[0] failed");
-        addr
-    }).collect();
-
-    // Macro to push 64-bit integers onto the stack
-    macro_rules! push {
-        ($expr:expr) => {
-            let sp = emu.get_reg(Register::Sp) - 8;
-            let mut wtr = vec![];
-            wtr.write_u64::<LittleEndian>($expr as u64)?;
-            emu.memory.write_mem(sp, &wtr, 8).unwrap();
-            emu.set_reg(Register::Sp, sp);
+    // Add seeds generated from the grammar alongside their derivation trees
+    if let Some(grammar) = &corpus.grammar {
+        for tree in grammar.seeds(GRAMMAR_SEEDS) {
+            w.push(Input::new(grammar.unparse(&tree), None).with_tree(Some(tree)));
         }
     }
+    if w.is_empty() { panic!("Please supply at least 1 initial seed"); }
+    drop(w);
 
-    // Setup argc, argv & envp
-    push!(0u64);            // Auxp
-    push!(0u64);            // Envp
-    push!(0u64);            // Null-terminate Argv
-    for arg in argv.iter().rev() {
-        push!(*arg);
-    }
-    push!(argv.len());    // Argc
+    // Setup the stack alongside the target's arguments
+    setup_stack(&mut emu, &args.fuzzed_app);
 
     // Insert various hooks into binary
     insert_hooks(&sym_map, &mut emu);
 
-    // Setup snapshot fuzzing at a point before the fuzz-input is read in
-    if let Some(addr) = SNAPSHOT_ADDR.get().unwrap() {
-        println!("Activated snapshot-based fuzzing");
-
-        // Insert snapshot fuzzer exit condition
-        emu.exit_conds.insert(*addr, ExitType::Snapshot);
-
-        // Snapshot the emulator
-        snapshot(&mut emu, &corpus);
+    // Resolve the guest values that are used as additional coverage feedback
+    for spec in ANNOTATIONS.get().unwrap() {
+        let annotation = Annotation::parse(&sym_map, spec).unwrap_or_else(|| {
+            error_exit(&format!("Invalid annotation: {}", spec));
+        });
+        emu.annotations.push(annotation);
     }
+    corpus.annotation_best = emu.annotations.iter().map(|_| AtomicU64::new(0)).collect();
+
+    // Insert the exit points, take the snapshots, and setup the harness that cases are run with
+    setup_harness(&mut emu, &corpus, &sym_map);
 
     // Calibrate the emulator for the timeout.
     // Alternatively configs can be used to override automatically determined timeout
@@ -247,17 +168,21 @@ fn main() -> std::io::Result<()> {
 
 
         // Check if we got new coverage
-        if received.coverage != 0 || received.cmpcov != 0 {
+        if received.coverage != 0 || received.cmpcov != 0 || received.cmp_progress != 0 ||
+                received.value_profile != 0 {
             last_cov_event = elapsed_time;
         }
 
         stats.coverage    += received.coverage;
         stats.cmpcov      += received.cmpcov;
+        stats.cmp_progress += received.cmp_progress;
+        stats.value_profile += received.value_profile;
         stats.total_cases += received.total_cases;
         stats.crashes     += received.crashes;
         stats.ucrashes    += received.ucrashes;
         stats.instr_count += received.instr_count;
         stats.timeouts    += received.timeouts;
+        stats.targets     += received.targets;
 
         // Print out updated statistics every second
         if last_time.elapsed() >= Duration::from_millis(500) {
@@ -59,6 +59,10 @@ pub struct Mmu {
 
     /// Bitmap that specifies which pages are dirtied (Page = 4096 bytes)
     pub dirty_bitmap: Vec<u64>,
+
+    /// Addresses at which `write_mem` modified memory that is also executable. Used to detect
+    /// self-modifying code that is written through syscalls instead of JIT-compiled stores
+    pub code_writes: Vec<usize>,
 }
 
 impl Mmu {
@@ -71,6 +75,7 @@ impl Mmu {
             dirty:        Vec::with_capacity(size / 4096 + 1),
             dirty_bitmap: vec![0u64; size / 4096 / 64 + 1],
             dirty_size:   0,
+            code_writes:  Vec::new(),
         }
     }
 
@@ -85,6 +90,7 @@ impl Mmu {
             dirty:        Vec::with_capacity(size / 4096 + 1),
             dirty_bitmap: vec![0u64; size / 4096 / 64 + 1],
             dirty_size:   0,
+            code_writes:  Vec::new(),
         }
     }
 
@@ -104,6 +110,7 @@ impl Mmu {
         // Reset dirty list
         self.dirty.clear();
         self.dirty_size = 0;
+        self.code_writes.clear();
 
         // Reset current base address of heap allocator
         self.alloc_addr = other.alloc_addr;
@@ -135,11 +142,20 @@ impl Mmu {
             return Err(Fault::WriteFault(data.len()));
         }
         let end_addr = addr.checked_add(size).ok_or(Fault::IntegerOverflow)?;
+        let mut code_write = None;
         for i in addr..end_addr {
             Mmu::check_perms(self.permissions[i], Perms::WRITE).ok_or(Fault::WriteFault(i))?;
+            if code_write.is_none() && self.permissions[i] & Perms::EXECUTE != 0 {
+                code_write = Some(i);
+            }
         }
         self.memory[addr..end_addr].copy_from_slice(&data[0..size]);
 
+        // Record writes to executable memory so the emulator can handle self-modifying code
+        if let Some(v) = code_write {
+            self.code_writes.push(v);
+        }
+
         let block_start = addr / 4096;
         let block_end   = (addr + size) / 4096;
         for block in block_start..=block_end {
@@ -556,6 +572,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn writing_executable_memory() {
+        let mut mem = Mmu::new(12 * 1024 * 1024);
+        let seg = elfparser::ProgramHeader {
+            seg_type: 0x1,
+            flags: 0x7,
+            offset: 0x0,
+            vaddr: 0x400000,
+            paddr: 0x400000,
+            filesz: 0x200,
+            memsz: 0x200,
+            align: 0x1000,
+        };
+        let load_data = vec![0x41u8; 0x200];
+        let write_data = vec![0x42u8; 0x20];
+        if mem.load_segment(seg, &load_data).is_none() {
+            panic!("Error during seg load");
+        }
+        assert!(mem.code_writes.is_empty());
+
+        if let Err(e) = mem.write_mem(0x400010, &write_data, 0x20) {
+            panic!("Error occured while writing memory: {:?}", e);
+        }
+        assert_eq!(mem.code_writes, vec![0x400010]);
+    }
+
     #[test]
     fn valid_read_mem() {
         let mut mem = Mmu::new(12 * 1024 * 1024);
@@ -1,12 +1,40 @@
-use crate::config::DICT_FILE;
+use crate::{
+    custom_mutator::{CustomMutator, MAX_INPUT_SIZE},
+    grammar::{Node, MAX_TREE_NODES},
+    CmpLogEntry, Corpus,
+};
 
 use rand_xoshiro::rand_core::RngCore;
 use rand_xoshiro::Xoroshiro64Star;
 use rand_xoshiro::rand_core::SeedableRng;
+use rustc_hash::FxHashSet;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 const MUTATE_SIMPLE: bool = false;
 
-#[derive(Copy, Clone, Debug)]
+/// Maximum number of inputs generated through input-to-state replacement for a single case
+const MAX_I2S_CASES: usize = 4096;
+
+/// Number of mutated cases after which a mutator adds its strategy statistics to the corpus and
+/// reweighs its strategies
+const SCHEDULE_INTERVAL: usize = 10_000;
+
+/// Number of uses after which the success rate observed for a strategy counts as much as the
+/// average success rate of all strategies. Keeps rarely used strategies from being reweighed based
+/// on a handful of lucky or unlucky cases
+const PRIOR_USES: f64 = 5_000.0;
+
+/// Number of attempts at mutating a derivation tree before the case is mutated byte-wise instead
+const MAX_TREE_MUTATIONS: usize = 16;
+
+/// Maximum number of dictionary tokens that are used by the deterministic stage. Each token is
+/// tried at every offset, so an auto-dictionary with thousands of entries would keep the stage
+/// from ever finishing. User-supplied tokens come first in the dictionary, so they are preferred
+/// over the tokens found in the target
+const MAX_DET_TOKENS: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mutation {
     ByteReplace,
     BitFlip,
@@ -16,9 +44,176 @@ pub enum Mutation {
     DupBlock,
     Resize,
     Dictionary,
+    DictInsert,
+    Splice,
+    CrossOver,
+}
+
+impl Mutation {
+    /// All mutation strategies, in the order they are indexed in
+    pub const ALL: [Mutation; 11] = [
+        Mutation::ByteReplace, Mutation::BitFlip, Mutation::MagicNum, Mutation::SimpleArithmetic,
+        Mutation::RemoveBlock, Mutation::DupBlock, Mutation::Resize, Mutation::Dictionary,
+        Mutation::DictInsert, Mutation::Splice, Mutation::CrossOver,
+    ];
+
+    /// Initial weight of the strategy. The cheaper/less destructive strategies are favored, and
+    /// the dictionary based strategies are only used if there is a dictionary
+    fn base_weight(&self, has_dict: bool) -> usize {
+        match self {
+            Mutation::ByteReplace      => 1000,
+            Mutation::BitFlip          => 1000,
+            Mutation::MagicNum         => 200,
+            Mutation::SimpleArithmetic => 500,
+            Mutation::RemoveBlock      => 30,
+            Mutation::DupBlock         => 30,
+            Mutation::Resize           => 10,
+            Mutation::Splice           => 50,
+            Mutation::Dictionary | Mutation::DictInsert | Mutation::CrossOver => {
+                if has_dict { 30 } else { 0 }
+            },
+        }
+    }
+}
+
+/// Number of times a mutation strategy was applied, and how many of the resulting cases were added
+/// to the corpus. Shared between the mutators of all threads through the corpus
+#[derive(Debug, Default)]
+pub struct MutationStats {
+    pub uses: AtomicUsize,
+    pub finds: AtomicUsize,
+}
+
+/// Weigh each mutation strategy by how much more (or less) likely its cases are to be added to the
+/// corpus than the average case (a bandit scheme). The rates are smoothed towards the average, and
+/// each strategy's weight stays within 0.1x-10x of its initial weight so no strategy is ever
+/// starved completely. Weights are indexed like `Mutation::ALL`
+pub fn mutation_weights(stats: &[MutationStats], has_dict: bool) -> Vec<usize> {
+    let uses: Vec<f64> = stats.iter().map(|e| e.uses.load(Ordering::Relaxed) as f64).collect();
+    let finds: Vec<f64> = stats.iter().map(|e| e.finds.load(Ordering::Relaxed) as f64).collect();
+    let total_finds: f64 = finds.iter().sum();
+
+    Mutation::ALL.iter().enumerate().map(|(i, mutation)| {
+        let base = mutation.base_weight(has_dict);
+
+        // Keep the initial weights until a strategy has found something to compare against
+        if base == 0 || total_finds == 0.0 {
+            return base;
+        }
+        let avg_rate = total_finds / uses.iter().sum::<f64>();
+        let rate = (finds[i] + avg_rate * PRIOR_USES) / (uses[i] + PRIOR_USES);
+        core::cmp::max((base as f64 * (rate / avg_rate).clamp(0.1, 10.0)) as usize, 1)
+    }).collect()
+}
+
+/// Values that are likely to trigger edge cases, written to every offset of the input during the
+/// deterministic stage
+const INTERESTING_8: [i8; 9] = [-128, -1, 0, 1, 16, 32, 64, 100, 127];
+const INTERESTING_16: [i16; 10] = [-32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767];
+const INTERESTING_32: [i32; 8] = [-2147483648, -100663046, -32769, 32768, 65535, 65536,
+    100663045, 2147483647];
+
+/// Largest value that is added to or subtracted from each byte during the deterministic stage
+const ARITH_MAX: usize = 35;
+
+/// Stages of the deterministic mutations, in the order they are run in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DetStage {
+    /// Invert each byte. Bytes for which this does not change the executed path are skipped by the
+    /// later stages
+    ByteFlip,
+
+    /// Flip each bit
+    BitFlip,
+
+    /// Add and subtract 1-35 from each byte
+    Arith,
+
+    /// Overwrite each byte/word/dword with interesting values, in both endiannesses
+    Interesting8,
+    Interesting16,
+    Interesting32,
+
+    /// Overwrite the input with each dictionary entry at each offset
+    DictOverwrite,
+
+    /// Insert each dictionary entry at each offset
+    DictInsert,
+
+    /// Every deterministic mutation has been run
+    Done,
+}
+
+impl DetStage {
+    pub const ALL: [DetStage; 9] = [
+        DetStage::ByteFlip, DetStage::BitFlip, DetStage::Arith, DetStage::Interesting8,
+        DetStage::Interesting16, DetStage::Interesting32, DetStage::DictOverwrite,
+        DetStage::DictInsert, DetStage::Done,
+    ];
+}
+
+/// Progress of the deterministic stage of a single corpus entry. This is saved to the output
+/// directory so the stage can be resumed if the fuzzer is restarted on the same inputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetProgress {
+    /// Current stage
+    pub stage: DetStage,
+
+    /// Number of mutations of the current stage that were already run or skipped
+    pub step: usize,
+
+    /// Bytes that changed the executed path when they were inverted (effector map). Bytes that
+    /// did not are skipped by the later stages
+    pub effector: Vec<bool>,
+
+    /// Dictionary tokens used by the dictionary stages. These are fixed when the stages start, so
+    /// tokens that are added to the dictionary afterwards don't shift the steps
+    pub tokens: Vec<Vec<u8>>,
+}
+
+impl DetProgress {
+    /// Start the deterministic stage for an input of `len` bytes
+    pub fn new(len: usize) -> Self {
+        Self { stage: DetStage::ByteFlip, step: 0, effector: vec![true; len], tokens: Vec::new() }
+    }
+
+    /// Record whether the last mutation changed the executed path. This is only relevant for
+    /// the byte flips, which determine the effector map
+    pub fn record_effect(&mut self, effective: bool) {
+        if self.stage == DetStage::ByteFlip && self.step > 0 {
+            self.effector[self.step - 1] = effective;
+        }
+    }
+
+    /// Load progress saved by `save`
+    pub fn load(path: &str) -> Option<Self> {
+        let saved = std::fs::read_to_string(path).ok()?;
+        let mut lines = saved.lines();
+        let mut fields = lines.next()?.split_whitespace();
+        let stage = *DetStage::ALL.get(fields.next()?.parse::<usize>().ok()?)?;
+        let step = fields.next()?.parse::<usize>().ok()?;
+        let effector = fields.next().unwrap_or("").bytes().map(|e| e == b'1').collect();
+        let tokens = lines.map(|line| {
+            (0..line.len()).step_by(2)
+                .map(|i| line.get(i..i + 2).and_then(|e| u8::from_str_radix(e, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+        }).collect::<Option<Vec<Vec<u8>>>>()?;
+        Some(Self { stage, step, effector, tokens })
+    }
+
+    /// Save the progress to `path` as `STAGE STEP EFFECTOR`, with the effector map stored as a
+    /// string of 0s and 1s, followed by one line per dictionary token in hex
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let effector: String = self.effector.iter().map(|&e| if e { '1' } else { '0' }).collect();
+        let mut saved = format!("{} {} {}\n", self.stage as usize, self.step, effector);
+        for token in &self.tokens {
+            saved.extend(token.iter().map(|e| format!("{:02x}", e)));
+            saved.push('\n');
+        }
+        std::fs::write(path, saved)
+    }
 }
 
-#[derive(Debug, Clone)]
 pub struct Mutator {
     /// Fast Rng
     rng: Xoroshiro64Star,
@@ -26,10 +221,30 @@ pub struct Mutator {
     /// Available mutation strategies
     mutation_strats: Vec<Mutation>,
 
+    /// Strategies that were applied to the current case
+    applied: Vec<Mutation>,
+
+    /// Uses and finds of each strategy that were not yet added to the corpus' statistics
+    uses: Vec<usize>,
+    finds: Vec<usize>,
+
+    /// Number of cases mutated since the strategies were last reweighed
+    schedule_counter: usize,
+
     /// Count-down to havoc mode
     havoc_counter: usize,
 
-    dictionary: Option<Vec<String>>,
+    /// Tokens used by the dictionary based strategies, see `Corpus::dictionary`. Tokens that are
+    /// found while lifting code are appended to it as the fuzzer runs
+    dictionary: Vec<Vec<u8>>,
+
+    /// Number of tokens found while lifting that were already added to the dictionary
+    lifted_tokens: usize,
+
+    /// Custom mutator that is used instead of the built-in strategies for `custom_prob` percent of
+    /// the cases
+    custom: Option<Box<dyn CustomMutator>>,
+    custom_prob: u32,
 }
 
 use std::fs::File;
@@ -42,48 +257,183 @@ where P: AsRef<Path>, {
     Ok(io::BufReader::new(file).lines())
 }
 
-pub fn parse_dict(file_name: &str) -> Vec<String> {
-    let mut dict: Vec<String> = Vec::new();
-    if let Ok(lines) = read_lines(file_name) {
-        for line in lines {
-            dict.push(line.unwrap());
+/// Parse a dictionary file. Entries use the AFL/libFuzzer format (`name@level="value"`), and
+/// entries whose level is above `level` are skipped. Lines that don't end in a quoted value are
+/// used verbatim so plain newline-separated dictionaries keep working
+pub fn parse_dict(file_name: &str, level: usize) -> Result<Vec<Vec<u8>>, String> {
+    let lines = read_lines(file_name).map_err(|e| format!("{}: {}", file_name, e))?;
+
+    let mut dict: Vec<Vec<u8>> = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", file_name, e))?;
+        if let Some(token) = parse_dict_line(&line, level)
+                .map_err(|e| format!("{}:{}: {}", file_name, i + 1, e))? {
+            dict.push(token);
         }
     }
-    dict
+    Ok(dict)
 }
 
-impl Mutator {
-    pub fn default() -> Self {
-        // Initialize the individual strategies for the mutation_strats array alongside their
-        // weight. This creates a larger array since weight is created by inserting new
-        // elements into the array, but I believe that this should be much faster than
-        // alternatives
-        let mut mut_strats: Vec<Mutation> = Vec::new();
-        mut_strats.append(&mut (0..1000).map(|_| { Mutation::ByteReplace }).collect());
-        mut_strats.append(&mut (0..1000).map(|_| { Mutation::BitFlip }).collect());
-        mut_strats.append(&mut (0..200).map(|_|  { Mutation::MagicNum }).collect());
-        mut_strats.append(&mut (0..500).map(|_|  { Mutation::SimpleArithmetic }).collect());
-        mut_strats.append(&mut (0..30).map(|_|   { Mutation::RemoveBlock }).collect());
-        mut_strats.append(&mut (0..30).map(|_|   { Mutation::DupBlock }).collect());
-        mut_strats.append(&mut (0..10).map(|_|   { Mutation::Resize }).collect());
-
-        // If the user specified a dictionary to be used while fuzzing, parse it and add dictionary
-        // replacements to the fuzz methods
-        let dict_vec = if let Some(dict) = DICT_FILE.get().unwrap() {
-            mut_strats.append(&mut (0..30).map(|_|   { Mutation::Dictionary }).collect());
-            Some(parse_dict(dict))
-        } else {
-            None
-        };
+/// Parse a single line of a dictionary file. Returns `None` for empty lines, comments, and entries
+/// that are above the requested level
+fn parse_dict_line(raw_line: &str, level: usize) -> Result<Option<Vec<u8>>, String> {
+    let line = raw_line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    // Only lines that end in a quoted value are entries, everything else comes from plain
+    // newline-separated dictionaries and is used byte-for-byte
+    let start = match line.find('"') {
+        Some(start) if start < line.len() - 1 && line.ends_with('"') && (start == 0 ||
+            line.split_once('=').map_or(false, |(_, e)| e.trim_start().starts_with('"'))) => start,
+        _ => return Ok(Some(raw_line.as_bytes().to_vec())),
+    };
+    let (prefix, value) = (&line[..start], &line[start + 1..line.len() - 1]);
+
+    // Everything in front of the value is an optional name and level followed by `=`
+    let prefix = prefix.trim_end();
+    if !prefix.is_empty() {
+        let name = prefix.strip_suffix('=').ok_or("Expected `=` before the token")?.trim_end();
+        if let Some((_, entry_level)) = name.split_once('@') {
+            let entry_level = entry_level.parse::<usize>()
+                .map_err(|_| format!("Invalid level: {}", entry_level))?;
+            if entry_level > level {
+                return Ok(None);
+            }
+        }
+    }
+
+    let token = unescape(value)?;
+    if token.is_empty() {
+        return Err("Empty token".to_string());
+    }
+    Ok(Some(token))
+}
+
+/// Decode the contents of a quoted token, which may contain `\\`, `\"`, and `\xNN` escapes
+pub fn unescape(value: &str) -> Result<Vec<u8>, String> {
+    let mut token = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => match bytes.next() {
+                Some(b'\\') => token.push(b'\\'),
+                Some(b'"')  => token.push(b'"'),
+                Some(b'x')  => {
+                    let hex = [bytes.next(), bytes.next()];
+                    let val = match hex {
+                        [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo]).ok()
+                            .and_then(|e| u8::from_str_radix(e, 16).ok()),
+                        _ => None,
+                    };
+                    token.push(val.ok_or("Invalid `\\x` escape")?);
+                },
+                _ => return Err("Invalid escape sequence".to_string()),
+            },
+            b'"' => return Err("Unescaped `\"` in token".to_string()),
+            _ => token.push(b),
+        }
+    }
+    Ok(token)
+}
 
+/// Convert the operand of a comparison into dictionary tokens. The value is trimmed to the
+/// smallest width it fits in and added in both byte orders, since the input may encode it either
+/// way. Trivial values that the other strategies already produce constantly are skipped
+pub fn constant_tokens(val: i64) -> Vec<Vec<u8>> {
+    if (-1..=1).contains(&val) {
+        return Vec::new();
+    }
+    let size = [1, 2, 4, 8].into_iter().find(|&size| fits_size(val as u64, size)).unwrap();
+    let le = val.to_le_bytes()[..size].to_vec();
+    let be: Vec<u8> = le.iter().rev().copied().collect();
+
+    if size == 1 { vec![le] } else { vec![le, be] }
+}
+
+impl Mutator {
+    /// Create a new mutator. The dictionary based strategies are only enabled if `dictionary`
+    /// contains tokens
+    pub fn new(dictionary: Vec<Vec<u8>>) -> Self {
+        let base_weights: Vec<usize> = Mutation::ALL.iter()
+            .map(|e| e.base_weight(!dictionary.is_empty())).collect();
         Self {
             rng: Xoroshiro64Star::seed_from_u64(0),
-            mutation_strats: mut_strats,
+            mutation_strats: Self::weighted_strats(&base_weights),
+            applied: Vec::new(),
+            uses: vec![0; Mutation::ALL.len()],
+            finds: vec![0; Mutation::ALL.len()],
+            schedule_counter: 0,
             havoc_counter: 0,
-            dictionary: dict_vec,
+            dictionary,
+            lifted_tokens: 0,
+            custom: None,
+            custom_prob: 0,
+        }
+    }
+
+    /// Blend a custom mutator with the built-in strategies. It is used for `prob` percent of the
+    /// mutated cases
+    pub fn set_custom(&mut self, mut custom: Box<dyn CustomMutator>, seed: u32, prob: u32) {
+        custom.init(seed);
+        self.custom = Some(custom);
+        self.custom_prob = prob;
+    }
+
+    /// Custom mutator if one is in use
+    pub fn custom(&mut self) -> Option<&mut (dyn CustomMutator + 'static)> {
+        self.custom.as_deref_mut()
+    }
+
+    /// Let the custom mutator fix up an input right before it is run
+    pub fn post_process(&mut self, input: &mut Vec<u8>) {
+        if let Some(custom) = self.custom.as_mut() {
+            custom.post_process(input);
+            input.truncate(MAX_INPUT_SIZE);
+        }
+    }
+
+    /// Append the tokens that were found while lifting code since the last call to the dictionary
+    fn add_lifted_tokens(&mut self, tokens: &[Vec<u8>]) {
+        for token in &tokens[self.lifted_tokens..] {
+            if !self.dictionary.contains(token) {
+                self.dictionary.push(token.clone());
+            }
+        }
+        self.lifted_tokens = tokens.len();
+    }
+
+    /// Build the array random strategies are drawn from. Each strategy is inserted as many times
+    /// as its weight, which trades memory for picking a strategy with a single index
+    fn weighted_strats(weights: &[usize]) -> Vec<Mutation> {
+        let mut mut_strats: Vec<Mutation> = Vec::new();
+        for (mutation, weight) in Mutation::ALL.iter().zip(weights) {
+            mut_strats.append(&mut (0..*weight).map(|_| { *mutation }).collect());
+        }
+        mut_strats
+    }
+
+    /// Credit the strategies that were applied to the last case with finding a new corpus entry
+    pub fn credit(&mut self) {
+        for mutation in &self.applied {
+            self.finds[*mutation as usize] += 1;
         }
     }
 
+    /// Add the uses and finds of the strategies to the statistics shared through the corpus, and
+    /// reweigh the strategies based on them. Tokens that were lifted in the meantime are picked up
+    /// here as well
+    fn reschedule(&mut self, corpus: &Corpus) {
+        self.add_lifted_tokens(&corpus.lifted_tokens.read());
+        for (i, stats) in corpus.mutation_stats.iter().enumerate() {
+            stats.uses.fetch_add(std::mem::take(&mut self.uses[i]), Ordering::Relaxed);
+            stats.finds.fetch_add(std::mem::take(&mut self.finds[i]), Ordering::Relaxed);
+        }
+        let weights = mutation_weights(&corpus.mutation_stats, !self.dictionary.is_empty());
+        self.mutation_strats = Self::weighted_strats(&weights);
+    }
+
     /// Return 2 random 32-bit unsigned integers
     fn get2_rand(&mut self) -> (usize, usize) {
         let tmp = self.rng.next_u64();
@@ -273,11 +623,12 @@ impl Mutator {
 
     /// Replace some of the input bytes with a provided dictionary entry
     fn dict_replace(&mut self, input: &mut Vec<u8>) -> Result<(), ()> {
-        let dict_idx = self.rng.next_u32() as usize % self.dictionary.as_ref().unwrap().len();
-        let entry = self.dictionary.as_ref().unwrap()[dict_idx].as_bytes();
+        if self.dictionary.is_empty() { return Err(()); }
+        let (r1, r2) = self.get2_rand();
+        let entry = &self.dictionary[r1 % self.dictionary.len()];
 
         if input.len() <= entry.len() { return Err(()); }
-        let input_idx = self.rng.next_u64() as usize % (input.len() - entry.len());
+        let input_idx = r2 % (input.len() - entry.len());
         for (i, j) in (input_idx..(input_idx + entry.len())).enumerate() {
             input[j] = entry[i];
         }
@@ -285,6 +636,234 @@ impl Mutator {
         Ok(())
     }
 
+    /// Insert a provided dictionary entry at a random offset of the input
+    fn dict_insert(&mut self, input: &mut Vec<u8>) -> Result<(), ()> {
+        if self.dictionary.is_empty() { return Err(()); }
+        let (r1, r2) = self.get2_rand();
+        let entry = &self.dictionary[r1 % self.dictionary.len()];
+
+        if entry.is_empty() { return Err(()); }
+        let input_idx = r2 % (input.len() + 1);
+        input.splice(input_idx..input_idx, entry.iter().copied());
+
+        Ok(())
+    }
+
+    /// Splice the input with a different corpus entry. The range in which both inputs diverge is
+    /// located, and the input is cut off at a random point within it and completed with the other
+    /// entry's data from that point on
+    fn splice(&mut self, input: &mut Vec<u8>, corpus: &Corpus) -> Result<(), ()> {
+        let inputs = corpus.inputs.read();
+        if inputs.len() < 2 { return Err(()); }
+        let other = &inputs[self.rng.next_u32() as usize % inputs.len()].data;
+
+        // Locate the first and last differing bytes, splitting outside of this range would just
+        // reproduce one of the inputs
+        let len = core::cmp::min(input.len(), other.len());
+        let first = input[..len].iter().zip(&other[..len]).position(|(a, b)| a != b).ok_or(())?;
+        let last = len - 1 - input[..len].iter().zip(&other[..len]).rev()
+            .position(|(a, b)| a != b).unwrap();
+        if last - first < 2 { return Err(()); }
+
+        let split = first + 1 + self.rng.next_u32() as usize % (last - first - 1);
+        input.truncate(split);
+        input.extend_from_slice(&other[split..]);
+        Ok(())
+    }
+
+    /// Cross the input over with a different corpus entry at a dictionary token that occurs in
+    /// both of them. The input is cut off at the token and completed with the other entry's data
+    /// starting at the same token, so both halves stay aligned on a boundary of the input format
+    /// (eg. a keyword) instead of an arbitrary offset
+    fn cross_over(&mut self, input: &mut Vec<u8>, corpus: &Corpus) -> Result<(), ()> {
+        let inputs = corpus.inputs.read();
+        if inputs.len() < 2 { return Err(()); }
+        let other = &inputs[self.rng.next_u32() as usize % inputs.len()].data;
+
+        // Try the tokens starting at a random one until one is found that occurs in both inputs
+        let num_tokens = self.dictionary.len();
+        let start = self.rng.next_u32() as usize;
+        let (r1, r2) = self.get2_rand();
+        for i in 0..num_tokens {
+            let token = &self.dictionary[(start + i) % num_tokens];
+            if token.is_empty() { continue; }
+
+            let find = |data: &[u8]| -> Vec<usize> {
+                data.windows(token.len()).enumerate()
+                    .filter(|(_, e)| *e == &token[..]).map(|(i, _)| i).collect()
+            };
+            let (ours, theirs) = (find(input), find(other));
+            if ours.is_empty() || theirs.is_empty() { continue; }
+
+            // Cutting the input late and the other entry early can grow the case past the size
+            // limit
+            let (cut, start) = (ours[r1 % ours.len()], theirs[r2 % theirs.len()]);
+            if cut + other.len() - start > MAX_INPUT_SIZE { return Err(()); }
+
+            input.truncate(cut);
+            input.extend_from_slice(&other[start..]);
+            return Ok(());
+        }
+        Err(())
+    }
+
+    /// Generate the next case of the deterministic stage of `input` and advance `progress` past
+    /// it. Mutations that only touch bytes without an effect according to the effector map, and
+    /// mutations that would not change the input are skipped. Returns None once every stage has
+    /// been run
+    pub fn deterministic(&self, input: &[u8], progress: &mut DetProgress) -> Option<Vec<u8>> {
+        let len = input.len();
+
+        loop {
+            let num_tokens = progress.tokens.len();
+            let stage_len = match progress.stage {
+                DetStage::ByteFlip      => len,
+                DetStage::BitFlip       => len * 8,
+                DetStage::Arith         => len * ARITH_MAX * 2,
+                DetStage::Interesting8  => len * INTERESTING_8.len(),
+                DetStage::Interesting16 => len.saturating_sub(1) * INTERESTING_16.len() * 2,
+                DetStage::Interesting32 => len.saturating_sub(3) * INTERESTING_32.len() * 2,
+                DetStage::DictOverwrite => len * num_tokens,
+                DetStage::DictInsert    => (len + 1) * num_tokens,
+                DetStage::Done          => return None,
+            };
+            if progress.step >= stage_len {
+                progress.stage = DetStage::ALL[progress.stage as usize + 1];
+                progress.step = 0;
+                if progress.stage == DetStage::DictOverwrite {
+                    progress.tokens = self.dictionary.iter().filter(|e| !e.is_empty())
+                        .take(MAX_DET_TOKENS).cloned().collect();
+                }
+                continue;
+            }
+            let step = progress.step;
+            progress.step += 1;
+
+            // Offset and size of the bytes that are overwritten alongside the new bytes
+            let (offset, size, bytes): (usize, usize, Vec<u8>) = match progress.stage {
+                DetStage::ByteFlip => (step, 1, vec![!input[step]]),
+                DetStage::BitFlip => (step / 8, 1, vec![input[step / 8] ^ (1 << (step % 8))]),
+                DetStage::Arith => {
+                    let delta = (step % (ARITH_MAX * 2) / 2 + 1) as u8;
+                    let byte = input[step / (ARITH_MAX * 2)];
+                    let byte = if step & 1 == 0 {
+                        byte.wrapping_add(delta)
+                    } else {
+                        byte.wrapping_sub(delta)
+                    };
+                    (step / (ARITH_MAX * 2), 1, vec![byte])
+                },
+                DetStage::Interesting8 => {
+                    let value = INTERESTING_8[step % INTERESTING_8.len()];
+                    (step / INTERESTING_8.len(), 1, value.to_le_bytes().to_vec())
+                },
+                DetStage::Interesting16 => {
+                    let value = INTERESTING_16[step / 2 % INTERESTING_16.len()];
+                    let bytes = if step & 1 == 0 {
+                        value.to_le_bytes()
+                    } else {
+                        value.to_be_bytes()
+                    };
+                    (step / 2 / INTERESTING_16.len(), 2, bytes.to_vec())
+                },
+                DetStage::Interesting32 => {
+                    let value = INTERESTING_32[step / 2 % INTERESTING_32.len()];
+                    let bytes = if step & 1 == 0 {
+                        value.to_le_bytes()
+                    } else {
+                        value.to_be_bytes()
+                    };
+                    (step / 2 / INTERESTING_32.len(), 4, bytes.to_vec())
+                },
+                DetStage::DictOverwrite => {
+                    let entry = &progress.tokens[step % num_tokens];
+                    if step / num_tokens + entry.len() > len { continue; }
+                    (step / num_tokens, entry.len(), entry.clone())
+                },
+                DetStage::DictInsert => {
+                    (step / num_tokens, 0, progress.tokens[step % num_tokens].clone())
+                },
+                DetStage::Done => unreachable!(),
+            };
+
+            // Skip mutations that only overwrite bytes without an effect, or that don't change the
+            // input at all. Insertions can't be judged by the effector map so they are always run
+            if size != 0 && (!progress.effector[offset..offset + size].contains(&true) ||
+                    input[offset..offset + size] == bytes[..]) {
+                continue;
+            }
+
+            let mut case = input[..offset].to_vec();
+            case.extend_from_slice(&bytes);
+            case.extend_from_slice(&input[offset + size..]);
+            return Some(case);
+        }
+    }
+
+    /// Input-to-state replacement (RedQueen). Search the input for the operands of each logged
+    /// comparison, either as-is, byte-swapped, or off by one, and generate a new input for every
+    /// match in which the operand is replaced with the value it was compared against. This lets
+    /// the fuzzer solve magic values and checksums that are directly read from the input
+    pub fn input_to_state(&self, input: &[u8], cmp_log: &[CmpLogEntry]) -> Vec<Vec<u8>> {
+        let mut cases: FxHashSet<Vec<u8>> = FxHashSet::default();
+        let mut seen: FxHashSet<(u64, u64)> = FxHashSet::default();
+
+        for entry in cmp_log {
+            // Operands of compares on buffers (eg. memcmp) may contain unrelated bytes past their
+            // size
+            let mask = if entry.size >= 8 { u64::MAX } else { (1 << (entry.size * 8)) - 1 };
+            let (op1, op2) = (entry.op1 & mask, entry.op2 & mask);
+
+            // Comparisons that already succeed or were already handled can be skipped
+            if op1 == op2 || !seen.insert((op1, op2)) {
+                continue;
+            }
+
+            for (pattern, repl) in [(op1, op2), (op2, op1)] {
+                // The target may have adjusted the value read from the input by one before
+                // comparing it, so the same adjustment is applied to the replacement
+                for delta in [0u64, 1, u64::MAX] {
+                    let pattern = pattern.wrapping_sub(delta);
+                    let repl    = repl.wrapping_sub(delta);
+
+                    // Integer compares can be narrower than the registers they are performed on,
+                    // while buffers are only replaced as a whole
+                    let sizes = (1..=entry.size).filter(|&e| {
+                        e.is_power_of_two() || e == entry.size
+                    });
+                    for size in sizes {
+                        if !fits_size(pattern, size) || !fits_size(repl, size) {
+                            continue;
+                        }
+
+                        for swap in [false, true] {
+                            if swap && size == 1 { continue; }
+
+                            let mut pattern_bytes = pattern.to_le_bytes()[..size].to_vec();
+                            let mut repl_bytes    = repl.to_le_bytes()[..size].to_vec();
+                            if swap {
+                                pattern_bytes.reverse();
+                                repl_bytes.reverse();
+                            }
+
+                            for (i, _) in input.windows(size).enumerate()
+                                    .filter(|(_, e)| *e == pattern_bytes) {
+                                let mut case = input.to_vec();
+                                case[i..i + size].copy_from_slice(&repl_bytes);
+                                cases.insert(case);
+
+                                if cases.len() >= MAX_I2S_CASES {
+                                    return cases.into_iter().collect();
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        cases.into_iter().collect()
+    }
+
     /// Chose a random mutation strategy
     fn chose_mut(&mut self) -> Mutation {
         let tmp_rand = self.rng.next_u32() as usize % self.mutation_strats.len();
@@ -293,7 +872,7 @@ impl Mutator {
 
     /// Apply various implemented mutation strategies. Every 100 cases, use 'havoc-mode' which
     /// applies multiple strategies at the same time
-    fn mutate_complex(&mut self, input: &mut Vec<u8>) {
+    fn mutate_complex(&mut self, input: &mut Vec<u8>, corpus: &Corpus) {
         let mut muts = Vec::new();
         self.havoc_counter += 1;
 
@@ -308,6 +887,14 @@ impl Mutator {
             muts.push(self.chose_mut());
         }
 
+        // Strategies are credited with the cases they are applied to, and periodically reweighed
+        self.schedule_counter += 1;
+        if self.schedule_counter == SCHEDULE_INTERVAL {
+            self.schedule_counter = 0;
+            self.reschedule(corpus);
+        }
+        self.applied.clear();
+
         for mutation in &mut muts {
             'inner: loop {
                 let res = match mutation {
@@ -319,11 +906,16 @@ impl Mutator {
                     Mutation::DupBlock         => self.duplicate_block(input),
                     Mutation::Resize           => self.resize(input),
                     Mutation::Dictionary       => self.dict_replace(input),
+                    Mutation::DictInsert       => self.dict_insert(input),
+                    Mutation::Splice           => self.splice(input, corpus),
+                    Mutation::CrossOver        => self.cross_over(input, corpus),
                 };
 
                 // If the chosen strategy failed, chose a different mutation and rerun the
                 // mutator, otherwise break out of the inner loop to keep the mutation result
                 if res.is_ok() { 
+                    self.applied.push(*mutation);
+                    self.uses[*mutation as usize] += 1;
                     break 'inner; 
                 } else {
                     *mutation = self.chose_mut();
@@ -342,13 +934,336 @@ impl Mutator {
         }
     }
 
-    /// Start the mutation process of an input
-    pub fn mutate(&mut self, input: &mut Vec<u8>) {
-        if MUTATE_SIMPLE {
+    /// Mutate the derivation tree of an input generated from the grammar using subtree
+    /// replacement, subtree splicing with a different corpus entry, or recursive expansion.
+    /// Returns the mutated tree alongside the input it serializes to, or None if none of
+    /// `MAX_TREE_MUTATIONS` attempts produced a tree within the size limits
+    pub fn mutate_tree(&mut self, tree: &Node, corpus: &Corpus) -> Option<(Node, Vec<u8>)> {
+        let grammar = corpus.grammar.as_ref().unwrap();
+
+        // None of the built-in strategies are credited with this case
+        self.applied.clear();
+
+        for _ in 0..MAX_TREE_MUTATIONS {
+            let mut mutated = tree.clone();
+            let res = match self.rng.next_u32() % 3 {
+                0 => {
+                    grammar.replace_subtree(&mut mutated, &mut self.rng);
+                    Ok(())
+                },
+                1 => {
+                    let inputs = corpus.inputs.read();
+                    let other = &inputs[self.rng.next_u32() as usize % inputs.len()];
+                    match &other.tree {
+                        Some(other) => grammar.splice_subtree(&mut mutated, other, &mut self.rng),
+                        None => Err(()),
+                    }
+                },
+                _ => grammar.recursive_expansion(&mut mutated, &mut self.rng),
+            };
+
+            // Retry with a different mutation if this one failed or grew the tree too large
+            if res.is_err() || mutated.size() > MAX_TREE_NODES {
+                continue;
+            }
+            let data = grammar.unparse(&mutated);
+            if data.len() <= MAX_INPUT_SIZE {
+                return Some((mutated, data));
+            }
+        }
+        None
+    }
+
+    /// Start the mutation process of an input. Entries of the corpus are used as the second input
+    /// of splicing strategies
+    pub fn mutate(&mut self, input: &mut Vec<u8>, corpus: &Corpus) {
+        if self.custom.is_some() && self.rng.next_u32() % 100 < self.custom_prob {
+            let inputs = corpus.inputs.read();
+            let other = &inputs[self.rng.next_u32() as usize % inputs.len()].data;
+            self.custom.as_mut().unwrap().mutate(input, other, MAX_INPUT_SIZE);
+
+            // None of the built-in strategies are credited with this case
+            self.applied.clear();
+        } else if MUTATE_SIMPLE {
             self.mutate_simple(input);
         } else {
-            self.mutate_complex(input);
+            self.mutate_complex(input, corpus);
         }
     }
 }
 
+/// Check if a comparison operand can be represented using `size` bytes, either zero- or
+/// sign-extended
+fn fits_size(val: u64, size: usize) -> bool {
+    size == 8 || val >> (size * 8) == 0 || (val as i64) >> (size * 8 - 1) == -1
+}
+
+/// Unit tests for parsing dictionary files and the mutation strategies
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::{COV_METHOD, CovMethod, DETERMINISTIC, VALUE_PROFILE}, Input};
+
+    /// Build a small corpus holding `inputs`
+    fn test_corpus(inputs: &[&[u8]]) -> Corpus {
+        COV_METHOD.get_or_init(|| CovMethod::CallStack);
+        VALUE_PROFILE.get_or_init(|| false);
+        DETERMINISTIC.get_or_init(|| false);
+
+        let corpus = Corpus::new(1 << 10);
+        corpus.inputs.write().extend(inputs.iter().map(|e| Input::new(e.to_vec(), Some(1))));
+        corpus
+    }
+
+    #[test]
+    fn dict_afl_entries() {
+        assert_eq!(parse_dict_line("kw=\"\\x89PNG\"", 0), Ok(Some(b"\x89PNG".to_vec())));
+        assert_eq!(parse_dict_line("  \"a\\\"b\\\\c\"  ", 0), Ok(Some(b"a\"b\\c".to_vec())));
+        assert_eq!(parse_dict_line("header_png = \"IHDR\"", 0), Ok(Some(b"IHDR".to_vec())));
+    }
+
+    #[test]
+    fn dict_skipped_lines() {
+        assert_eq!(parse_dict_line("", 0), Ok(None));
+        assert_eq!(parse_dict_line("# \"comment\"", 0), Ok(None));
+        assert_eq!(parse_dict_line("kw@1=\"deep\"", 0), Ok(None));
+        assert_eq!(parse_dict_line("kw@1=\"deep\"", 1), Ok(Some(b"deep".to_vec())));
+    }
+
+    #[test]
+    fn dict_plain_lines() {
+        assert_eq!(parse_dict_line("GET /index", 0), Ok(Some(b"GET /index".to_vec())));
+        assert_eq!(parse_dict_line("say \"hi\" now", 0), Ok(Some(b"say \"hi\" now".to_vec())));
+        assert_eq!(parse_dict_line("  GET / ", 0), Ok(Some(b"  GET / ".to_vec())));
+        assert_eq!(parse_dict_line("kw \"abc\"", 0), Ok(Some(b"kw \"abc\"".to_vec())));
+        assert_eq!(parse_dict_line("kw=\"abc", 0), Ok(Some(b"kw=\"abc".to_vec())));
+        assert_eq!(parse_dict_line("a\\x41", 0), Ok(Some(b"a\\x41".to_vec())));
+    }
+
+    #[test]
+    fn dict_invalid_entries() {
+        assert!(parse_dict_line("kw=\"\\x8\"", 0).is_err());
+        assert!(parse_dict_line("kw=\"\\q\"", 0).is_err());
+        assert!(parse_dict_line("kw=\"a\"b\"", 0).is_err());
+        assert!(parse_dict_line("kw@x=\"abc\"", 0).is_err());
+        assert!(parse_dict_line("kw=\"", 0).is_err());
+        assert!(parse_dict_line("kw=\"\"", 0).is_err());
+    }
+
+    #[test]
+    fn splice_inputs() {
+        let ours   = [b'a'; 64];
+        let theirs = [b'b'; 48];
+        let corpus = test_corpus(&[&ours, &theirs]);
+        let mut mutator = Mutator::new(Vec::new());
+
+        let mut spliced = 0;
+        for _ in 0..100 {
+            let mut input = ours.to_vec();
+            if mutator.splice(&mut input, &corpus).is_err() {
+                assert_eq!(input, ours);
+                continue;
+            }
+            spliced += 1;
+
+            // The input is only cut within the range both inputs differ in, and completed with
+            // the other entry's data
+            assert_eq!(input.len(), theirs.len());
+            let split = input.iter().position(|&e| e == b'b').unwrap();
+            assert!(split > 0 && split < theirs.len() - 1);
+            assert!(input[..split].iter().all(|&e| e == b'a'));
+            assert!(input[split..].iter().all(|&e| e == b'b'));
+        }
+        assert!(spliced > 0);
+    }
+
+    #[test]
+    fn cross_over_inputs() {
+        let ours   = b"aaaaKEYaaaa";
+        let theirs = b"bbKEYbbbbbbbbbb";
+        let corpus = test_corpus(&[ours, theirs]);
+        let mut mutator = Mutator::new(vec![b"KEY".to_vec()]);
+
+        let mut crossed = 0;
+        for _ in 0..100 {
+            let mut input = ours.to_vec();
+            if mutator.cross_over(&mut input, &corpus).is_err() || input == ours {
+                continue;
+            }
+            crossed += 1;
+
+            // Both halves are joined at the token
+            assert_eq!(input, b"aaaaKEYbbbbbbbbbb");
+        }
+        assert!(crossed > 0);
+
+        // Cases that would grow past the size limit are rejected
+        let mut large = b"KEY".to_vec();
+        large.resize(MAX_INPUT_SIZE, b'b');
+        let corpus = test_corpus(&[ours, &large]);
+        for _ in 0..100 {
+            let mut input = ours.to_vec();
+            if mutator.cross_over(&mut input, &corpus).is_ok() {
+                assert!(input.len() <= MAX_INPUT_SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn mutation_weights_follow_stats() {
+        let stats: Vec<MutationStats> = Mutation::ALL.iter().map(|_| MutationStats::default())
+            .collect();
+        let base = |has_dict| -> Vec<usize> {
+            Mutation::ALL.iter().map(|e| e.base_weight(has_dict)).collect()
+        };
+
+        // Without any finds the initial weights are kept
+        assert_eq!(mutation_weights(&stats, true), base(true));
+        assert_eq!(mutation_weights(&stats, false), base(false));
+
+        // Strategies that find more than the average are favored, the others are penalized but
+        // never drop to zero
+        for e in &stats {
+            e.uses.store(100_000, Ordering::Relaxed);
+        }
+        stats[Mutation::BitFlip as usize].finds.store(1_000, Ordering::Relaxed);
+        stats[Mutation::ByteReplace as usize].finds.store(10, Ordering::Relaxed);
+        let weights = mutation_weights(&stats, true);
+        assert!(weights[Mutation::BitFlip as usize] > base(true)[Mutation::BitFlip as usize]);
+        assert!(weights[Mutation::ByteReplace as usize] <
+            base(true)[Mutation::ByteReplace as usize]);
+        assert!(weights.iter().all(|&e| e > 0));
+
+        // Dictionary strategies are never used without a dictionary
+        let weights = mutation_weights(&stats, false);
+        for mutation in [Mutation::Dictionary, Mutation::DictInsert, Mutation::CrossOver] {
+            assert_eq!(weights[mutation as usize], 0);
+        }
+        assert!(!Mutator::weighted_strats(&weights).contains(&Mutation::Dictionary));
+    }
+
+    #[test]
+    fn deterministic_stages() {
+        let mut mutator = Mutator::new(vec![b"AB".to_vec()]);
+        let input = b"xyz";
+        let mut progress = DetProgress::new(input.len());
+
+        // Byte flips come first and fill in the effector map
+        for (i, effective) in [true, false, true].into_iter().enumerate() {
+            let case = mutator.deterministic(input, &mut progress).unwrap();
+            let mut expected = input.to_vec();
+            expected[i] = !expected[i];
+            assert_eq!(case, expected);
+            progress.record_effect(effective);
+        }
+        assert_eq!(progress.effector, [true, false, true]);
+
+        // Later stages skip the bytes without an effect, insertions are always run
+        let mut cases = Vec::new();
+        while let Some(case) = mutator.deterministic(input, &mut progress) {
+            // Tokens added once the dictionary stages started are not used
+            if progress.stage == DetStage::DictOverwrite && mutator.dictionary.len() == 1 {
+                mutator.dictionary.push(b"CD".to_vec());
+            }
+            if matches!(progress.stage, DetStage::BitFlip | DetStage::Arith |
+                    DetStage::Interesting8) {
+                assert_eq!(case[1], b'y');
+            }
+            cases.push(case);
+        }
+        assert_eq!(progress.stage, DetStage::Done);
+        assert_eq!(progress.tokens, [b"AB".to_vec()]);
+        assert!(cases.iter().all(|e| !e.windows(2).any(|e| e == b"CD")));
+        assert!(cases.contains(&b"ABz".to_vec()));
+        assert!(cases.contains(&b"ABxyz".to_vec()));
+        assert!(cases.contains(&b"xyzAB".to_vec()));
+        assert!(cases.iter().all(|e| e.as_slice() != input));
+        assert_eq!(mutator.deterministic(input, &mut progress), None);
+    }
+
+    #[test]
+    fn det_progress_round_trip() {
+        let path = std::env::temp_dir().join(format!("det_progress_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let progress = DetProgress {
+            stage:    DetStage::Interesting16,
+            step:     42,
+            effector: vec![true, false, false, true],
+            tokens:   vec![b"AB".to_vec(), vec![0x00, 0xff]],
+        };
+        progress.save(path).unwrap();
+        assert_eq!(DetProgress::load(path), Some(progress));
+
+        // Inputs without any bytes store an empty effector map
+        let progress = DetProgress::new(0);
+        progress.save(path).unwrap();
+        assert_eq!(DetProgress::load(path), Some(progress));
+
+        std::fs::write(path, "9 0 1\n").unwrap();
+        assert_eq!(DetProgress::load(path), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn constant_token_widths() {
+        // Trivial values are skipped
+        assert!(constant_tokens(0).is_empty());
+        assert!(constant_tokens(-1).is_empty());
+        assert!(constant_tokens(1).is_empty());
+
+        // Values are trimmed to the smallest width they fit in, sign-extended or not
+        assert_eq!(constant_tokens(0x41), [vec![0x41u8]]);
+        assert_eq!(constant_tokens(-2), [vec![0xfeu8]]);
+        assert_eq!(constant_tokens(0x1234), [vec![0x34u8, 0x12], vec![0x12, 0x34]]);
+        assert_eq!(constant_tokens(-0x1000), [vec![0x00u8, 0xf0], vec![0xf0, 0x00]]);
+        assert_eq!(constant_tokens(0x89504e47), [b"\x47\x4e\x50\x89".to_vec(),
+            b"\x89PNG".to_vec()]);
+        assert_eq!(constant_tokens(0x1122334455667788)[1],
+            [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+    }
+
+    /// Appends a marker to each case it mutates, and grows cases past the size limit when fixing
+    /// them up
+    struct Marker;
+
+    impl CustomMutator for Marker {
+        fn init(&mut self, _seed: u32) {}
+
+        fn mutate(&mut self, input: &mut Vec<u8>, _add_input: &[u8], _max_size: usize) {
+            input.extend_from_slice(b"MARK");
+        }
+
+        fn post_process(&mut self, input: &mut Vec<u8>) {
+            input.resize(MAX_INPUT_SIZE + 1, 0);
+        }
+    }
+
+    #[test]
+    fn custom_mutator_blend() {
+        let corpus = test_corpus(&[b"seed"]);
+
+        // The custom mutator is only used for its share of the cases
+        for (prob, expected) in [(0, 0), (100, 1000)] {
+            let mut mutator = Mutator::new(Vec::new());
+            mutator.set_custom(Box::new(Marker), 0, prob);
+
+            let mut custom_cases = 0;
+            for _ in 0..1000 {
+                let mut input = b"seed".to_vec();
+                mutator.mutate(&mut input, &corpus);
+                if input == b"seedMARK" {
+                    custom_cases += 1;
+                    assert!(mutator.applied.is_empty());
+                }
+            }
+            assert_eq!(custom_cases, expected);
+        }
+
+        let mut mutator = Mutator::new(Vec::new());
+        mutator.set_custom(Box::new(Marker), 0, 50);
+        let mut input = b"seed".to_vec();
+        mutator.post_process(&mut input);
+        assert_eq!(input.len(), MAX_INPUT_SIZE);
+    }
+}
@@ -1,11 +1,13 @@
 use crate::{
-    config::{COV_METHOD, NO_PERM_CHECKS, SNAPSHOT_ADDR, NUM_THREADS, DEBUG_PRINT, CMP_COV, 
-        RUN_CASES, SEND_REMOTE},
+    config::{COV_METHOD, NO_PERM_CHECKS, SNAPSHOTS, NUM_THREADS, DEBUG_PRINT, CMP_COV, 
+        CMP_LOG, VALUE_PROFILE, RUN_CASES, SEND_REMOTE, MAP_SIZE, MAP_STATS},
+    mutator::{Mutation, mutation_weights},
     Statistics, Corpus,
 };
 
 use core::fmt;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 use std::collections::HashMap;
 
@@ -69,6 +71,16 @@ pub fn log(color: LogType, msg: &str) {
     }
 }
 
+/// Percentage of the coverage map's entries that are in use, and the number of distinct edges or
+/// blocks that collided with another one alongside the percentage of all keys that they make up
+fn map_stats(corpus: &Arc<Corpus>) -> (f64, usize, f64) {
+    let keys = corpus.map_keys.load(Ordering::Relaxed);
+    let collisions = corpus.map_collisions.read().len();
+    let density = keys as f64 / *MAP_SIZE.get().unwrap() as f64 * 100.0;
+    let rate = collisions as f64 / (keys + collisions).max(1) as f64 * 100.0;
+    (density, collisions, rate)
+}
+
 /// Print out statistics in a nicely formated static screen
 fn pretty_stats(term: &Term, stats: &Statistics, elapsed_time: f64, timeout: u64, corpus: 
                 &Arc<Corpus>, last_cov: f64) {
@@ -116,9 +128,11 @@ fn pretty_stats(term: &Term, stats: &Statistics, elapsed_time: f64, timeout: u64
     term.move_cursor_to(54, 7).unwrap();
     term.write_line(&format!("   Timeouts: \t{}", stats.timeouts.to_formatted_string(&Locale::en)))
         .unwrap();
+    term.move_cursor_to(54, 8).unwrap();
+    term.write_line(&format!("   Targets reached: {}", stats.targets)).unwrap();
 
     // Performance numbers
-    term.move_cursor_down(2).unwrap();
+    term.move_cursor_down(1).unwrap();
     term.write_line(
         &format!("\t{}\n\t   Fuzz cases per second: {:12}\n\t   \
                 Instrs per second [mil]: {:12}",
@@ -142,6 +156,10 @@ fn pretty_stats(term: &Term, stats: &Statistics, elapsed_time: f64, timeout: u64
     term.move_cursor_to(54, 12).unwrap();
     term.write_line(&format!("   CmpCov: {}", stats.cmpcov)).unwrap();
     term.move_cursor_to(54, 13).unwrap();
+    term.write_line(&format!("   Cmp progress: {}", stats.cmp_progress)).unwrap();
+    term.move_cursor_to(54, 14).unwrap();
+    term.write_line(&format!("   Value profile: {}", stats.value_profile)).unwrap();
+    term.move_cursor_to(54, 15).unwrap();
     term.write_line(&format!("   Time since last cov: {:02}:{:02}:{:02}", 
                     cov_hr, cov_min, cov_sec)).unwrap();
 
@@ -153,34 +171,62 @@ fn pretty_stats(term: &Term, stats: &Statistics, elapsed_time: f64, timeout: u64
     // Config information
     term.move_cursor_down(1).unwrap();
     term.write_line(
-        &format!("\t{}\n\t   Num Threads: {}\n\t   Coverage type: {:?}\n\t   \
-        Snapshots enabled: {}\n\t   ASAN: {}\n\t   Timeout: {}\n\t   CmpCov: {}\n\t   Max runs: {}",
+        &format!("\t{}\n\t   Num Threads: {}\n\t   Coverage type: {:?}\n\t   Map size: {}\n\t   \
+        Snapshots enabled: {}\n\t   ASAN: {}\n\t   Timeout: {}\n\t   CmpCov: {}\n\t   \
+        CmpLog: {}\n\t   Value profile: {}\n\t   Max runs: {}",
         Blue("Config"), 
         NUM_THREADS.get().unwrap(),
         COV_METHOD.get().unwrap(),
-        SNAPSHOT_ADDR.get().unwrap().is_some(),
+        MAP_SIZE.get().unwrap().to_formatted_string(&Locale::en),
+        !SNAPSHOTS.get().unwrap().is_empty(),
         !NO_PERM_CHECKS.get().unwrap(),
         timeout.to_formatted_string(&Locale::en),
         CMP_COV.get().unwrap(),
+        CMP_LOG.get().unwrap(),
+        VALUE_PROFILE.get().unwrap(),
         run_cases,
     )).unwrap();
 
+    // Current weights of the mutation strategies that are in use, two per line
+    let has_dict = !corpus.dictionary.is_empty() || !corpus.lifted_tokens.read().is_empty();
+    let weights = mutation_weights(&corpus.mutation_stats, has_dict);
+    let total: usize = weights.iter().sum();
+    let entries: Vec<String> = Mutation::ALL.iter().zip(&weights).filter(|(_, &w)| w != 0)
+        .map(|(m, &w)| format!("{:?}: {:.1}%", m, w as f64 / total as f64 * 100.0))
+        .map(|e| format!("{:<26}", e)).collect();
+    term.move_cursor_down(1).unwrap();
+    term.write_line(&format!("\t{}", Blue("Mutation weights"))).unwrap();
+    for line in entries.chunks(2) {
+        term.write_line(&format!("\t   {}", line.concat())).unwrap();
+    }
+
     // Corpus stats
-    term.move_cursor_to(54, 15).unwrap();
+    term.move_cursor_to(54, 17).unwrap();
     term.write_line(&format!("{}", Blue("Corpus"))).unwrap();
-    term.move_cursor_to(54, 16).unwrap();
+    term.move_cursor_to(54, 18).unwrap();
     term.write_line(&format!("   Num Entries: {}", corpus.inputs.read().len())).unwrap();
-    term.move_cursor_to(54, 17).unwrap();
+    term.move_cursor_to(54, 19).unwrap();
     term.write_line(&format!("   Avg Instrs per case: {}", 
                              (stats.instr_count / stats.total_cases as u64)
                              )).unwrap();
 
+    // Coverage map usage, only measured with map stats
+    if *MAP_STATS.get().unwrap() {
+        let (density, collisions, rate) = map_stats(corpus);
+        term.move_cursor_to(54, 21).unwrap();
+        term.write_line(&format!("{}", Blue("Coverage map"))).unwrap();
+        term.move_cursor_to(54, 22).unwrap();
+        term.write_line(&format!("   Map density: {:.2}%", density)).unwrap();
+        term.move_cursor_to(54, 23).unwrap();
+        term.write_line(&format!("   Collisions: {} ({:.2}%)", collisions, rate)).unwrap();
+    }
+
     // Flush buffer and write to terminal
     term.flush().unwrap();
 }
 
 /// Simple debug view of statistics
-fn basic_stats(stats: &Statistics, elapsed_time: f64) {
+fn basic_stats(stats: &Statistics, elapsed_time: f64, corpus: &Arc<Corpus>) {
     println!(
         "[{:8.2}] fuzz cases: {:12} : fcps: {:8} : coverage: {:6} : crashes: {:8} \
         \n\t   instr_cnt: {:13} : ips: {:9} : ucrashes: {:6} : timeouts: {:8}", 
@@ -194,6 +240,10 @@ fn basic_stats(stats: &Statistics, elapsed_time: f64) {
         stats.ucrashes,
         stats.timeouts
     );
+    if *MAP_STATS.get().unwrap() {
+        let (density, collisions, rate) = map_stats(corpus);
+        println!("\t   map density: {:.2}% : collisions: {} ({:.2}%)", density, collisions, rate);
+    }
 }
 
 fn send_remote(ip: String, port: usize, stats: &Statistics, elapsed_time: f64) {
@@ -206,6 +256,8 @@ fn send_remote(ip: String, port: usize, stats: &Statistics, elapsed_time: f64) {
     map.insert("ucrashes", stats.ucrashes);
     map.insert("coverage", stats.coverage);
     map.insert("cmpcov", stats.cmpcov);
+    map.insert("cmp_progress", stats.cmp_progress);
+    map.insert("value_profile", stats.value_profile);
     map.insert("instr_count", stats.instr_count as usize);
     map.insert("timeouts", stats.timeouts as usize);
     map.insert("exec_time", elapsed_time as usize * 1_000);
@@ -217,7 +269,7 @@ fn send_remote(ip: String, port: usize, stats: &Statistics, elapsed_time: f64) {
 pub fn print_stats(term: &Term, stats: &Statistics, elapsed_time: f64, timeout: u64, 
                    corpus: &Arc<Corpus>, last_cov: f64) {
     if *DEBUG_PRINT.get().unwrap() {
-        basic_stats(stats, elapsed_time);
+        basic_stats(stats, elapsed_time, corpus);
     } else {
         pretty_stats(term, stats, elapsed_time, timeout, corpus, last_cov);
     }